@@ -0,0 +1,4193 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::datastructures::{
+    build_argon2, validate_password, verify_password_blocking, AccountInfo, AuditEvent,
+    AuditOutcome, BatchOperation, BatchResult, ExportAccount, ExportGroup, MaintenanceMode,
+    RepoAccess, SessionInfo, TestSuite,
+};
+use crate::logger::{build_log_config, LogTarget};
+use crate::{connect_optional_session_store, consume_csrf_token, generate_csrf_token};
+use crate::{database, datastructures, metrics};
+use crate::{get_maintenance_mode, kill_user_sessions, login, revoke_session, validate_cookie};
+use crate::{with_timeout, LoginDenial, Meta, MAINTENANCE_KEY};
+use crate::{AuthError, Config, Cookie, FormData, RequestPools, WrapConfigure};
+use crate::{SessionBackend, SessionStore};
+use anyhow::Result;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use handlebars::Handlebars;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+use sqlx::{ConnectOptions, Connection, Row, SqliteConnection};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::io::{BufRead, Read, Write};
+use std::result::Result::Ok;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::StreamExt as _;
+
+pub(crate) struct IOModule<R, W> {
+    pub(crate) reader: R,
+    pub(crate) writer: W,
+}
+
+impl<R: BufRead, W: Write> IOModule<R, W> {
+    // Processing the `authenticate-post` called by cgit.
+    pub(crate) async fn cmd_authenticate_post(
+        &mut self,
+        matches: &ArgMatches<'_>,
+        cfg: Config,
+        store: &mut dyn SessionStore,
+        pools: &Arc<RequestPools>,
+    ) -> Result<()> {
+        // Only POST carries a body, reading stdin of other methods would block forever.
+        if !is_post_request(matches) {
+            log::warn!(
+                "Reject {} request to login form",
+                matches.value_of("request-method").unwrap_or("")
+            );
+            return self.write_denial(LoginDenial::MethodNotAllowed);
+        }
+
+        // Read stdin from upstream, one more byte than allowed to detect oversized body.
+        let limit = cfg.get_max_post_bytes();
+        let mut buffer = String::new();
+        self.reader
+            .by_ref()
+            .take(limit + 1)
+            .read_to_string(&mut buffer)?;
+        if buffer.len() as u64 > limit {
+            log::warn!("Reject login post larger than {} bytes", limit);
+            return self.write_denial(LoginDenial::PayloadTooLarge);
+        }
+
+        //log::debug!("{}", buffer);
+        let data = FormData::parse(buffer.as_bytes(), cfg.get_form_fields());
+
+        let host = matches.value_of("http-host").unwrap_or("");
+        // Cookie scoped to an unknown host could be replayed on it, so such login is refused.
+        if !cfg.is_host_allowed(host) {
+            log::warn!("Reject login post to host {} not in allowed-hosts", host);
+            return self.write_denial(LoginDenial::HostNotAllowed);
+        }
+        let cfg = WrapConfigure::with_pools(cfg, pools.clone());
+
+        if data.is_logout() {
+            // Without token any site could post the form and log its visitors out.
+            if !consume_csrf_token(store, data.get_csrf_token()).await? {
+                log::warn!("Reject logout post without valid CSRF token");
+                return self.write_denial(LoginDenial::InvalidForm);
+            }
+            return self.logout(matches, cfg.get_config(), &data, store).await;
+        }
+
+        let ret = match login(&cfg, &data, host, store).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                // Single line, so web server error log can be matched against it.
+                eprintln!("cgit-simple-auth: internal error: {:#}", e);
+                #[cfg(test)]
+                eprintln!(
+                    "If database locked error occurs frequently, \
+                please use environment DISK_WAIT_TIME to specify longer time."
+                );
+                log::error!("{:?}", e);
+                Err(LoginDenial::InternalError)
+            }
+        };
+
+        cfg.get_config().write_audit_log(&AuditEvent::new(
+            data.get_user(),
+            host,
+            matches.value_of("repo").unwrap_or(""),
+            match &ret {
+                Ok(_) => AuditOutcome::Allow,
+                Err(LoginDenial::TooManyAttempts(_)) => AuditOutcome::Lockout,
+                Err(_) => AuditOutcome::Deny,
+            },
+        ));
+
+        metrics::count_login(
+            store,
+            match &ret {
+                Ok(_) => "success",
+                Err(denial) => denial.get_metric_outcome(),
+            },
+        )
+        .await;
+
+        let cookie = match ret {
+            Ok(cookie) => cookie,
+            Err(denial) => {
+                // Slow down password guessing, other denials are answered at once.
+                if denial == LoginDenial::BadCredentials {
+                    let delay = cfg.get_config().get_login_failure_delay()?;
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+                return self.write_denial(denial);
+            }
+        };
+
+        let cfg = cfg.get_config();
+        let value = match cfg.get_cookie_secret()? {
+            Some(secret) if cfg.is_stateless_sessions() => cookie.to_signed_string(
+                &secret,
+                datastructures::get_current_timestamp() + cfg.get_cookie_max_age(),
+            ),
+            _ => cookie.to_string(),
+        };
+        self.write_redirect(matches, cfg, &data, &value, cfg.get_cookie_max_age())
+    }
+
+    // Drop session of posted cookie and expire it in browser.
+    async fn logout(
+        &mut self,
+        matches: &ArgMatches<'_>,
+        cfg: &Config,
+        data: &FormData,
+        store: &mut dyn SessionStore,
+    ) -> Result<()> {
+        let cookies = matches.value_of("http-cookie").unwrap_or("");
+        let mut user = String::new();
+        if let Ok(Some(cookie)) = Cookie::load_from_request(cookies) {
+            revoke_session(store, &cookie.get_key()).await?;
+            user = cookie.get_user().to_string();
+        }
+
+        cfg.write_audit_log(&AuditEvent::new(
+            &user,
+            matches.value_of("http-host").unwrap_or(""),
+            matches.value_of("repo").unwrap_or(""),
+            AuditOutcome::Logout,
+        ));
+
+        self.write_redirect(matches, cfg, data, "", 0)
+    }
+
+    fn write_redirect(
+        &mut self,
+        matches: &ArgMatches<'_>,
+        cfg: &Config,
+        data: &FormData,
+        cookie_value: &str,
+        max_age: u64,
+    ) -> Result<()> {
+        let is_secure = matches
+            .value_of("https")
+            .is_some_and(|x| matches!(x, "yes" | "on" | "1"));
+        let domain = cfg
+            .get_cookie_domain_for(matches.value_of("http-host").unwrap_or(""))
+            .map(|domain| format!("; Domain={}", domain))
+            .unwrap_or_default();
+        // Posted form field keeps query string of the original page, referer is the fallback.
+        let target = match data.get_redirect() {
+            "" => matches.value_of("http-referer").unwrap_or("/"),
+            redirect => redirect,
+        };
+        let location =
+            datastructures::get_safe_redirect(target, matches.value_of("http-host").unwrap_or(""));
+        let cookie_suffix = if is_secure { "; secure" } else { "" };
+        let expires = if max_age == 0 && cfg.is_cookie_legacy_expires() {
+            "; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        } else {
+            ""
+        };
+        writeln!(&mut self.writer, "Status: 302 Found")?;
+        writeln!(&mut self.writer, "Cache-Control: no-cache, no-store")?;
+        writeln!(&mut self.writer, "Location: {}", location)?;
+        writeln!(
+            &mut self.writer,
+            "Set-Cookie: {}={}{}; Path={}; Max-Age={}{}; HttpOnly{}",
+            datastructures::COOKIE_NAME,
+            cookie_value,
+            domain,
+            cfg.get_cookie_path()?,
+            max_age,
+            expires,
+            cookie_suffix
+        )?;
+
+        writeln!(&mut self.writer)?;
+        Ok(())
+    }
+
+    // Tell user why login is denied, message never reveals whether the user exists.
+    fn write_denial(&mut self, denial: LoginDenial) -> Result<()> {
+        writeln!(&mut self.writer, "Status: {}", denial.get_status())?;
+        writeln!(&mut self.writer, "Cache-Control: no-cache, no-store")?;
+        if denial == LoginDenial::MethodNotAllowed {
+            writeln!(&mut self.writer, "Allow: POST")?;
+        }
+        if let LoginDenial::TooManyAttempts(remaining) = denial {
+            writeln!(&mut self.writer, "Retry-After: {}", remaining)?;
+        }
+        writeln!(&mut self.writer, "Content-Type: text/plain; charset=utf-8")?;
+        writeln!(&mut self.writer)?;
+        match denial {
+            // Lockout is checked before password, so remaining time tells nothing about credentials.
+            LoginDenial::TooManyAttempts(remaining) => writeln!(
+                &mut self.writer,
+                "{}, please try again in {} seconds",
+                denial.get_message(),
+                remaining
+            )?,
+            _ => writeln!(&mut self.writer, "{}", denial.get_message())?,
+        }
+        Ok(())
+    }
+
+    // Processing the `body` called by cgit.
+    pub(crate) async fn cmd_body(
+        &mut self,
+        matches: &ArgMatches<'_>,
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> Result<()> {
+        let source = match cfg.get_login_template() {
+            Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+                log::warn!(
+                    "Read login template {} failure, fallback to embedded page: {:?}",
+                    path,
+                    e
+                );
+                include_str!("authentication_page.html").to_string()
+            }),
+            None => include_str!("authentication_page.html").to_string(),
+        };
+        let csrf_token = generate_csrf_token(store).await?;
+        let current_url = get_current_url(matches);
+
+        // Urls come from request, they are HTML escaped by `{{ }}` and must stay in quoted attributes.
+        let handlebars = Handlebars::new();
+        let meta = Meta {
+            action: matches.value_of("login-url").unwrap_or(""),
+            redirect: datastructures::get_safe_redirect(
+                &current_url,
+                matches.value_of("http-host").unwrap_or(""),
+            ),
+            version: env!("CARGO_PKG_VERSION"),
+            custom_warning: cfg.get_login_message(),
+            csrf_token: &csrf_token,
+            user_field: &cfg.get_form_fields().user,
+            password_field: &cfg.get_form_fields().password,
+            theme: cfg.get_login_theme(),
+            pow_difficulty: cfg.get_pow_difficulty()?,
+        };
+        handlebars.render_template_to_write(&source, &meta, &mut self.writer)?;
+        Ok(())
+    }
+}
+
+fn is_post_request(matches: &ArgMatches<'_>) -> bool {
+    matches
+        .value_of("request-method")
+        .is_some_and(|method| method.eq_ignore_ascii_case("POST"))
+}
+
+// Body is read on its own thread, so a client that never sends it can not hang the filter.
+// Return None if reading does not finish in time.
+pub(crate) fn read_input_with_timeout<R: Read + Send + 'static>(
+    reader: R,
+    limit: u64,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let ret = reader.take(limit).read_to_end(&mut buffer).map(|_| buffer);
+        sender.send(ret).ok();
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(ret) => Ok(Some(ret?)),
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// cgit passes `current-url` without query string, so the original page is rebuilt from both.
+fn get_current_url(matches: &ArgMatches<'_>) -> String {
+    let current_url = matches.value_of("current-url").unwrap_or("");
+    match matches.value_of("query-string").unwrap_or("") {
+        "" => current_url.to_string(),
+        _ if current_url.contains('?') => current_url.to_string(),
+        query => format!("{}?{}", current_url, query),
+    }
+}
+
+// Request of unprotected page can pass without session.
+pub(crate) fn check_bypass(matches: &ArgMatches<'_>, cfg: &Config) -> bool {
+    let repo = matches.value_of("repo").unwrap_or("");
+    let current_url = matches.value_of("current-url").unwrap_or("");
+
+    cfg.is_bypass_path(repo, current_url)
+        || (!repo.is_empty() && (!cfg.check_repo_protect(repo) || cfg.is_public_repo(repo)))
+}
+
+// Processing the `authenticate-cookie` called by cgit.
+// Any request method is checked the same way (git smart HTTP also POSTs), stdin is never read.
+pub(crate) async fn cmd_authenticate_cookie(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    store: &mut dyn SessionStore,
+    pools: &RequestPools,
+) -> Result<bool> {
+    let cookies = matches.value_of("http-cookie").unwrap_or("");
+    let repo = matches.value_of("repo").unwrap_or("");
+    let host = matches.value_of("http-host").unwrap_or("");
+
+    if check_bypass(matches, &cfg) {
+        return Ok(true);
+    }
+
+    if cookies.is_empty() {
+        cfg.write_audit_log(&AuditEvent::new("", host, repo, AuditOutcome::Deny));
+        metrics::count_cookie(store, false).await;
+        return Ok(false);
+    }
+
+    let ret = if get_maintenance_mode(&cfg, store).await? == MaintenanceMode::DenyAll {
+        log::info!("Reject session during maintenance");
+        None
+    } else {
+        validate_cookie(&cfg, cookies, repo, store, pools).await?
+    };
+    metrics::count_cookie(store, ret.is_some()).await;
+    match ret {
+        Some(user) => {
+            cfg.write_audit_log(&AuditEvent::new(&user, host, repo, AuditOutcome::Allow));
+            Ok(true)
+        }
+        None => {
+            // Audit records the user cookie claims, even if it was rejected.
+            let user = Cookie::load_from_request(cookies)
+                .ok()
+                .flatten()
+                .map(|cookie| cookie.get_user().to_string())
+                .unwrap_or_default();
+            cfg.write_audit_log(&AuditEvent::new(&user, host, repo, AuditOutcome::Deny));
+            Ok(false)
+        }
+    }
+}
+
+pub(crate) async fn cmd_init(cfg: Config) -> Result<()> {
+    let loc = std::path::Path::new(cfg.get_database_location());
+    let exists = loc.exists();
+    if !exists {
+        std::fs::File::create(loc)?;
+    }
+
+    let pool = cfg.connect_database().await?;
+
+    if exists {
+        let mut conn = pool.acquire().await?;
+        let initialized = has_auth_meta(&mut conn).await?;
+        if initialized {
+            // Table without version would pass as initialized and fail every later command.
+            if let Err(e) = read_schema_version(&mut conn).await {
+                drop(conn);
+                pool.close().await;
+                return Err(e);
+            }
+        }
+        drop(conn);
+
+        if initialized {
+            sqlx::query(database::current::CREATE_GROUP_TABLES)
+                .execute(&pool)
+                .await?;
+            return Ok(());
+        }
+    }
+
+    sqlx::query(database::current::CREATE_TABLES)
+        .execute(&pool)
+        .await?;
+    eprintln!("Initialize the database successfully");
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+fn get_user_arg(matches: &ArgMatches<'_>) -> String {
+    datastructures::normalize_username(matches.value_of("user").unwrap_or(""))
+}
+
+// `\w` matches Unicode word characters, so accented and non-Latin names are accepted
+// while whitespace, punctuation and control characters are not.
+fn check_user_and_password(user: &str, passwd: &str) -> Result<()> {
+    let re = regex::Regex::new(r"^\w+$").unwrap();
+    if user.is_empty() || passwd.is_empty() {
+        return Err(
+            AuthError::InvalidUsername("Invalid user or password length".to_string()).into(),
+        );
+    }
+
+    if user.chars().count() >= 20 {
+        return Err(
+            AuthError::InvalidUsername("Username length should less than 21".to_string()).into(),
+        );
+    }
+
+    if !re.is_match(user) {
+        return Err(AuthError::InvalidUsername(
+            "Username must pass regex check\"^\\w+$\"".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// If `is_hash` is set, passwd is an already formatted PHC string and policy can't be checked.
+async fn get_password_hash(passwd: &str, is_hash: bool, cfg: &Config) -> Result<String> {
+    if is_hash {
+        if PasswordHash::new(passwd).is_err() {
+            return Err(anyhow::Error::msg(
+                "Password hash should be a valid PHC string",
+            ));
+        }
+        Ok(passwd.to_string())
+    } else {
+        validate_password(cfg.get_password_policy(), passwd)?;
+        FormData::gen_string_argon2_hash_blocking(
+            passwd,
+            cfg.get_argon2_algorithm()?,
+            cfg.get_argon2_output_len()?,
+            cfg.get_argon2_salt_len()?,
+            cfg.get_argon2_secret()?,
+        )
+        .await
+    }
+}
+
+pub(crate) async fn cmd_add_user(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    add_user(matches, cfg, &mut std::io::stdout(), &mut std::io::stderr()).await
+}
+
+const DEFAULT_RANDOM_PASSWORD_LENGTH: usize = 20;
+
+// Password from argument, or a generated one with `--random`, which is also returned.
+fn get_password_arg(matches: &ArgMatches<'_>, cfg: &Config) -> Result<(String, bool)> {
+    if !matches.is_present("random") {
+        return Ok((
+            matches.value_of("password").unwrap_or("").to_string(),
+            false,
+        ));
+    }
+    let length = match matches.value_of("random") {
+        Some(length) => length
+            .parse::<usize>()
+            .ok()
+            .filter(|x| (8..=128).contains(x))
+            .ok_or_else(|| {
+                AuthError::WeakPassword("Random password length should be 8 to 128".to_string())
+            })?,
+        None => DEFAULT_RANDOM_PASSWORD_LENGTH,
+    };
+    // Retry the rare draw missing a character class required by policy,
+    // policy error of last draw is returned if length itself is too short.
+    for _ in 1..100 {
+        let passwd = datastructures::random_password(length);
+        if validate_password(cfg.get_password_policy(), &passwd).is_ok() {
+            return Ok((passwd, true));
+        }
+    }
+    let passwd = datastructures::random_password(length);
+    validate_password(cfg.get_password_policy(), &passwd)?;
+    Ok((passwd, true))
+}
+
+// Plaintext is printed once to `output`, it is never shown or stored again.
+fn write_random_password<O: Write, W: Write>(
+    user: &str,
+    passwd: &str,
+    output: &mut O,
+    messages: &mut W,
+) -> Result<()> {
+    writeln!(output, "{}", passwd)?;
+    writeln!(
+        messages,
+        "Warning: generated password of {} is shown only once, send it to the user over a secure channel",
+        user
+    )?;
+    Ok(())
+}
+
+// Status message goes to `messages` (stderr for command line), stdout is kept for listings.
+pub(crate) async fn add_user<O: Write, W: Write>(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    output: &mut O,
+    messages: &mut W,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+    let (passwd, is_random) = get_password_arg(matches, &cfg)?;
+    check_user_and_password(user, &passwd)?;
+
+    let password_hash = get_password_hash(&passwd, matches.is_present("hash"), &cfg).await?;
+    let expires_at = matches.value_of("expires").map(parse_expires).transpose()?;
+
+    let pool = cfg.connect_database().await?;
+
+    let uid = create_user(
+        &mut *pool.acquire().await?,
+        user,
+        &password_hash,
+        expires_at,
+    )
+    .await?;
+
+    writeln!(messages, "Insert {} ({}) to database", user, uid)?;
+    if is_random {
+        write_random_password(user, &passwd, output, messages)?;
+    }
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn create_user(
+    conn: &mut SqliteConnection,
+    user: &str,
+    password_hash: &str,
+    expires_at: Option<i64>,
+) -> Result<String> {
+    if sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = ? "#)
+        .bind(user)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some()
+    {
+        return Err(AuthError::UserExists.into());
+    }
+    insert_user(conn, user, password_hash, expires_at).await
+}
+
+async fn insert_user(
+    conn: &mut SqliteConnection,
+    user: &str,
+    password_hash: &str,
+    expires_at: Option<i64>,
+) -> Result<String> {
+    let uid = uuid::Uuid::new_v4().to_hyphenated().to_string();
+
+    sqlx::query(
+        r#"INSERT INTO "accounts" ("user", "password", "uid", "expires_at") VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(user)
+    .bind(password_hash)
+    .bind(&uid)
+    .bind(expires_at)
+    .execute(conn)
+    .await?;
+    Ok(uid)
+}
+
+// Accept unix timestamp, or duration from now with s, m, h, d suffix (e.g. 30d).
+pub(crate) fn parse_expires(s: &str) -> Result<i64> {
+    let s = s.trim();
+    if s.ends_with(|c: char| c.is_ascii_digit()) {
+        return s
+            .parse()
+            .map_err(|_| anyhow::Error::msg(format!("Invalid expire time: {}", s)));
+    }
+    let value =
+        parse_duration(s).map_err(|_| anyhow::Error::msg(format!("Invalid expire time: {}", s)))?;
+    Ok(datastructures::get_current_timestamp() as i64 + value as i64)
+}
+
+// Seconds of a duration like `30d`, `12h`, `5m`, `10s` or plain `10`.
+fn parse_duration(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (value, unit) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        Some('d') => (&s[..s.len() - 1], 86400),
+        _ => (s, 1),
+    };
+    value
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(unit))
+        .ok_or_else(|| anyhow::Error::msg(format!("Invalid duration: {}", s)))
+}
+
+pub(crate) async fn cmd_set_user_disabled(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    disabled: bool,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+
+    let pool = cfg.connect_database().await?;
+
+    set_user_disabled(&mut *pool.acquire().await?, user, disabled).await?;
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn set_user_disabled(conn: &mut SqliteConnection, user: &str, disabled: bool) -> Result<()> {
+    let result = sqlx::query(r#"UPDATE "accounts" SET "disabled" = ? WHERE "user" = ?"#)
+        .bind(disabled)
+        .bind(user)
+        .execute(conn)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::UserNotFound(user.to_string()).into());
+    }
+
+    eprintln!(
+        "User {} is {}",
+        user,
+        if disabled { "disabled" } else { "enabled" }
+    );
+    Ok(())
+}
+
+// Index entries whose session is gone (expired or deleted) are pruned while collecting.
+pub(crate) async fn collect_user_sessions(
+    store: &mut dyn SessionStore,
+    user: &str,
+) -> Result<Vec<SessionInfo>> {
+    let mut sessions = Vec::new();
+    let mut stale = Vec::new();
+    for key in store.get_user_sessions(user).await? {
+        match store.get_session_ttl(&format!("cgit_auth_{}", key)).await? {
+            Some(ttl) => {
+                let created_at = key
+                    .split_once('_')
+                    .and_then(|(timestamp, _)| timestamp.parse().ok())
+                    .unwrap_or_default();
+                sessions.push(SessionInfo {
+                    key,
+                    ttl,
+                    created_at,
+                })
+            }
+            None => stale.push(key),
+        }
+    }
+    store.remove_user_sessions(user, &stale).await?;
+    sessions.sort_by(|a, b| (a.created_at, &a.key).cmp(&(b.created_at, &b.key)));
+    Ok(sessions)
+}
+
+// Session cleanup after account change, skipped with a warning if session backend is unavailable.
+async fn cleanup_user_sessions(store: Option<&mut dyn SessionStore>, user: &str) {
+    let ret = match store {
+        Some(store) => kill_user_sessions(store, user).await,
+        None => Err(anyhow::Error::msg("session backend unavailable")),
+    };
+    match ret {
+        Ok(killed) => eprintln!("Remove {} session(s) of {}", killed, user),
+        Err(e) => {
+            log::warn!("Remove sessions of {} failure: {:?}", user, e);
+            eprintln!(
+                "Warning: sessions of {} were not removed ({}), run `user killsessions {}` later",
+                user, e, user
+            );
+        }
+    }
+}
+
+pub(crate) async fn cmd_list_sessions<W: Write>(
+    matches: &ArgMatches<'_>,
+    store: &mut dyn SessionStore,
+    writer: &mut W,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+    let mut sessions = collect_user_sessions(store, user).await?;
+
+    if let Some(age) = matches.value_of("older-than") {
+        let cutoff = datastructures::get_current_timestamp().saturating_sub(parse_duration(age)?);
+        sessions.retain(|session| session.created_at < cutoff);
+    }
+
+    if matches.is_present("kill") {
+        let mut killed = 0;
+        for session in &sessions {
+            if revoke_session(store, &session.key).await? {
+                killed += 1;
+            }
+        }
+        let keys = sessions.into_iter().map(|x| x.key).collect::<Vec<_>>();
+        store.remove_user_sessions(user, &keys).await?;
+        eprintln!("Remove {} session(s) of {}", killed, user);
+        return Ok(());
+    }
+
+    if matches.is_present("json") {
+        serde_json::to_writer(&mut *writer, &sessions)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+
+    if sessions.is_empty() {
+        writeln!(writer, "There is not session of {}.", user)?;
+        return Ok(());
+    }
+    writeln!(
+        writer,
+        "There is {} session{} of {}",
+        sessions.len(),
+        if sessions.len() > 1 { "s" } else { "" },
+        user
+    )?;
+    for session in &sessions {
+        writeln!(
+            writer,
+            "{}\tcreated at {}\texpires in {}s",
+            session.key, session.created_at, session.ttl
+        )?;
+    }
+    Ok(())
+}
+
+// Repositories user can read from database grants or cached ACL, sorted by repository.
+pub(crate) async fn collect_user_access(
+    pool: &SqlitePool,
+    store: &mut dyn SessionStore,
+    user: &str,
+) -> Result<Vec<RepoAccess>> {
+    if sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = ?"#)
+        .bind(user)
+        .fetch_optional(pool)
+        .await?
+        .is_none()
+    {
+        return Err(AuthError::UserNotFound(user.to_string()).into());
+    }
+
+    // repo -> (direct, groups)
+    let mut grants: BTreeMap<String, (bool, Vec<String>)> = BTreeMap::new();
+    for (repo, users) in sqlx::query_as::<_, (String, String)>(r#"SELECT * FROM "repos""#)
+        .fetch_all(pool)
+        .await?
+    {
+        grants.entry(repo).or_default().0 = users.split_whitespace().any(|x| x == user);
+    }
+
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("group_repos")
+        .fetch_all(pool)
+        .await?;
+    if !rows.is_empty() {
+        for (repo, group, is_member) in sqlx::query_as::<_, (String, String, bool)>(
+            r#"SELECT "repo", "group", EXISTS(SELECT 1 FROM "group_members" WHERE "group_members"."group" = "group_repos"."group" AND "user" = ?) FROM "group_repos""#,
+        )
+        .bind(user)
+        .fetch_all(pool)
+        .await?
+        {
+            let entry = grants.entry(repo).or_default();
+            if is_member {
+                entry.1.push(group);
+            }
+        }
+    }
+
+    let mut access = Vec::new();
+    for (repo, (direct, mut groups)) in grants {
+        let cached = if store.has_repo_set(&repo).await? {
+            Some(store.is_repo_allowed(&repo, user).await?)
+        } else {
+            None
+        };
+        let allowed = direct || !groups.is_empty();
+        if !allowed && cached != Some(true) {
+            continue;
+        }
+        groups.sort();
+        access.push(RepoAccess {
+            stale: cached.is_some_and(|cached| cached != allowed),
+            repo,
+            direct,
+            groups,
+            cached,
+        });
+    }
+    Ok(access)
+}
+
+pub(crate) async fn cmd_show_access<W: Write>(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    store: &mut dyn SessionStore,
+    writer: &mut W,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+    let pool = cfg.connect_read_only_database().await?;
+    let access = collect_user_access(&pool, store, user).await?;
+    pool.close().await;
+
+    if matches.is_present("json") {
+        serde_json::to_writer(&mut *writer, &access)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+
+    writeln!(
+        writer,
+        "User {} can access {} {}",
+        user,
+        access.len(),
+        if access.len() == 1 {
+            "repository"
+        } else {
+            "repositories"
+        }
+    )?;
+    for entry in &access {
+        let mut sources = Vec::new();
+        if entry.direct {
+            sources.push("direct".to_string());
+        }
+        sources.extend(entry.groups.iter().map(|group| format!("group {}", group)));
+        if sources.is_empty() {
+            sources.push("none".to_string());
+        }
+        writeln!(
+            writer,
+            "{}\tgranted by {}\tcache {}{}",
+            entry.repo,
+            sources.join(", "),
+            match entry.cached {
+                Some(true) => "allowed",
+                Some(false) => "denied",
+                None => "empty",
+            },
+            if entry.stale {
+                "\tSTALE (cache disagrees with database)"
+            } else {
+                ""
+            }
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn cmd_kill_sessions(
+    matches: &ArgMatches<'_>,
+    store: &mut dyn SessionStore,
+) -> Result<()> {
+    if matches.is_present("all") {
+        return cmd_logout_all(store).await;
+    }
+    let user = &get_user_arg(matches);
+    let killed = kill_user_sessions(store, user).await?;
+    eprintln!("Remove {} session(s) of {}", killed, user);
+    Ok(())
+}
+
+// Toggle maintenance kept in session backend, so it takes effect without editing cgitrc.
+pub(crate) async fn cmd_maintenance<W: Write>(
+    matches: &ArgMatches<'_>,
+    cfg: &Config,
+    store: &mut dyn SessionStore,
+    writer: &mut W,
+) -> Result<()> {
+    let mode = match matches.value_of("state").unwrap() {
+        "on" if matches.is_present("deny-sessions") => MaintenanceMode::DenyAll,
+        "on" => MaintenanceMode::DenyLogins,
+        "off" => MaintenanceMode::Off,
+        _ => {
+            writeln!(writer, "{}", get_maintenance_mode(cfg, store).await?)?;
+            return Ok(());
+        }
+    };
+    store
+        .set_counter(MAINTENANCE_KEY, mode.to_counter())
+        .await?;
+    log::warn!("Set maintenance mode to {}", mode);
+    eprintln!("Maintenance mode is {}", mode);
+    if mode < cfg.get_maintenance_mode() {
+        eprintln!(
+            "Warning: maintenance-mode in cgitrc keeps it {}",
+            cfg.get_maintenance_mode()
+        );
+    }
+    Ok(())
+}
+
+// Global logout, e.g. after a suspected breach. Cached ACL and CSRF tokens are kept.
+async fn cmd_logout_all(store: &mut dyn SessionStore) -> Result<()> {
+    let killed = store.delete_all_sessions().await?;
+    log::warn!("Remove all {} session(s)", killed);
+    eprintln!("Remove {} session(s) of all users", killed);
+    Ok(())
+}
+
+// Create first account right after `database init`, skipped if database already has users.
+pub(crate) async fn cmd_init_admin(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let user = &match matches.value_of("admin-user") {
+        Some(user) => datastructures::normalize_username(user),
+        None => return Ok(()),
+    };
+    let passwd = matches.value_of("admin-password").unwrap_or("");
+    check_user_and_password(user, passwd)?;
+
+    let password_hash = get_password_hash(passwd, false, &cfg).await?;
+
+    let pool = cfg.connect_database().await?;
+
+    let (count,) = sqlx::query_as::<_, (i32,)>(r#"SELECT COUNT(*) FROM "accounts""#)
+        .fetch_one(&pool)
+        .await?;
+    if count > 0 {
+        eprintln!(
+            "Database already has {} user{}, skip creating {}",
+            count,
+            if count > 1 { "s" } else { "" },
+            user
+        );
+        pool.close().await;
+        return Ok(());
+    }
+
+    let uid = insert_user(&mut *pool.acquire().await?, user, &password_hash, None).await?;
+    eprintln!("Insert {} ({}) to database", user, uid);
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+pub(crate) async fn cmd_change_password(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    store: Option<&mut dyn SessionStore>,
+) -> Result<()> {
+    change_password(matches, cfg, store, &mut std::io::stdout()).await
+}
+
+pub(crate) async fn change_password<O: Write>(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    store: Option<&mut dyn SessionStore>,
+    output: &mut O,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+    let (passwd, is_random) = get_password_arg(matches, &cfg)?;
+    check_user_and_password(user, &passwd)?;
+
+    let is_hash = matches.is_present("hash");
+    let password_hash = get_password_hash(&passwd, is_hash, &cfg).await?;
+
+    let pool = cfg.connect_database().await?;
+
+    update_password(
+        &mut *pool.acquire().await?,
+        &cfg,
+        user,
+        &passwd,
+        &password_hash,
+        is_hash,
+    )
+    .await?;
+
+    if is_random {
+        write_random_password(user, &passwd, output, &mut std::io::stderr())?;
+    }
+
+    pool.close().await;
+
+    cleanup_user_sessions(store, user).await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn update_password(
+    conn: &mut SqliteConnection,
+    cfg: &Config,
+    user: &str,
+    passwd: &str,
+    password_hash: &str,
+    is_hash: bool,
+) -> Result<()> {
+    let (uid, previous) = sqlx::query_as::<_, (String, String)>(
+        r#"SELECT "uid", "password" FROM "accounts" WHERE "user" = ?"#,
+    )
+    .bind(user)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| AuthError::UserNotFound(user.to_string()))?;
+
+    let history_size = cfg.get_password_policy().history_size;
+    if history_size > 0 {
+        sqlx::query(database::current::CREATE_PASSWORD_HISTORY_TABLE)
+            .execute(&mut *conn)
+            .await?;
+        // Given hash can not be compared, it is still recorded for next change.
+        if !is_hash && is_password_reused(conn, cfg, &uid, &previous, passwd).await? {
+            return Err(AuthError::WeakPassword(format!(
+                "Password should differ from last {} password(s)",
+                history_size
+            ))
+            .into());
+        }
+    }
+
+    // Password must not change without its predecessor going into history.
+    let mut transaction = conn.begin().await?;
+    sqlx::query(r#"UPDATE "accounts" SET "password" = ? WHERE "user" = ?"#)
+        .bind(password_hash)
+        .bind(user)
+        .execute(&mut transaction)
+        .await?;
+
+    if history_size > 1 {
+        record_password_history(&mut transaction, &uid, &previous, history_size - 1).await?;
+    }
+    transaction.commit().await?;
+
+    eprintln!("Update password of {}", user);
+    Ok(())
+}
+
+// Current hash and newest previous ones, each verified with the parameters stored in it.
+async fn is_password_reused(
+    conn: &mut SqliteConnection,
+    cfg: &Config,
+    uid: &str,
+    current: &str,
+    passwd: &str,
+) -> Result<bool> {
+    let history_size = cfg.get_password_policy().history_size;
+    let mut hashes = vec![current.to_string()];
+    hashes.extend(
+        sqlx::query_as::<_, (String,)>(
+            r#"SELECT "password" FROM "password_history" WHERE "uid" = ? ORDER BY "changed_at" DESC, rowid DESC LIMIT ?"#,
+        )
+        .bind(uid)
+        .bind(history_size as i64 - 1)
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|(hash,)| hash),
+    );
+
+    let secret = cfg.get_argon2_secret()?;
+    for hash in &hashes {
+        // Damaged hash matches nothing.
+        if PasswordHash::new(hash).is_err() {
+            continue;
+        }
+        if verify_password_blocking(passwd, hash, secret.clone()).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Keep `keep` newest previous hashes of uid.
+async fn record_password_history(
+    conn: &mut SqliteConnection,
+    uid: &str,
+    previous: &str,
+    keep: usize,
+) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO "password_history" ("uid", "password", "changed_at") VALUES (?, ?, ?)"#,
+    )
+    .bind(uid)
+    .bind(previous)
+    .bind(datastructures::get_current_timestamp() as i64)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM "password_history" WHERE "uid" = ? AND rowid NOT IN (SELECT rowid FROM "password_history" WHERE "uid" = ? ORDER BY "changed_at" DESC, rowid DESC LIMIT ?)"#,
+    )
+    .bind(uid)
+    .bind(uid)
+    .bind(keep as i64)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// Check password read from first line of input against stored hash, nothing else is touched.
+pub(crate) async fn cmd_verify_password<R: BufRead>(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    reader: &mut R,
+) -> Result<bool> {
+    let user = &get_user_arg(matches);
+    let mut passwd = String::new();
+    reader.read_line(&mut passwd)?;
+    let passwd = passwd.trim_end_matches(&['\r', '\n'][..]);
+
+    let pool = cfg.connect_read_only_database().await?;
+    let hash =
+        sqlx::query_as::<_, (String,)>(r#"SELECT "password" FROM "accounts" WHERE "user" = ?"#)
+            .bind(user)
+            .fetch_optional(&pool)
+            .await?;
+    pool.close().await;
+
+    let (hash,) = hash.ok_or_else(|| AuthError::UserNotFound(user.to_string()))?;
+    let hash = PasswordHash::new(&hash)
+        .map_err(|e| anyhow::Error::msg(format!("Stored hash of {} is invalid: {}", user, e)))?;
+    let secret = cfg.get_argon2_secret()?;
+    Ok(build_argon2(secret.as_deref())?
+        .verify_password(passwd.as_bytes(), &hash)
+        .is_ok())
+}
+
+// Print algorithm and cost of stored hash, the hash and salt themselves are not shown.
+pub(crate) async fn cmd_inspect_hash<W: Write>(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    writer: &mut W,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+    let pool = cfg.connect_read_only_database().await?;
+    let hash =
+        sqlx::query_as::<_, (String,)>(r#"SELECT "password" FROM "accounts" WHERE "user" = ?"#)
+            .bind(user)
+            .fetch_optional(&pool)
+            .await?;
+    pool.close().await;
+
+    let (hash,) = hash.ok_or_else(|| AuthError::UserNotFound(user.to_string()))?;
+    let hash = PasswordHash::new(&hash)
+        .map_err(|e| anyhow::Error::msg(format!("Stored hash of {} is invalid: {}", user, e)))?;
+    let params = argon2::Params::try_from(&hash)
+        .map_err(|e| anyhow::Error::msg(format!("Stored hash of {} is invalid: {}", user, e)))?;
+    writeln!(writer, "algorithm: {}", hash.algorithm)?;
+    writeln!(
+        writer,
+        "version: {}",
+        hash.version.map(|x| x.to_string()).unwrap_or_default()
+    )?;
+    writeln!(writer, "m_cost: {}", params.m_cost)?;
+    writeln!(writer, "t_cost: {}", params.t_cost)?;
+    writeln!(writer, "p_cost: {}", params.p_cost)?;
+    writeln!(writer, "output length: {}", params.output_size)?;
+    // Salt is unpadded B64, every 4 characters carry 3 bytes.
+    writeln!(
+        writer,
+        "salt length: {}",
+        hash.salt.map(|x| x.as_str().len() * 3 / 4).unwrap_or(0)
+    )?;
+    let outdated = datastructures::is_hash_outdated(
+        &hash,
+        cfg.get_argon2_algorithm()?,
+        cfg.get_argon2_output_len()?,
+    );
+    writeln!(writer, "outdated: {}", if outdated { "yes" } else { "no" })?;
+    Ok(())
+}
+
+/// Set by SIGTERM or Ctrl-C during batch commands, they check it between steps and stop
+/// before committing, so database is never left half-written. Other commands keep default
+/// signal handling.
+#[derive(Clone)]
+pub(crate) struct Interrupted {
+    sender: Arc<tokio::sync::watch::Sender<bool>>,
+    receiver: tokio::sync::watch::Receiver<bool>,
+}
+
+impl Default for Interrupted {
+    fn default() -> Self {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl Interrupted {
+    /// Install the signal handler, must be called inside tokio runtime.
+    pub fn listen() -> Result<Self> {
+        let interrupted = Self::default();
+        let handler = interrupted.clone();
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            #[cfg(not(unix))]
+            tokio::signal::ctrl_c().await.ok();
+            log::warn!("Received shutdown signal, stop after current step");
+            handler.set();
+        });
+        Ok(interrupted)
+    }
+
+    pub(crate) fn set(&self) {
+        self.sender.send(true).ok();
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolve once the signal is received, for long running commands like `serve`.
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            // Sender lives as long as `self`, so the channel is never closed here.
+            receiver.changed().await.ok();
+        }
+    }
+}
+
+// Import users from file, each line is `user,password` or `user<TAB>password`.
+pub(crate) async fn cmd_import_users(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    interrupted: &Interrupted,
+) -> Result<()> {
+    let is_hash = matches.is_present("hash");
+    let strict = matches.is_present("strict");
+
+    let context = read_import_input(matches)?;
+
+    let pool = cfg.connect_database().await?;
+    let mut tx = pool.begin().await?;
+
+    let mut imported = 0;
+    let mut failures: Vec<(usize, String)> = Default::default();
+    let mut seen: HashSet<String> = Default::default();
+
+    for (nth, line) in context.lines().enumerate().map(|(nth, x)| (nth + 1, x)) {
+        // Transaction is dropped uncommitted, so nothing of this file is imported.
+        if interrupted.is_set() {
+            return Err(anyhow::Error::msg(format!(
+                "Interrupted at line {}, nothing imported",
+                nth
+            )));
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (user, passwd) = match line.split_once([',', '\t']) {
+            Some((user, passwd)) => (datastructures::normalize_username(user.trim()), passwd),
+            None => {
+                failures.push((nth, "Missing password field".to_string()));
+                continue;
+            }
+        };
+
+        let user = user.as_str();
+        let ret = match check_user_and_password(user, passwd) {
+            Ok(_) => get_password_hash(passwd, is_hash, &cfg).await,
+            Err(e) => Err(e),
+        };
+        let password_hash = match ret {
+            Ok(password_hash) => password_hash,
+            Err(e) => {
+                failures.push((nth, e.to_string()));
+                continue;
+            }
+        };
+
+        let exists = !seen.insert(user.to_string())
+            || sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = ?"#)
+                .bind(user)
+                .fetch_optional(&mut tx)
+                .await?
+                .is_some();
+        if exists {
+            if strict {
+                return Err(anyhow::Error::msg(format!(
+                    "Line {}: User {} already exists, nothing imported",
+                    nth, user
+                )));
+            }
+            eprintln!("Line {}: User {} already exists, skipped", nth, user);
+            continue;
+        }
+
+        sqlx::query(r#"INSERT INTO "accounts" ("user", "password", "uid") VALUES (?, ?, ?)"#)
+            .bind(user)
+            .bind(password_hash)
+            .bind(uuid::Uuid::new_v4().to_hyphenated().to_string())
+            .execute(&mut tx)
+            .await?;
+        imported += 1;
+    }
+
+    if strict && !failures.is_empty() {
+        for (nth, reason) in &failures {
+            eprintln!("Line {}: {}", nth, reason);
+        }
+        return Err(anyhow::Error::msg(format!(
+            "{} line{} failed, nothing imported",
+            failures.len(),
+            if failures.len() > 1 { "s" } else { "" }
+        )));
+    }
+
+    tx.commit().await?;
+    pool.close().await;
+
+    for (nth, reason) in &failures {
+        eprintln!("Line {}: {}", nth, reason);
+    }
+    eprintln!(
+        "Import {} user{} to database, {} failed",
+        imported,
+        if imported > 1 { "s" } else { "" },
+        failures.len()
+    );
+
+    if imported > 0 {
+        cfg.write_database_commit_timestamp().await?;
+    }
+    Ok(())
+}
+
+// `--input` or positional file, standard input if neither is given.
+fn read_import_input(matches: &ArgMatches<'_>) -> Result<String> {
+    match matches
+        .value_of("input")
+        .or_else(|| matches.value_of("file"))
+    {
+        Some(path) => Ok(std::fs::read_to_string(path)?),
+        None => {
+            let mut content = String::new();
+            std::io::stdin().read_to_string(&mut content)?;
+            Ok(content)
+        }
+    }
+}
+
+const EXPORT_CHECKSUM_PREFIX: &str = "sha256 ";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Strip checksum line written by `cmd_export`, documents exported before it existed have none.
+pub(crate) fn verify_export_checksum(content: &str) -> Result<&str> {
+    let trimmed = content.trim_end_matches('\n');
+    let (document, checksum) = match trimmed.rsplit_once('\n') {
+        Some((document, last)) => match last.strip_prefix(EXPORT_CHECKSUM_PREFIX) {
+            Some(checksum) => (document, checksum),
+            None => return Ok(content),
+        },
+        None => return Ok(content),
+    };
+    // Checksum covers every byte before its own line, including the newline ending the document.
+    let expected = to_hex(&Sha256::digest(&content.as_bytes()[..document.len() + 1]));
+    if !checksum.trim().eq_ignore_ascii_case(&expected) {
+        return Err(anyhow::Error::msg(
+            "Checksum mismatch, backup file is corrupted, nothing restored",
+        ));
+    }
+    Ok(document)
+}
+
+// Hash everything written through it, for the checksum line of `cmd_export`.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Restore users, repository ACL and groups from document written by `cmd_export`.
+pub(crate) async fn cmd_restore_users(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    interrupted: &Interrupted,
+) -> Result<()> {
+    let strict = matches.is_present("strict");
+
+    let content = read_import_input(matches)?;
+    let document: serde_json::Value = serde_json::from_str(verify_export_checksum(&content)?)?;
+    let accounts: Vec<ExportAccount> =
+        serde_json::from_value(document.get("accounts").cloned().unwrap_or_default())?;
+    let groups: Vec<ExportGroup> =
+        serde_json::from_value(document.get("groups").cloned().unwrap_or_default())?;
+
+    let pool = cfg.connect_database().await?;
+    let mut tx = pool.begin().await?;
+
+    let mut restored = 0;
+    let mut repos: BTreeMap<&str, Vec<&str>> = Default::default();
+
+    for account in &accounts {
+        if interrupted.is_set() {
+            return Err(anyhow::Error::msg(format!(
+                "Interrupted at user {}, nothing restored",
+                account.user
+            )));
+        }
+        if PasswordHash::new(&account.password).is_err() {
+            return Err(anyhow::Error::msg(format!(
+                "User {} has an invalid password hash, nothing restored",
+                account.user
+            )));
+        }
+
+        if sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = ?"#)
+            .bind(&account.user)
+            .fetch_optional(&mut tx)
+            .await?
+            .is_some()
+        {
+            if strict {
+                return Err(anyhow::Error::msg(format!(
+                    "User {} already exists, nothing restored",
+                    account.user
+                )));
+            }
+            eprintln!("User {} already exists, skipped", account.user);
+            continue;
+        }
+
+        sqlx::query(
+            r#"INSERT INTO "accounts" ("user", "password", "uid", "disabled", "expires_at") VALUES (?, ?, ?, ?, ?)"#,
+        )
+        .bind(&account.user)
+        .bind(&account.password)
+        .bind(&account.uid)
+        .bind(account.disabled)
+        .bind(account.expires_at)
+        .execute(&mut tx)
+        .await?;
+        for repo in &account.repos {
+            repos.entry(repo).or_default().push(&account.user);
+        }
+        restored += 1;
+    }
+
+    for (repo, users) in repos {
+        let current =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "users" FROM "repos" WHERE "repo" = ?"#)
+                .bind(repo)
+                .fetch_optional(&mut tx)
+                .await?;
+        match current {
+            Some((current,)) => {
+                let mut current = current.split_whitespace().collect::<Vec<&str>>();
+                for user in users {
+                    if !current.contains(&user) {
+                        current.push(user);
+                    }
+                }
+                sqlx::query(r#"UPDATE "repos" SET "users" = ? WHERE "repo" = ?"#)
+                    .bind(current.join(" "))
+                    .bind(repo)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query(r#"INSERT INTO "repos" VALUES (?, ?)"#)
+                    .bind(repo)
+                    .bind(users.join(" "))
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+    }
+
+    let mut restored_groups = 0;
+    for group in &groups {
+        if interrupted.is_set() {
+            return Err(anyhow::Error::msg(format!(
+                "Interrupted at group {}, nothing restored",
+                group.group
+            )));
+        }
+        if sqlx::query(r#"SELECT 1 FROM "groups" WHERE "group" = ?"#)
+            .bind(&group.group)
+            .fetch_optional(&mut tx)
+            .await?
+            .is_some()
+        {
+            if strict {
+                return Err(anyhow::Error::msg(format!(
+                    "Group {} already exists, nothing restored",
+                    group.group
+                )));
+            }
+            eprintln!("Group {} already exists, skipped", group.group);
+            continue;
+        }
+
+        sqlx::query(r#"INSERT INTO "groups" VALUES (?)"#)
+            .bind(&group.group)
+            .execute(&mut tx)
+            .await?;
+        for user in &group.members {
+            sqlx::query(r#"INSERT OR IGNORE INTO "group_members" VALUES (?, ?)"#)
+                .bind(&group.group)
+                .bind(user)
+                .execute(&mut tx)
+                .await?;
+        }
+        for repo in &group.repos {
+            sqlx::query(r#"INSERT OR IGNORE INTO "group_repos" VALUES (?, ?)"#)
+                .bind(&group.group)
+                .bind(repo)
+                .execute(&mut tx)
+                .await?;
+        }
+        restored_groups += 1;
+    }
+
+    tx.commit().await?;
+    pool.close().await;
+
+    eprintln!(
+        "Restore {} user{} to database",
+        restored,
+        if restored > 1 { "s" } else { "" }
+    );
+    if restored_groups > 0 {
+        eprintln!(
+            "Restore {} group{} to database",
+            restored_groups,
+            if restored_groups > 1 { "s" } else { "" }
+        );
+    }
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+// Export users and their repository ACL, then groups as JSON, entries are written one by one.
+// Last line is SHA-256 of the document, so restore can detect a corrupted backup.
+pub(crate) async fn cmd_export<W: Write>(cfg: Config, output: &mut W) -> Result<()> {
+    let pool = cfg.connect_read_only_database().await?;
+    // Read everything from one snapshot, so a concurrent change never splits the backup.
+    let mut transaction = pool.begin().await?;
+    let writer = &mut ChecksumWriter {
+        inner: &mut *output,
+        hasher: Sha256::new(),
+    };
+
+    write!(
+        writer,
+        "{{\"version\":{},\"accounts\":[",
+        serde_json::to_string(database::current::VERSION)?
+    )?;
+
+    let mut user_repos: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (repo, users) in sqlx::query_as::<_, (String, String)>(
+        r#"SELECT "repo", "users" FROM "repos" ORDER BY "repo""#,
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    {
+        for user in users.split_whitespace() {
+            user_repos
+                .entry(user.to_string())
+                .or_default()
+                .push(repo.clone());
+        }
+    }
+    for repos in user_repos.values_mut() {
+        repos.dedup();
+    }
+
+    {
+        let mut iter = sqlx::query_as::<_, (String, String, String, bool, Option<i64>)>(
+            r#"SELECT "user", "password", "uid", "disabled", "expires_at" FROM "accounts" ORDER BY "user""#,
+        )
+        .fetch(&mut transaction);
+
+        let mut first = true;
+        while let Some((user, password, uid, disabled, expires_at)) =
+            iter.next().await.transpose()?
+        {
+            let repos = user_repos.remove(&user).unwrap_or_default();
+
+            if !first {
+                write!(writer, ",")?;
+            }
+            first = false;
+            serde_json::to_writer(
+                &mut *writer,
+                &ExportAccount {
+                    user,
+                    uid,
+                    password,
+                    disabled,
+                    expires_at,
+                    repos,
+                },
+            )?;
+        }
+    }
+
+    write!(writer, "],\"groups\":[")?;
+    let mut group_members: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (group, user) in sqlx::query_as::<_, (String, String)>(
+        r#"SELECT "group", "user" FROM "group_members" ORDER BY "user""#,
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    {
+        group_members.entry(group).or_default().push(user);
+    }
+    let mut group_repos: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (group, repo) in sqlx::query_as::<_, (String, String)>(
+        r#"SELECT "group", "repo" FROM "group_repos" ORDER BY "repo""#,
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    {
+        group_repos.entry(group).or_default().push(repo);
+    }
+
+    {
+        let mut iter =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "group" FROM "groups" ORDER BY "group""#)
+                .fetch(&mut transaction);
+
+        let mut first = true;
+        while let Some((group,)) = iter.next().await.transpose()? {
+            let members = group_members.remove(&group).unwrap_or_default();
+            let repos = group_repos.remove(&group).unwrap_or_default();
+
+            if !first {
+                write!(writer, ",")?;
+            }
+            first = false;
+            serde_json::to_writer(
+                &mut *writer,
+                &ExportGroup {
+                    group,
+                    members,
+                    repos,
+                },
+            )?;
+        }
+    }
+    transaction.commit().await?;
+
+    writeln!(writer, "]}}")?;
+    let checksum = to_hex(&writer.hasher.finalize_reset());
+    writeln!(output, "{}{}", EXPORT_CHECKSUM_PREFIX, checksum)?;
+    Ok(())
+}
+
+// Export into a temporary file next to `path` which replaces it only when complete,
+// so a crash never leaves a truncated backup behind.
+pub(crate) async fn cmd_export_to_file(cfg: Config, path: &str) -> Result<()> {
+    let path = std::path::Path::new(path);
+    let mut tmp_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::Error::msg(format!("{} is not a file path", path.display())))?
+        .to_os_string();
+    tmp_name.push(format!(".tmp-{}", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = async {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        cmd_export(cfg, &mut file).await?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        std::fs::remove_file(&tmp_path).ok();
+    }
+    result
+}
+
+// user -> sorted repositories granted directly or through groups, two queries for all users.
+async fn fetch_user_repos(pool: &SqlitePool) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut grants: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (repo, users) in sqlx::query_as::<_, (String, String)>(r#"SELECT * FROM "repos""#)
+        .fetch_all(pool)
+        .await?
+    {
+        for user in users.split_whitespace() {
+            grants
+                .entry(user.to_string())
+                .or_default()
+                .push(repo.clone());
+        }
+    }
+
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("group_repos")
+        .fetch_all(pool)
+        .await?;
+    if !rows.is_empty() {
+        for (user, repo) in sqlx::query_as::<_, (String, String)>(
+            r#"SELECT DISTINCT "user", "repo" FROM "group_members" JOIN "group_repos" USING ("group")"#,
+        )
+        .fetch_all(pool)
+        .await?
+        {
+            grants.entry(user).or_default().push(repo);
+        }
+    }
+
+    for repos in grants.values_mut() {
+        repos.sort();
+        repos.dedup();
+    }
+    Ok(grants)
+}
+
+pub(crate) async fn cmd_list_user<W: Write>(
+    cfg: Config,
+    json: bool,
+    with_repos: bool,
+    writer: &mut W,
+) -> Result<()> {
+    let pool = cfg.connect_read_only_database().await?;
+
+    let (count,) = sqlx::query_as::<_, (i32,)>(r#"SELECT COUNT(*) FROM "accounts""#)
+        .fetch_one(&pool)
+        .await?;
+    let mut grants = if with_repos {
+        fetch_user_repos(&pool).await?
+    } else {
+        Default::default()
+    };
+    let mut take_repos =
+        |user: &str| Some(grants.remove(user).unwrap_or_default()).filter(|_| with_repos);
+
+    // Columns are decoded per row, so a broken row is reported without ending the listing.
+    let mut iter = sqlx::query(r#"SELECT "user", "last_login", "last_host" FROM "accounts""#)
+        .fetch(&pool)
+        .map(|row| {
+            let row = row?;
+            Ok::<_, sqlx::Error>((
+                row.try_get::<String, _>(0)?,
+                row.try_get::<Option<i64>, _>(1)?,
+                row.try_get::<Option<String>, _>(2)?,
+            ))
+        });
+
+    if json {
+        let mut accounts = Vec::new();
+        let mut broken = 0;
+        while let Some(row) = iter.next().await {
+            match row {
+                Ok((user, last_login, last_host)) => accounts.push(AccountInfo {
+                    repos: take_repos(&user),
+                    user,
+                    last_login,
+                    last_host,
+                }),
+                Err(e) => {
+                    report_broken_row(&e);
+                    broken += 1;
+                }
+            }
+        }
+        serde_json::to_writer(&mut *writer, &accounts)?;
+        writeln!(writer)?;
+        check_broken_rows(broken)?;
+    } else if count > 0 {
+        writeln!(
+            writer,
+            "There is {} user{} in database",
+            count,
+            if count > 1 { "s" } else { "" }
+        )?;
+        let mut broken = 0;
+        while let Some(row) = iter.next().await {
+            match row {
+                Ok((user, last_login, last_host)) => {
+                    write!(writer, "{}", user)?;
+                    if let (Some(last_login), Some(last_host)) = (last_login, last_host) {
+                        write!(writer, "\tlast login at {} from {}", last_login, last_host)?;
+                    }
+                    match take_repos(&user) {
+                        Some(repos) if repos.is_empty() => write!(writer, "\trepos: (none)")?,
+                        Some(repos) => write!(writer, "\trepos: {}", repos.join(" "))?,
+                        None => {}
+                    }
+                    writeln!(writer)?;
+                }
+                Err(e) => {
+                    report_broken_row(&e);
+                    broken += 1;
+                }
+            }
+        }
+        check_broken_rows(broken)?;
+    } else {
+        writeln!(writer, "There is not user exists.")?
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn cmd_count_user<W: Write>(cfg: Config, writer: &mut W) -> Result<()> {
+    let pool = cfg.connect_read_only_database().await?;
+    let (count,) = sqlx::query_as::<_, (i64,)>(r#"SELECT COUNT(*) FROM "accounts""#)
+        .fetch_one(&pool)
+        .await?;
+    writeln!(writer, "{}", count)?;
+    Ok(())
+}
+
+// Listing keeps going past undecodable rows, they are reported and fail the command at the end.
+fn report_broken_row(e: &sqlx::Error) {
+    eprintln!("Unable to read row: {}", e);
+    log::error!("Unable to read row: {:?}", e);
+}
+
+fn check_broken_rows(broken: usize) -> Result<()> {
+    if broken > 0 {
+        return Err(anyhow::Error::msg(format!(
+            "{} row{} could not be read",
+            broken,
+            if broken > 1 { "s" } else { "" }
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) async fn cmd_delete_user(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    mut store: Option<&mut dyn SessionStore>,
+) -> Result<()> {
+    let user = &get_user_arg(matches);
+
+    let pool = cfg.connect_database().await?;
+
+    let mut tx = pool.begin().await?;
+    let purged_repos = delete_user(&mut tx, user, matches.is_present("purge")).await?;
+    tx.commit().await?;
+
+    pool.close().await;
+
+    clear_cached_repos(store.as_mut().map(|x| &mut **x as _), &purged_repos).await;
+    cleanup_user_sessions(store, user).await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+// Return repositories whose effective users changed by purge.
+async fn delete_user(conn: &mut SqliteConnection, user: &str, purge: bool) -> Result<Vec<String>> {
+    if user.is_empty() {
+        return Err(AuthError::InvalidUsername("Please input a valid username".to_string()).into());
+    }
+
+    let (uid,) = sqlx::query_as::<_, (String,)>(r#"SELECT "uid" FROM "accounts" WHERE "user" = ?"#)
+        .bind(user)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| AuthError::UserNotFound(user.to_string()))?;
+
+    sqlx::query(r#"DELETE FROM "accounts" WHERE "user" = ?"#)
+        .bind(user)
+        .execute(&mut *conn)
+        .await?;
+
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("password_history")
+        .fetch_all(&mut *conn)
+        .await?;
+    if !rows.is_empty() {
+        sqlx::query(r#"DELETE FROM "password_history" WHERE "uid" = ?"#)
+            .bind(uid)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    let purged_repos = if purge {
+        purge_user_acl(conn, user).await?
+    } else {
+        Default::default()
+    };
+
+    eprintln!("Delete {} from database", user);
+    if purge {
+        eprintln!(
+            "Remove {} from ACL of {} repository(s)",
+            user,
+            purged_repos.len()
+        );
+    }
+    Ok(purged_repos)
+}
+
+// Remove user from repository ACLs and group memberships, so a reused username gains nothing.
+// Return repositories whose effective users changed.
+async fn purge_user_acl(conn: &mut SqliteConnection, user: &str) -> Result<Vec<String>> {
+    let mut repos = Vec::new();
+    let rows = sqlx::query_as::<_, (String, String)>(r#"SELECT "repo", "users" FROM "repos""#)
+        .fetch_all(&mut *conn)
+        .await?;
+    for (repo, users) in rows {
+        let users = users.split_whitespace().collect::<Vec<&str>>();
+        if !users.contains(&user) {
+            continue;
+        }
+        sqlx::query(r#"UPDATE "repos" SET "users" = ? WHERE "repo" = ?"#)
+            .bind(
+                users
+                    .into_iter()
+                    .filter(|x| !x.eq(&user))
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            )
+            .bind(&repo)
+            .execute(&mut *conn)
+            .await?;
+        repos.push(repo);
+    }
+
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("group_members")
+        .fetch_all(&mut *conn)
+        .await?;
+    if !rows.is_empty() {
+        let granted = sqlx::query_as::<_, (String,)>(
+            r#"SELECT DISTINCT "repo" FROM "group_members" JOIN "group_repos" USING ("group") WHERE "user" = ?"#,
+        )
+        .bind(user)
+        .fetch_all(&mut *conn)
+        .await?;
+        for (repo,) in granted {
+            if !repos.contains(&repo) {
+                repos.push(repo);
+            }
+        }
+        sqlx::query(r#"DELETE FROM "group_members" WHERE "user" = ?"#)
+            .bind(user)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(repos)
+}
+
+pub(crate) async fn cmd_reset_database(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    if !matches.is_present("confirm") {
+        return Err(anyhow::Error::msg(
+            "Please add --confirm argument to process reset",
+        ));
+    }
+
+    let pool = cfg.connect_database().await?;
+
+    sqlx::query(database::current::DROP_TABLES)
+        .execute(&pool)
+        .await?;
+
+    sqlx::query(database::current::CREATE_TABLES)
+        .execute(&pool)
+        .await?;
+
+    eprintln!("Reset database successfully");
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn has_auth_meta(conn: &mut SqliteConnection) -> Result<bool> {
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("auth_meta")
+        .fetch_all(conn)
+        .await?;
+    Ok(!rows.is_empty())
+}
+
+// Partially initialized database may lack the table or the row, tell operator how to recover.
+async fn read_schema_version(conn: &mut SqliteConnection) -> Result<String> {
+    if !has_auth_meta(conn).await? {
+        return Err(AuthError::InconsistentDatabase(
+            "table auth_meta is missing, run `database init` to create the tables".to_string(),
+        )
+        .into());
+    }
+    let (v,) = sqlx::query_as::<_, (String,)>(
+        r#"SELECT "value" FROM "auth_meta" WHERE "key" = 'version' "#,
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or_else(|| {
+        AuthError::InconsistentDatabase(
+            "auth_meta has no schema version, run `database reset` to recreate the tables \
+            (all accounts are lost) or restore a backup"
+                .to_string(),
+        )
+    })?;
+    Ok(v)
+}
+
+async fn migrate_database(
+    conn: &mut SqliteConnection,
+    origin: String,
+    interrupted: &Interrupted,
+) -> Result<String> {
+    let mut version = origin;
+    while !version.eq(database::current::VERSION) {
+        if interrupted.is_set() {
+            return Err(anyhow::Error::msg(format!(
+                "Interrupted at version {}, database is not changed",
+                version
+            )));
+        }
+        let migration = database::MIGRATIONS
+            .iter()
+            .find(|m| m.from.eq(&version))
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "No migration from database version {} to {}",
+                    version,
+                    database::current::VERSION
+                ))
+            })?;
+
+        let mut transaction = conn.begin().await?;
+        for statements in migration.statements {
+            sqlx::query(statements).execute(&mut transaction).await?;
+        }
+        sqlx::query(r#"UPDATE "auth_meta" SET "value" = ? WHERE "key" = 'version'"#)
+            .bind(migration.to)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+
+        log::debug!(
+            "Upgrade database from {} to {}",
+            migration.from,
+            migration.to
+        );
+        version = migration.to.to_string();
+    }
+    Ok(version)
+}
+
+fn remove_file_if_exists(path: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Remove WAL and shared memory files SQLite keeps beside a database, they belong to that file only.
+fn remove_database_sidecars(path: &str) -> Result<()> {
+    remove_file_if_exists(&format!("{}-wal", path))?;
+    remove_file_if_exists(&format!("{}-shm", path))
+}
+
+fn remove_database_files(path: &str) -> Result<()> {
+    remove_file_if_exists(path)?;
+    remove_database_sidecars(path)
+}
+
+// Commits by other connections since the last call on this connection change the value.
+async fn read_data_version(conn: &mut SqliteConnection) -> Result<i64> {
+    let (version,) = sqlx::query_as::<_, (i64,)>("PRAGMA data_version")
+        .fetch_one(conn)
+        .await?;
+    Ok(version)
+}
+
+// Migrate the snapshot in place, `None` means it is at current version already.
+async fn migrate_database_copy(path: &str, interrupted: &Interrupted) -> Result<Option<String>> {
+    let mut conn = SqliteConnection::connect(path).await?;
+    let result = match read_schema_version(&mut conn).await {
+        Ok(origin) if database::is_newer_than_current(&origin) => {
+            Err(AuthError::SchemaTooNew(origin).into())
+        }
+        Ok(origin) if origin.eq(database::current::VERSION) => {
+            eprintln!(
+                "Database is already at version {}, nothing to upgrade",
+                origin
+            );
+            Ok(None)
+        }
+        Ok(origin) => migrate_database(&mut conn, origin, interrupted)
+            .await
+            .map(Some),
+        Err(e) => Err(e),
+    };
+    // Closing the last connection checkpoints its WAL, the file is complete on its own afterwards.
+    conn.close().await?;
+    result
+}
+
+// Migrations run on a snapshot in scratch directory which replaces the database only after all
+// steps succeed, so a failure or crash leaves the original database intact. VACUUM INTO includes
+// frames not yet checkpointed from WAL, and writers are locked out while the migrated file is
+// renamed into place from a sibling on the same volume.
+pub(crate) async fn cmd_upgrade_database(cfg: Config, interrupted: &Interrupted) -> Result<()> {
+    cfg.check_database_exists()?;
+    cfg.check_scratch_dir()?;
+    let location = cfg.get_database_location();
+    let tmp_path = cfg
+        .get_copied_database_location()
+        .to_string_lossy()
+        .to_string();
+    // VACUUM INTO refuses to overwrite, the copy may be left over from a crashed run.
+    remove_database_files(&tmp_path)?;
+
+    let mut live = SqliteConnectOptions::from_str(location)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(cfg.get_operation_timeout())
+        .connect()
+        .await?;
+    let snapshot = read_data_version(&mut live).await?;
+    if let Err(e) = sqlx::query("VACUUM INTO ?")
+        .bind(&tmp_path)
+        .execute(&mut live)
+        .await
+    {
+        live.close().await?;
+        remove_database_files(&tmp_path)?;
+        return Err(e.into());
+    }
+
+    let version = match migrate_database_copy(&tmp_path, interrupted).await {
+        // Last chance to stop, replacing the database can not be interrupted safely.
+        Ok(Some(_)) if interrupted.is_set() => {
+            Err(anyhow::Error::msg("Interrupted, database is not changed"))
+        }
+        result => result,
+    };
+    let version = match version {
+        Ok(Some(version)) => version,
+        result => {
+            live.close().await?;
+            remove_database_files(&tmp_path)?;
+            return result.map(|_| ());
+        }
+    };
+
+    // Scratch directory may be on another volume, only a rename on the same one is atomic.
+    let sibling = format!("{}.upgrade-{}", location, std::process::id());
+    let copied =
+        std::fs::copy(&tmp_path, &sibling).and_then(|_| std::fs::File::open(&sibling)?.sync_all());
+    remove_database_files(&tmp_path)?;
+    if let Err(e) = copied {
+        live.close().await?;
+        remove_database_files(&sibling)?;
+        return Err(e.into());
+    }
+
+    // Hold the write lock until old WAL and shared memory are gone, they must not be replayed onto
+    // the new file. A commit since the snapshot would be lost, so give up instead.
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut live).await?;
+    let replaced = if read_data_version(&mut live).await? != snapshot {
+        Err(anyhow::Error::msg(
+            "Database was changed during upgrade, it is not changed, run upgrade again",
+        ))
+    } else {
+        std::fs::rename(&sibling, location)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| remove_database_sidecars(location))
+    };
+    sqlx::query("ROLLBACK").execute(&mut live).await?;
+    live.close().await?;
+    if let Err(e) = replaced {
+        remove_database_files(&sibling)?;
+        return Err(e);
+    }
+    eprintln!("Upgrade database to version {} successful", version);
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn check_database_health(cfg: &Config) -> Result<String> {
+    if !std::path::Path::new(cfg.get_database_location()).exists() {
+        return Err(anyhow::Error::msg(format!(
+            "{} not found",
+            cfg.get_database_location()
+        )));
+    }
+    let mut conn = SqliteConnectOptions::from_str(cfg.get_database_location())?
+        .read_only(true)
+        .busy_timeout(cfg.get_sqlite_busy_timeout())
+        .connect()
+        .await?;
+    let version = read_schema_version(&mut conn).await;
+    conn.close().await?;
+    version
+}
+
+async fn check_redis_health(cfg: &Config) -> Result<()> {
+    let mut conn = cfg.connect_redis().await?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut conn)
+        .await?;
+    Ok(())
+}
+
+// Check database, schema version and session backend, return true if all of them are healthy.
+pub(crate) async fn cmd_healthcheck<W: Write>(cfg: Config, writer: &mut W) -> Result<bool> {
+    let mut healthy = true;
+
+    match check_database_health(&cfg).await {
+        Ok(version) => {
+            writeln!(writer, "database: ok")?;
+            if version.eq(database::current::VERSION) {
+                writeln!(writer, "schema version: ok ({})", version)?;
+            } else {
+                writeln!(
+                    writer,
+                    "schema version: FAILED ({} but {} required)",
+                    version,
+                    database::current::VERSION
+                )?;
+                healthy = false;
+            }
+        }
+        Err(e) => {
+            writeln!(writer, "database: FAILED ({})", e)?;
+            writeln!(writer, "schema version: skipped")?;
+            healthy = false;
+        }
+    }
+
+    if cfg.get_session_backend() == SessionBackend::Redis {
+        match check_redis_health(&cfg).await {
+            Ok(_) => writeln!(writer, "redis: ok")?,
+            Err(e) => {
+                writeln!(writer, "redis: FAILED ({})", e)?;
+                healthy = false;
+            }
+        }
+    }
+
+    Ok(healthy)
+}
+
+// Print cgitrc lines wiring this binary as auth-filter, values are taken from current config.
+pub(crate) fn cmd_examples<W: Write>(
+    cfg: &Config,
+    exe: &std::path::Path,
+    writer: &mut W,
+) -> Result<()> {
+    let backend = match cfg.get_session_backend() {
+        SessionBackend::Redis => "redis",
+        SessionBackend::Sqlite => "sqlite",
+    };
+    writeln!(
+        writer,
+        "# Add to {}",
+        datastructures::DEFAULT_CONFIG_LOCATION
+    )?;
+    writeln!(writer, "auth-filter={}", exe.display())?;
+    writeln!(
+        writer,
+        "cgit-simple-auth-database={}",
+        cfg.get_database_location()
+    )?;
+    writeln!(writer, "cgit-simple-auth-cookie-ttl={}", cfg.cookie_ttl)?;
+    writeln!(writer, "cgit-simple-auth-session-backend={}", backend)?;
+    writeln!(writer, "cgit-simple-auth-protect=full")?;
+    writeln!(writer)?;
+    writeln!(writer, "# Then create database and first user")?;
+    writeln!(
+        writer,
+        "{} database init --admin-user admin --admin-password <password>",
+        exe.display()
+    )?;
+    Ok(())
+}
+
+// Print schema version of database, return true if it is not the current version.
+pub(crate) async fn cmd_schema_version(cfg: Config) -> Result<bool> {
+    let loc = std::path::Path::new(cfg.get_database_location());
+    let version = if loc.exists() {
+        let mut conn = SqliteConnectOptions::from_str(cfg.get_database_location())?
+            .read_only(true)
+            .busy_timeout(cfg.get_sqlite_busy_timeout())
+            .connect()
+            .await?;
+        let version = if has_auth_meta(&mut conn).await? {
+            Some(read_schema_version(&mut conn).await)
+        } else {
+            None
+        };
+        conn.close().await?;
+        version.transpose()?
+    } else {
+        None
+    };
+
+    match version {
+        Some(v) if v.eq(database::current::VERSION) => {
+            println!("{} (up to date)", v);
+            Ok(false)
+        }
+        Some(v) if database::is_newer_than_current(&v) => {
+            println!(
+                "{} (newer than {} supported by this binary)",
+                v,
+                database::current::VERSION
+            );
+            Ok(true)
+        }
+        Some(v) => {
+            println!("{} (upgrade to {} required)", v, database::current::VERSION);
+            Ok(true)
+        }
+        None => {
+            println!("uninitialized");
+            Ok(true)
+        }
+    }
+}
+
+// Report broken accounts and dangling repository ACL entries, return true if problems remain.
+pub(crate) async fn cmd_check_database(matches: &ArgMatches<'_>, cfg: Config) -> Result<bool> {
+    let fix = matches.is_present("fix");
+
+    let pool = if fix {
+        cfg.connect_database().await?
+    } else {
+        cfg.connect_read_only_database().await?
+    };
+
+    let mut problems = 0;
+    let mut fixed = 0;
+
+    let accounts =
+        sqlx::query_as::<_, (String, String)>(r#"SELECT "user", "password" FROM "accounts""#)
+            .fetch_all(&pool)
+            .await?;
+
+    for (user, password) in &accounts {
+        if PasswordHash::new(password).is_err() {
+            println!("Account {} has an unparseable password hash", user);
+            problems += 1;
+        }
+    }
+
+    let mut iter = sqlx::query_as::<_, (String, i32)>(
+        r#"SELECT "user", COUNT(*) FROM "accounts" GROUP BY "user" HAVING COUNT(*) > 1"#,
+    )
+    .fetch(&pool);
+    while let Some((user, count)) = iter.next().await.transpose()? {
+        println!("Username {} appears {} times", user, count);
+        problems += 1;
+    }
+    drop(iter);
+
+    let users = accounts
+        .iter()
+        .map(|(user, _)| user.as_str())
+        .collect::<HashSet<&str>>();
+
+    let repos = sqlx::query_as::<_, (String, String)>(r#"SELECT "repo", "users" FROM "repos""#)
+        .fetch_all(&pool)
+        .await?;
+
+    for (repo, repo_users) in &repos {
+        let (valid, orphaned): (Vec<&str>, Vec<&str>) = repo_users
+            .split_whitespace()
+            .partition(|user| users.contains(user));
+        if orphaned.is_empty() {
+            continue;
+        }
+
+        println!(
+            "Repository {} ACL references missing user{} {}",
+            repo,
+            if orphaned.len() > 1 { "s" } else { "" },
+            orphaned.join(",")
+        );
+        problems += orphaned.len();
+
+        if fix {
+            if valid.is_empty() {
+                sqlx::query(r#"DELETE FROM "repos" WHERE "repo" = ?"#)
+                    .bind(repo)
+                    .execute(&pool)
+                    .await?;
+            } else {
+                sqlx::query(r#"UPDATE "repos" SET "users" = ? WHERE "repo" = ?"#)
+                    .bind(valid.join(" "))
+                    .bind(repo)
+                    .execute(&pool)
+                    .await?;
+            }
+
+            invalidate_repos_cache(&cfg, std::slice::from_ref(repo)).await?;
+            fixed += orphaned.len();
+        }
+    }
+
+    pool.close().await;
+
+    if problems == 0 {
+        println!("No problem found in database");
+    } else {
+        println!(
+            "Found {} problem{} in database{}",
+            problems,
+            if problems > 1 { "s" } else { "" },
+            if fixed > 0 {
+                format!(", {} fixed", fixed)
+            } else {
+                "".to_string()
+            }
+        );
+    }
+
+    if fixed > 0 {
+        cfg.write_database_commit_timestamp().await?;
+    }
+    Ok(problems > fixed)
+}
+
+// Size of database file and its WAL, they are reported together because VACUUM moves pages into WAL.
+fn database_file_size(location: &str) -> u64 {
+    [location.to_string(), format!("{}-wal", location)]
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+pub(crate) async fn cmd_vacuum_database<W: Write>(cfg: Config, writer: &mut W) -> Result<()> {
+    cfg.check_database_exists()?;
+    let location = cfg.get_database_location();
+    let before = database_file_size(location);
+
+    // A single connection which waits for cgit readers instead of failing with SQLITE_BUSY,
+    // VACUUM is atomic so readers keep seeing a consistent database meanwhile.
+    let mut conn = SqliteConnectOptions::from_str(location)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(cfg.get_operation_timeout())
+        .connect()
+        .await?;
+    sqlx::query("VACUUM").execute(&mut conn).await?;
+    let (busy,) = sqlx::query_as::<_, (i32,)>("PRAGMA wal_checkpoint(TRUNCATE)")
+        .fetch_one(&mut conn)
+        .await?;
+    conn.close().await?;
+    if busy != 0 {
+        log::warn!("WAL of {} is still in use, it was not truncated", location);
+    }
+
+    writeln!(
+        writer,
+        "Database size {} bytes before, {} bytes after",
+        before,
+        database_file_size(location)
+    )?;
+    Ok(())
+}
+
+pub(crate) async fn cmd_repo_user_control(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    is_delete: bool,
+) -> Result<()> {
+    let repo = matches.value_of("repo").unwrap_or("");
+    let user = &get_user_arg(matches);
+
+    let pool = cfg.connect_database().await?;
+
+    let changed = update_repo_acl(
+        &mut *pool.acquire().await?,
+        repo,
+        user,
+        is_delete,
+        matches.is_present("clear-all"),
+    )
+    .await?;
+
+    // User may still access repository through group, so let `authenticate-cookie` rebuild cache.
+    invalidate_repos_cache(&cfg, &changed).await?;
+
+    Ok(())
+}
+
+// Return the repository if its ACL was changed.
+async fn update_repo_acl(
+    conn: &mut SqliteConnection,
+    repo: &str,
+    user: &str,
+    is_delete: bool,
+    clear_all: bool,
+) -> Result<Vec<String>> {
+    if repo.is_empty()
+        || (is_delete && !clear_all && user.is_empty())
+        || (!is_delete && user.is_empty())
+    {
+        return Err(anyhow::Error::msg("Invalid repository or username"));
+    }
+
+    if sqlx::query(r#"SELECT "users" FROM "repos" WHERE "repo" = ?"#)
+        .bind(repo)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_none()
+    {
+        if is_delete {
+            eprintln!("Row is empty.");
+            return Ok(Vec::new());
+        }
+        sqlx::query(r#"INSERT INTO "repos" VALUES (?, ?)"#)
+            .bind(repo)
+            .bind("")
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    let (users,) =
+        sqlx::query_as::<_, (String,)>(r#"SELECT "users" FROM "repos" WHERE "repo" = ?"#)
+            .bind(repo)
+            .fetch_optional(&mut *conn)
+            .await?
+            .unwrap();
+    let mut users = users.split_whitespace().collect::<Vec<&str>>();
+
+    if let Some(index) = users.clone().into_iter().position(|x| x.eq(user)) {
+        if is_delete {
+            if clear_all {
+                users.clear();
+            } else {
+                users.remove(index);
+            }
+        } else {
+            return Err(anyhow::Error::msg("User already in repository ACL"));
+        }
+    }
+
+    if !is_delete {
+        users.push(user);
+    }
+
+    sqlx::query(r#"UPDATE "repos" SET "users" = ? WHERE "repo" = ?"#)
+        .bind(users.join(" "))
+        .bind(repo)
+        .execute(&mut *conn)
+        .await?;
+
+    if !clear_all {
+        eprintln!(
+            "{} user {} {} repository {} ACL successful",
+            if is_delete { "Delete" } else { "Add" },
+            user,
+            if is_delete { "from" } else { "to" },
+            repo,
+        );
+    } else {
+        eprintln!("Clear all users from repository {} ACL", repo);
+    }
+
+    Ok(vec![repo.to_string()])
+}
+
+// Drop cached repository ACL, it will be rebuilt by next `authenticate-cookie`.
+async fn invalidate_repos_cache(cfg: &Config, repos: &[String]) -> Result<()> {
+    if repos.is_empty() {
+        return Ok(());
+    }
+
+    let mut store = connect_optional_session_store(cfg).await;
+    clear_cached_repos(store.as_mut().map(|x| x.as_mut() as _), repos).await;
+    Ok(())
+}
+
+async fn clear_cached_repos(store: Option<&mut dyn SessionStore>, repos: &[String]) {
+    let ret = match store {
+        Some(store) => store.clear_repo_sets(repos).await,
+        None => Err(anyhow::Error::msg("session backend unavailable")),
+    };
+    if let Err(e) = ret {
+        log::warn!("Clear cached repository ACL failure: {:?}", e);
+        eprintln!(
+            "Warning: cached ACL was not cleared ({}), it expires after repo-cache-ttl",
+            e
+        );
+    }
+}
+
+async fn get_group_repos(conn: &mut SqliteConnection, group: &str) -> Result<Vec<String>> {
+    Ok(
+        sqlx::query_as::<_, (String,)>(r#"SELECT "repo" FROM "group_repos" WHERE "group" = ?"#)
+            .bind(group)
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|(repo,)| repo)
+            .collect(),
+    )
+}
+
+async fn check_group_exists(conn: &mut SqliteConnection, group: &str) -> Result<()> {
+    if sqlx::query(r#"SELECT 1 FROM "groups" WHERE "group" = ?"#)
+        .bind(group)
+        .fetch_optional(conn)
+        .await?
+        .is_none()
+    {
+        return Err(anyhow::Error::msg(format!("Group {} not found", group)));
+    }
+    Ok(())
+}
+
+pub(crate) async fn cmd_add_group(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let group = matches.value_of("group").unwrap_or("");
+
+    let pool = cfg.connect_database().await?;
+
+    create_group(&mut *pool.acquire().await?, group).await?;
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn create_group(conn: &mut SqliteConnection, group: &str) -> Result<()> {
+    let re = regex::Regex::new(r"^\w+$").unwrap();
+    if !re.is_match(group) {
+        return Err(anyhow::Error::msg(
+            "Group name must pass regex check\"^\\w+$\"",
+        ));
+    }
+
+    if sqlx::query(r#"SELECT 1 FROM "groups" WHERE "group" = ?"#)
+        .bind(group)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some()
+    {
+        return Err(anyhow::Error::msg("Group already exists!"));
+    }
+
+    sqlx::query(r#"INSERT INTO "groups" VALUES (?)"#)
+        .bind(group)
+        .execute(&mut *conn)
+        .await?;
+
+    eprintln!("Add group {} to database", group);
+    Ok(())
+}
+
+pub(crate) async fn cmd_delete_group(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let group = matches.value_of("group").unwrap_or("");
+
+    let pool = cfg.connect_database().await?;
+
+    let mut transaction = pool.begin().await?;
+    let repos = delete_group(&mut transaction, group).await?;
+    transaction.commit().await?;
+
+    invalidate_repos_cache(&cfg, &repos).await?;
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+// Return repositories the group had access to.
+async fn delete_group(conn: &mut SqliteConnection, group: &str) -> Result<Vec<String>> {
+    check_group_exists(conn, group).await?;
+    let repos = get_group_repos(conn, group).await?;
+
+    for statement in &[
+        r#"DELETE FROM "group_repos" WHERE "group" = ?"#,
+        r#"DELETE FROM "group_members" WHERE "group" = ?"#,
+        r#"DELETE FROM "groups" WHERE "group" = ?"#,
+    ] {
+        sqlx::query(statement)
+            .bind(group)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    eprintln!("Delete group {} from database", group);
+    Ok(repos)
+}
+
+pub(crate) async fn cmd_group_grant(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let group = matches.value_of("group").unwrap_or("");
+    let repo = matches.value_of("repo").unwrap_or("");
+
+    let pool = cfg.connect_database().await?;
+
+    grant_group(
+        &mut *pool.acquire().await?,
+        group,
+        repo,
+        matches.is_present("revoke"),
+    )
+    .await?;
+
+    invalidate_repos_cache(&cfg, &[repo.to_string()]).await?;
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+async fn grant_group(
+    conn: &mut SqliteConnection,
+    group: &str,
+    repo: &str,
+    is_revoke: bool,
+) -> Result<()> {
+    if repo.is_empty() {
+        return Err(anyhow::Error::msg("Invalid repository"));
+    }
+
+    check_group_exists(conn, group).await?;
+
+    let granted = sqlx::query(r#"SELECT 1 FROM "group_repos" WHERE "group" = ? AND "repo" = ?"#)
+        .bind(group)
+        .bind(repo)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+
+    if is_revoke {
+        if !granted {
+            return Err(anyhow::Error::msg(format!(
+                "Group {} has no access to repository {}",
+                group, repo
+            )));
+        }
+        sqlx::query(r#"DELETE FROM "group_repos" WHERE "group" = ? AND "repo" = ?"#)
+            .bind(group)
+            .bind(repo)
+            .execute(&mut *conn)
+            .await?;
+    } else {
+        if granted {
+            return Err(anyhow::Error::msg("Group already has access to repository"));
+        }
+        sqlx::query(r#"INSERT INTO "group_repos" VALUES (?, ?)"#)
+            .bind(group)
+            .bind(repo)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    eprintln!(
+        "{} group {} access {} repository {} successful",
+        if is_revoke { "Revoke" } else { "Grant" },
+        group,
+        if is_revoke { "from" } else { "to" },
+        repo
+    );
+    Ok(())
+}
+
+pub(crate) async fn cmd_group_member(matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let group = matches.value_of("group").unwrap_or("");
+    let user = &get_user_arg(matches);
+
+    let pool = cfg.connect_database().await?;
+
+    let repos = set_group_member(
+        &mut *pool.acquire().await?,
+        group,
+        user,
+        matches.is_present("remove"),
+    )
+    .await?;
+
+    invalidate_repos_cache(&cfg, &repos).await?;
+
+    pool.close().await;
+
+    cfg.write_database_commit_timestamp().await?;
+    Ok(())
+}
+
+// Return repositories of the group, their effective users changed.
+async fn set_group_member(
+    conn: &mut SqliteConnection,
+    group: &str,
+    user: &str,
+    is_remove: bool,
+) -> Result<Vec<String>> {
+    check_group_exists(conn, group).await?;
+
+    let is_member =
+        sqlx::query(r#"SELECT 1 FROM "group_members" WHERE "group" = ? AND "user" = ?"#)
+            .bind(group)
+            .bind(user)
+            .fetch_optional(&mut *conn)
+            .await?
+            .is_some();
+
+    if is_remove {
+        if !is_member {
+            return Err(anyhow::Error::msg(format!(
+                "User {} not in group {}",
+                user, group
+            )));
+        }
+        sqlx::query(r#"DELETE FROM "group_members" WHERE "group" = ? AND "user" = ?"#)
+            .bind(group)
+            .bind(user)
+            .execute(&mut *conn)
+            .await?;
+    } else {
+        if is_member {
+            return Err(anyhow::Error::msg("User already in group"));
+        }
+        if sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = ?"#)
+            .bind(user)
+            .fetch_optional(&mut *conn)
+            .await?
+            .is_none()
+        {
+            return Err(AuthError::UserNotFound(user.to_string()).into());
+        }
+        sqlx::query(r#"INSERT INTO "group_members" VALUES (?, ?)"#)
+            .bind(group)
+            .bind(user)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    eprintln!(
+        "{} user {} {} group {} successful",
+        if is_remove { "Remove" } else { "Add" },
+        user,
+        if is_remove { "from" } else { "to" },
+        group
+    );
+    get_group_repos(conn, group).await
+}
+
+async fn cmd_list_repos_acl(arg_matches: &ArgMatches<'_>, cfg: Config) -> Result<()> {
+    let repo = arg_matches.value_of("repo").unwrap_or("");
+
+    let pool = cfg.connect_read_only_database().await?;
+
+    if repo.is_empty() {
+        let (length,) = sqlx::query_as::<_, (i32,)>(r#"SELECT COUNT(*) FROM "repos""#)
+            .fetch_optional(&pool)
+            .await?
+            .unwrap_or((0,));
+
+        println!(
+            "There is total {} {} in database",
+            length,
+            if length == 1 {
+                "repository"
+            } else {
+                "repositories"
+            },
+        );
+
+        let mut iter =
+            sqlx::query_as::<_, (String, String)>(r#"SELECT * FROM "repos""#).fetch(&pool);
+        let mut broken = 0;
+        while let Some(row) = iter.next().await {
+            match row {
+                Ok((repo, users)) => println!(
+                    "{}: {}",
+                    repo,
+                    users.split_whitespace().collect::<Vec<&str>>().join(",")
+                ),
+                Err(e) => {
+                    report_broken_row(&e);
+                    broken += 1;
+                }
+            }
+        }
+        check_broken_rows(broken)?;
+    } else {
+        let ret =
+            sqlx::query_as::<_, (String, String)>(r#"SELECT * FROM "repos" WHERE "repo" = ?"#)
+                .bind(repo)
+                .fetch_optional(&pool)
+                .await?;
+        if let Some((repo, users)) = ret {
+            println!(
+                "{}: {}",
+                repo,
+                users.split_whitespace().collect::<Vec<&str>>().join(",")
+            )
+        } else {
+            println!("Repository {} not register in database", repo)
+        }
+    }
+
+    Ok(())
+}
+
+// Cookie of cgit requests and password of user commands are masked unless log-sensitive is set.
+pub(crate) fn format_arguments(args: &[String], log_sensitive: bool) -> String {
+    let command = args
+        .iter()
+        .skip(1)
+        .position(|arg| !arg.starts_with('-'))
+        .map(|nth| nth + 1)
+        .unwrap_or(args.len());
+    let sensitive = match args.get(command).map(|x| x.as_str()) {
+        Some("authenticate-cookie") | Some("authenticate-post") | Some("body") => Some(command + 1),
+        Some("user") => match args.get(command + 1).map(|x| x.as_str()) {
+            Some("add") | Some("passwd") => Some(command + 3),
+            _ => None,
+        },
+        _ => None,
+    }
+    .filter(|_| !log_sensitive);
+    args.iter()
+        .enumerate()
+        .map(|(nth, arg)| {
+            if Some(nth) == sensitive {
+                format!("[{}]=<redacted>", nth)
+            } else {
+                format!("[{}]={}", nth, arg)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Run one management command received by `serve`, its listing goes to output.
+// Commands reading stdin or files, and everything called by cgit, are not served.
+async fn dispatch_served_command<W: Write>(
+    arg_matches: &ArgMatches<'_>,
+    cfg: Config,
+    output: &mut W,
+) -> Result<()> {
+    match arg_matches.subcommand() {
+        ("user", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => add_user(matches, cfg, output, &mut std::io::stderr()).await,
+            ("del", Some(matches)) => {
+                let mut store = connect_optional_session_store(&cfg).await;
+                cmd_delete_user(matches, cfg, store.as_mut().map(|x| x.as_mut() as _)).await
+            }
+            ("passwd", Some(matches)) => {
+                let mut store = connect_optional_session_store(&cfg).await;
+                change_password(
+                    matches,
+                    cfg,
+                    store.as_mut().map(|x| x.as_mut() as _),
+                    output,
+                )
+                .await
+            }
+            ("disable", Some(matches)) => cmd_set_user_disabled(matches, cfg, true).await,
+            ("enable", Some(matches)) => cmd_set_user_disabled(matches, cfg, false).await,
+            ("killsessions", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_kill_sessions(matches, store.as_mut()).await
+            }
+            ("sessions", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_list_sessions(matches, store.as_mut(), output).await
+            }
+            ("access", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_show_access(matches, cfg, store.as_mut(), output).await
+            }
+            ("list", Some(matches)) if matches.is_present("count") => {
+                cmd_count_user(cfg, output).await
+            }
+            ("list", Some(matches)) => {
+                cmd_list_user(
+                    cfg,
+                    matches.is_present("json"),
+                    matches.is_present("with-repos"),
+                    output,
+                )
+                .await
+            }
+            ("export", Some(_matches)) => cmd_export(cfg, output).await,
+            ("inspect", Some(matches)) => cmd_inspect_hash(matches, cfg, output).await,
+            (name, _) => Err(anyhow::Error::msg(format!(
+                "user {} is not served over socket",
+                name
+            ))),
+        },
+        ("repo", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => cmd_repo_user_control(matches, cfg, false).await,
+            ("del", Some(matches)) => cmd_repo_user_control(matches, cfg, true).await,
+            (name, _) => Err(anyhow::Error::msg(format!(
+                "repo {} is not served over socket",
+                name
+            ))),
+        },
+        ("group", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => cmd_add_group(matches, cfg).await,
+            ("del", Some(matches)) => cmd_delete_group(matches, cfg).await,
+            ("grant", Some(matches)) => cmd_group_grant(matches, cfg).await,
+            ("member", Some(matches)) => cmd_group_member(matches, cfg).await,
+            (name, _) => Err(anyhow::Error::msg(format!(
+                "group {} is not served over socket",
+                name
+            ))),
+        },
+        ("maintenance", Some(matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            cmd_maintenance(matches, &cfg, store.as_mut(), output).await
+        }
+        ("count", Some(_matches)) => cmd_count_user(cfg, output).await,
+        ("metrics", Some(_matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            metrics::write_metrics(store.as_mut(), output).await
+        }
+        ("logout-all", Some(_matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            cmd_logout_all(store.as_mut()).await
+        }
+        ("healthcheck", Some(_matches)) => {
+            if !cmd_healthcheck(cfg, output).await? {
+                return Err(anyhow::Error::msg("Health check failed"));
+            }
+            Ok(())
+        }
+        (name @ ("authenticate-cookie" | "authenticate-post" | "body"), _) => Err(
+            anyhow::Error::msg(format!("auth-filter subcommand {} is not served", name)),
+        ),
+        (name, _) => Err(anyhow::Error::msg(format!(
+            "{} is not served over socket",
+            name
+        ))),
+    }
+}
+
+// Session store cleanup of committed batch operations.
+#[derive(Default)]
+struct BatchCleanup {
+    repos: Vec<String>,
+    users: Vec<String>,
+}
+
+// Hashing is slow, it is done before the batch transaction takes the write lock.
+async fn hash_batch_password(operation: &BatchOperation, cfg: &Config) -> Result<Option<String>> {
+    match operation.op.as_str() {
+        "adduser" | "passwd" => {
+            let user = datastructures::normalize_username(&operation.user);
+            check_user_and_password(&user, &operation.password)?;
+            Ok(Some(
+                get_password_hash(&operation.password, false, cfg).await?,
+            ))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn run_batch_operation(
+    conn: &mut SqliteConnection,
+    cfg: &Config,
+    operation: &BatchOperation,
+    password_hash: Option<String>,
+    cleanup: &mut BatchCleanup,
+) -> Result<()> {
+    let user = &datastructures::normalize_username(&operation.user);
+    let repo = operation.repo.as_str();
+    let group = operation.group.as_str();
+    let password_hash = password_hash.unwrap_or_default();
+    match operation.op.as_str() {
+        "adduser" => {
+            let uid = create_user(conn, user, &password_hash, None).await?;
+            eprintln!("Insert {} ({}) to database", user, uid);
+        }
+        "deluser" => {
+            let repos = delete_user(conn, user, operation.purge).await?;
+            cleanup.repos.extend(repos);
+            cleanup.users.push(user.to_string());
+        }
+        "passwd" => {
+            update_password(conn, cfg, user, &operation.password, &password_hash, false).await?;
+            cleanup.users.push(user.to_string());
+        }
+        "disable" => set_user_disabled(conn, user, true).await?,
+        "enable" => set_user_disabled(conn, user, false).await?,
+        "grant" => cleanup
+            .repos
+            .extend(update_repo_acl(conn, repo, user, false, false).await?),
+        "revoke" => cleanup
+            .repos
+            .extend(update_repo_acl(conn, repo, user, true, false).await?),
+        "addgroup" => create_group(conn, group).await?,
+        "delgroup" => cleanup.repos.extend(delete_group(conn, group).await?),
+        "addmember" => cleanup
+            .repos
+            .extend(set_group_member(conn, group, user, false).await?),
+        "delmember" => cleanup
+            .repos
+            .extend(set_group_member(conn, group, user, true).await?),
+        "grantgroup" => {
+            grant_group(conn, group, repo, false).await?;
+            cleanup.repos.push(repo.to_string());
+        }
+        "revokegroup" => {
+            grant_group(conn, group, repo, true).await?;
+            cleanup.repos.push(repo.to_string());
+        }
+        op => {
+            return Err(anyhow::Error::msg(format!(
+                "Unknown batch operation {}",
+                op
+            )))
+        }
+    }
+    Ok(())
+}
+
+// Run JSON array of operations read from input in one transaction, print JSON array of results.
+// Each operation runs in a savepoint, a failed one is rolled back alone and the others are
+// committed together at the end. Return false if any of them failed.
+pub(crate) async fn cmd_batch<R: Read, W: Write>(
+    cfg: Config,
+    reader: R,
+    writer: &mut W,
+) -> Result<bool> {
+    let operations: Vec<BatchOperation> = serde_json::from_reader(reader)?;
+    let mut hashes = Vec::with_capacity(operations.len());
+    for operation in &operations {
+        hashes.push(hash_batch_password(operation, &cfg).await);
+    }
+
+    let pool = cfg.connect_database().await?;
+    let mut transaction = pool.begin().await?;
+    let mut cleanup = BatchCleanup::default();
+    let mut results = Vec::with_capacity(operations.len());
+    for (operation, hash) in operations.iter().zip(hashes) {
+        let ret = match hash {
+            Ok(hash) => {
+                let mut savepoint = transaction.begin().await?;
+                match run_batch_operation(&mut savepoint, &cfg, operation, hash, &mut cleanup).await
+                {
+                    Ok(()) => savepoint.commit().await.map_err(anyhow::Error::from),
+                    Err(e) => {
+                        savepoint.rollback().await?;
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        };
+        results.push(match ret {
+            Ok(()) => BatchResult {
+                op: operation.op.clone(),
+                ok: true,
+                code: None,
+                error: None,
+            },
+            Err(e) => {
+                log::warn!("Batch operation {} failure: {:?}", operation.op, e);
+                BatchResult {
+                    op: operation.op.clone(),
+                    ok: false,
+                    code: Some(AuthError::exit_code_of(&e)),
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        });
+    }
+    transaction.commit().await?;
+    pool.close().await;
+
+    cleanup.repos.sort();
+    cleanup.repos.dedup();
+    if !cleanup.repos.is_empty() || !cleanup.users.is_empty() {
+        let mut store = connect_optional_session_store(&cfg).await;
+        if !cleanup.repos.is_empty() {
+            clear_cached_repos(store.as_mut().map(|x| x.as_mut() as _), &cleanup.repos).await;
+        }
+        for user in &cleanup.users {
+            cleanup_user_sessions(store.as_mut().map(|x| x.as_mut() as _), user).await;
+        }
+    }
+    if results.iter().any(|x| x.ok) {
+        cfg.write_database_commit_timestamp().await?;
+    }
+
+    serde_json::to_writer(&mut *writer, &results)?;
+    writeln!(writer)?;
+    Ok(results.iter().all(|x| x.ok))
+}
+
+// One command per line, arguments are split on whitespace. Output of command is followed by
+// a status line, `OK` or `ERR <exit code> <message>`.
+async fn serve_connection(stream: tokio::net::UnixStream, cfg: Config) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        let mut output = Vec::new();
+        let ret = match build_app().get_matches_from_safe(
+            std::iter::once(env!("CARGO_PKG_NAME")).chain(words.iter().copied()),
+        ) {
+            Ok(matches) => dispatch_served_command(&matches, cfg.clone(), &mut output).await,
+            Err(e) => Err(anyhow::Error::msg(
+                e.message.lines().next().unwrap_or("").to_string(),
+            )),
+        };
+        match ret {
+            Ok(()) => output.extend_from_slice(b"OK\n"),
+            Err(e) => {
+                // Only command name is logged, arguments may carry a password.
+                log::warn!("Served command {} failure: {:?}", words[0], e);
+                output.extend_from_slice(
+                    format!(
+                        "ERR {} {}\n",
+                        AuthError::exit_code_of(&e),
+                        format!("{:#}", e).replace('\n', " ")
+                    )
+                    .as_bytes(),
+                );
+            }
+        }
+        writer.write_all(&output).await?;
+    }
+    Ok(())
+}
+
+// Bind socket inside a fresh 0700 directory next to `path`, so it is never reachable with
+// permissions from umask, then move it into place once it is 0600.
+fn bind_private_socket(path: &str) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let private_dir = format!("{}.{}", path, std::process::id());
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let private_path = format!("{}/sock", private_dir);
+    let ret = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        // Replaces stale socket atomically.
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    // Directory is left empty unless binding failed halfway.
+    let cleanup = remove_file_if_exists(&private_path)
+        .and_then(|_| std::fs::remove_dir(&private_dir).map_err(Into::into));
+    let listener = ret?;
+    cleanup?;
+    Ok(listener)
+}
+
+// Serve management commands to admin tools, access is controlled by permissions of the socket.
+pub(crate) async fn cmd_serve(
+    matches: &ArgMatches<'_>,
+    cfg: Config,
+    interrupted: &Interrupted,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = matches.value_of("socket").unwrap();
+    // Socket left behind by previous run is replaced, a socket still answering belongs to
+    // running `serve` and other files are never touched.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow::Error::msg(format!(
+                "{} exists and is not a socket",
+                path
+            )));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow::Error::msg(format!(
+                "{} is in use by another serve process",
+                path
+            )));
+        }
+    }
+    let listener = bind_private_socket(path)?;
+    eprintln!("Listening on {}", path);
+
+    loop {
+        tokio::select! {
+            ret = listener.accept() => {
+                let (stream, _) = ret?;
+                let cfg = cfg.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_connection(stream, cfg).await {
+                        log::warn!("Serve connection failure: {:?}", e);
+                    }
+                });
+            }
+            _ = interrupted.wait() => break,
+        }
+    }
+    std::fs::remove_file(path)?;
+    eprintln!("Stop listening on {}", path);
+    Ok(())
+}
+
+pub(crate) async fn async_main(arg_matches: ArgMatches<'_>, mut cfg: Config) -> Result<i32> {
+    cfg.no_redis = arg_matches.is_present("no-redis");
+    log::debug!(
+        "{}",
+        format_arguments(&env::args().collect::<Vec<String>>(), cfg.log_sensitive)
+    );
+    // Reject invalid configuration before handling any command.
+    if uses_argon2_secret(&arg_matches) {
+        cfg.validate_with_secret()?;
+    } else {
+        cfg.validate()?;
+    }
+    match arg_matches.subcommand() {
+        ("authenticate-cookie", Some(matches)) => {
+            // Unprotected pages should not depend on session backend.
+            if check_bypass(matches, &cfg) {
+                return Ok(1);
+            }
+            let timeout = cfg.get_operation_timeout();
+            let mut store = with_timeout(timeout, cfg.connect_session_store()).await?;
+            // Every step of the request shares these pools.
+            let pools = RequestPools::default();
+            let ret = with_timeout(
+                timeout,
+                cmd_authenticate_cookie(matches, cfg, store.as_mut(), &pools),
+            )
+            .await;
+            pools.close().await;
+            if let Ok(should_pass) = ret {
+                if should_pass {
+                    return Ok(1);
+                }
+            }
+        }
+        ("authenticate-post", Some(matches)) => {
+            let timeout = cfg.get_operation_timeout();
+            // One more byte than allowed, so oversized body is still detected.
+            let input = if is_post_request(matches) {
+                read_input_with_timeout(std::io::stdin(), cfg.get_max_post_bytes() + 1, timeout)?
+            } else {
+                Some(Vec::new())
+            };
+            let timed_out = input.is_none();
+
+            let output = std::io::stdout();
+            let mut module = IOModule {
+                reader: std::io::Cursor::new(input.unwrap_or_default()),
+                writer: output,
+            };
+            if timed_out {
+                module.write_denial(LoginDenial::RequestTimeout)?;
+                return Err(anyhow::Error::msg(format!(
+                    "Login form was not received in {:?}",
+                    timeout
+                )));
+            }
+            let mut store = match with_timeout(timeout, cfg.connect_session_store()).await {
+                Ok(store) => store,
+                Err(e) => {
+                    module.write_denial(LoginDenial::Unavailable)?;
+                    return Err(e);
+                }
+            };
+            let pools = Arc::new(RequestPools::default());
+            let ret = tokio::time::timeout(
+                timeout,
+                module.cmd_authenticate_post(matches, cfg.clone(), store.as_mut(), &pools),
+            )
+            .await;
+            pools.close().await;
+            match ret {
+                Ok(ret) => ret?,
+                Err(_) => {
+                    module.write_denial(LoginDenial::Unavailable)?;
+                    return Err(anyhow::Error::msg(format!(
+                        "Operation timed out after {:?}",
+                        timeout
+                    )));
+                }
+            }
+        }
+        ("body", Some(matches)) => {
+            let stdin = std::io::stdin();
+            let input = stdin.lock();
+
+            let output = std::io::stdout();
+            let mut module = IOModule {
+                reader: input,
+                writer: output,
+            };
+            let timeout = cfg.get_operation_timeout();
+            let mut store = with_timeout(timeout, cfg.connect_session_store()).await?;
+            with_timeout(timeout, module.cmd_body(matches, cfg, store.as_mut())).await?;
+        }
+        ("user", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => {
+                cmd_add_user(matches, cfg).await?;
+            }
+            ("del", Some(matches)) => {
+                let mut store = connect_optional_session_store(&cfg).await;
+                cmd_delete_user(matches, cfg, store.as_mut().map(|x| x.as_mut() as _)).await?;
+            }
+            ("passwd", Some(matches)) => {
+                let mut store = connect_optional_session_store(&cfg).await;
+                cmd_change_password(matches, cfg, store.as_mut().map(|x| x.as_mut() as _)).await?;
+            }
+            ("verify", Some(matches)) => {
+                let stdin = std::io::stdin();
+                if !cmd_verify_password(matches, cfg, &mut stdin.lock()).await? {
+                    return Ok(1);
+                }
+            }
+            ("inspect", Some(matches)) => {
+                cmd_inspect_hash(matches, cfg, &mut std::io::stdout()).await?;
+            }
+            ("killsessions", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_kill_sessions(matches, store.as_mut()).await?;
+            }
+            ("sessions", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_list_sessions(matches, store.as_mut(), &mut std::io::stdout()).await?;
+            }
+            ("access", Some(matches)) => {
+                let mut store = cfg.connect_session_store().await?;
+                cmd_show_access(matches, cfg, store.as_mut(), &mut std::io::stdout()).await?;
+            }
+            ("disable", Some(matches)) => {
+                cmd_set_user_disabled(matches, cfg, true).await?;
+            }
+            ("enable", Some(matches)) => {
+                cmd_set_user_disabled(matches, cfg, false).await?;
+            }
+            ("import", Some(matches)) => {
+                if matches.is_present("restore") {
+                    cmd_restore_users(matches, cfg, &Interrupted::listen()?).await?;
+                } else {
+                    cmd_import_users(matches, cfg, &Interrupted::listen()?).await?;
+                }
+            }
+            ("export", Some(matches)) => match matches.value_of("output") {
+                Some(path) => cmd_export_to_file(cfg, path).await?,
+                None => cmd_export(cfg, &mut std::io::stdout()).await?,
+            },
+            ("list", Some(matches)) => {
+                if matches.is_present("count") {
+                    cmd_count_user(cfg, &mut std::io::stdout()).await?;
+                } else {
+                    cmd_list_user(
+                        cfg,
+                        matches.is_present("json"),
+                        matches.is_present("with-repos"),
+                        &mut std::io::stdout(),
+                    )
+                    .await?;
+                }
+            }
+            _ => {}
+        },
+        ("database", Some(matches)) => match matches.subcommand() {
+            ("init", Some(matches)) => {
+                cmd_init(cfg.clone()).await?;
+                cmd_init_admin(matches, cfg).await?;
+            }
+            ("upgrade", Some(_matches)) => {
+                cmd_upgrade_database(cfg, &Interrupted::listen()?).await?;
+            }
+            ("reset", Some(matches)) => {
+                cmd_reset_database(matches, cfg).await?;
+            }
+            ("schema-version", Some(_matches)) => {
+                let upgrade_required = cmd_schema_version(cfg).await?;
+                if upgrade_required {
+                    return Ok(1);
+                }
+            }
+            ("vacuum", Some(_matches)) => {
+                cmd_vacuum_database(cfg, &mut std::io::stdout()).await?;
+            }
+            ("check", Some(matches)) => {
+                let has_problem = cmd_check_database(matches, cfg).await?;
+                if has_problem {
+                    return Ok(1);
+                }
+            }
+            _ => {}
+        },
+        ("examples", Some(_matches)) => {
+            cmd_examples(&cfg, &env::current_exe()?, &mut std::io::stdout())?;
+        }
+        ("count", Some(_matches)) => {
+            cmd_count_user(cfg, &mut std::io::stdout()).await?;
+        }
+        ("metrics", Some(_matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            metrics::write_metrics(store.as_mut(), &mut std::io::stdout()).await?;
+        }
+        ("logout-all", Some(_matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            cmd_logout_all(store.as_mut()).await?;
+        }
+        ("maintenance", Some(matches)) => {
+            let mut store = cfg.connect_session_store().await?;
+            cmd_maintenance(matches, &cfg, store.as_mut(), &mut std::io::stdout()).await?;
+        }
+        ("serve", Some(matches)) => {
+            cmd_serve(matches, cfg, &Interrupted::listen()?).await?;
+        }
+        ("batch", Some(_matches)) => {
+            let stdin = std::io::stdin();
+            if !cmd_batch(cfg, stdin.lock(), &mut std::io::stdout()).await? {
+                return Ok(1);
+            }
+        }
+        ("healthcheck", Some(_matches)) => {
+            let healthy = cmd_healthcheck(cfg, &mut std::io::stdout()).await?;
+            if !healthy {
+                return Ok(1);
+            }
+        }
+        ("repo", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => cmd_repo_user_control(matches, cfg, false).await?,
+            ("del", Some(matches)) => {
+                cmd_repo_user_control(matches, cfg, true).await?;
+            }
+            ("list", Some(matches)) => {
+                cmd_list_repos_acl(matches, cfg).await?;
+            }
+            _ => {}
+        },
+        ("group", Some(matches)) => match matches.subcommand() {
+            ("add", Some(matches)) => {
+                cmd_add_group(matches, cfg).await?;
+            }
+            ("del", Some(matches)) => {
+                cmd_delete_group(matches, cfg).await?;
+            }
+            ("grant", Some(matches)) => {
+                cmd_group_grant(matches, cfg).await?;
+            }
+            ("member", Some(matches)) => {
+                cmd_group_member(matches, cfg).await?;
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+    Ok(0)
+}
+
+fn random_password_arg() -> Arg<'static, 'static> {
+    Arg::with_name("random")
+        .long("random")
+        .takes_value(true)
+        .min_values(0)
+        .max_values(1)
+        .value_name("length")
+        .conflicts_with_all(&["password", "hash"])
+        .help("Generate password of length (Default is 20) and print it once")
+}
+
+/// Crate version with git commit of the build, e.g. `4.0.0 (1a2b3c4)`.
+pub(crate) const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_COMMIT"), ")");
+
+// Shared by argument parsing and `completions`, so generated scripts never drift from real commands.
+pub(crate) fn build_app() -> App<'static, 'static> {
+    // Sub-arguments for each command, see cgi defines.
+    let sub_args = &[
+        Arg::with_name("http-cookie").required(true), // 2
+        Arg::with_name("request-method").required(true),
+        Arg::with_name("query-string").required(true),
+        Arg::with_name("http-referer").required(true), // 5
+        Arg::with_name("path-info").required(true),
+        Arg::with_name("http-host").required(true),
+        Arg::with_name("https").required(true),
+        Arg::with_name("repo").required(true),
+        Arg::with_name("page").required(true), // 10
+        Arg::with_name("current-url").required(true),
+        Arg::with_name("login-url").required(true),
+    ];
+
+    App::new("Simple Authentication Filter for cgit")
+        .version(VERSION)
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Increase log level, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .multiple(true)
+                .conflicts_with("verbose")
+                .help("Decrease log level, can be repeated"),
+        )
+        .arg(
+            Arg::with_name("worker-threads")
+                .long("worker-threads")
+                .takes_value(true)
+                .value_name("N")
+                .help(
+                    "Run management commands on N worker threads (Default runs on current thread)",
+                ),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("name")
+                .help("Override options with cgit-simple-auth-profile.<name>.* ones in cgitrc"),
+        )
+        .arg(
+            Arg::with_name("no-redis").long("no-redis").help(
+                "Skip Redis in management commands, sessions and cached ACL are not cleaned up",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("authenticate-cookie")
+                .about("Processing authenticated cookie")
+                .args(sub_args)
+                .setting(AppSettings::Hidden),
+        )
+        .subcommand(
+            SubCommand::with_name("authenticate-post")
+                .about("Processing posted username and password")
+                .args(sub_args)
+                .setting(AppSettings::Hidden),
+        )
+        .subcommand(
+            SubCommand::with_name("body")
+                .about("Return the login form")
+                .args(sub_args)
+                .setting(AppSettings::Hidden),
+        )
+        .subcommand(
+            SubCommand::with_name("database")
+                .about("Database rated commands")
+                .subcommand(
+                    SubCommand::with_name("init")
+                        .about("Init sqlite database")
+                        .arg(
+                            Arg::with_name("admin-user")
+                                .long("admin-user")
+                                .takes_value(true)
+                                .requires("admin-password")
+                                .help("Create first user after initialization"),
+                        )
+                        .arg(
+                            Arg::with_name("admin-password")
+                                .long("admin-password")
+                                .takes_value(true)
+                                .requires("admin-user"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("reset")
+                        .about("Reset database")
+                        .arg(Arg::with_name("confirm").long("confirm"))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("upgrade")
+                        .about("Upgrade database to current schema version")
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("schema-version")
+                        .about("Print database schema version, exit 1 if upgrade is required")
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Check database integrity")
+                        .arg(Arg::with_name("fix").long("fix"))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("vacuum")
+                        .about("Checkpoint WAL and compact database file")
+                        .display_order(0),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("examples")
+                .about("Print cgitrc lines to use this binary as auth-filter")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Print number of users, same as `user list --count`")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("metrics")
+                .about("Print login and cookie counters in Prometheus text format")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("logout-all")
+                .about("Remove sessions of all users")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("maintenance")
+                .about("Deny new logins for maintenance, or print current state")
+                .arg(
+                    Arg::with_name("state")
+                        .required(true)
+                        .possible_values(&["on", "off", "status"]),
+                )
+                .arg(
+                    Arg::with_name("deny-sessions")
+                        .long("deny-sessions")
+                        .help("Also reject existing sessions while it is on"),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run management commands received on a Unix socket, one per line")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Socket path, it is created readable and writable by owner only"),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Run JSON array of operations from stdin, exit 1 if any of them fails")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("healthcheck")
+                .about("Check database and session backend, exit 1 if any check fails")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("buildinfo")
+                .about("Print version, git commit and compiled features")
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("Users rated commands")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add user to database")
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(Arg::with_name("password").required_unless("random"))
+                        .arg(Arg::with_name("hash").long("hash"))
+                        .arg(random_password_arg())
+                        .arg(
+                            Arg::with_name("expires")
+                                .long("expires")
+                                .takes_value(true)
+                                .help("Unix timestamp, or duration from now like 30d, 12h"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("disable")
+                        .about("Reject login and sessions of user")
+                        .arg(Arg::with_name("user").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("enable")
+                        .about("Allow disabled user to login again")
+                        .arg(Arg::with_name("user").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("passwd")
+                        .about("Change password of user")
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(Arg::with_name("password").required_unless("random"))
+                        .arg(Arg::with_name("hash").long("hash"))
+                        .arg(random_password_arg())
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Check password read from stdin, exit 1 if it does not match")
+                        .arg(Arg::with_name("user").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Show algorithm and cost parameters of stored password hash")
+                        .arg(Arg::with_name("user").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("del")
+                        .about("Delete user from database")
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(
+                            Arg::with_name("purge")
+                                .long("purge")
+                                .help("Also remove user from repository ACLs and groups"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("killsessions")
+                        .about("Log out user from all active sessions")
+                        .arg(Arg::with_name("user").required_unless("all"))
+                        .arg(
+                            Arg::with_name("all")
+                                .long("all")
+                                .conflicts_with("user")
+                                .help("Log out every user"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("access")
+                        .about("Show repositories user can access and cached ACL state")
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(Arg::with_name("json").long("json").help("Print as JSON"))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("sessions")
+                        .about("List active sessions of user")
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(Arg::with_name("json").long("json").help("Print as JSON"))
+                        .arg(
+                            Arg::with_name("older-than")
+                                .long("older-than")
+                                .takes_value(true)
+                                .help(
+                                    "Only sessions logged in longer ago than duration like 7d, 12h",
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("kill")
+                                .long("kill")
+                                .requires("older-than")
+                                .help("Remove matched sessions instead of listing them"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Import users from file, one `user,password` per line")
+                        .arg(Arg::with_name("file").help("Read from standard input if omitted"))
+                        .arg(
+                            Arg::with_name("input")
+                                .long("input")
+                                .takes_value(true)
+                                .value_name("path")
+                                .conflicts_with("file")
+                                .help("Same as file argument"),
+                        )
+                        .arg(Arg::with_name("hash").long("hash"))
+                        .arg(Arg::with_name("strict").long("strict"))
+                        .arg(
+                            Arg::with_name("restore")
+                                .long("restore")
+                                .conflicts_with("hash"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Export all users and repository ACL as JSON")
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .takes_value(true)
+                                .value_name("path")
+                                .help("Write to file atomically instead of standard output"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List all users with their last login")
+                        .arg(Arg::with_name("json").long("json").help("Print as JSON"))
+                        .arg(
+                            Arg::with_name("count")
+                                .long("count")
+                                .conflicts_with_all(&["json", "with-repos"])
+                                .help("Only print number of users"),
+                        )
+                        .arg(
+                            Arg::with_name("with-repos")
+                                .long("with-repos")
+                                .help("Show repositories each user can access, including by group"),
+                        )
+                        .display_order(0),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("repo")
+                .about("Repository ACL rated commands")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add user to repository")
+                        .arg(Arg::with_name("repo").required(true))
+                        .arg(Arg::with_name("user").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("del")
+                        .about("Del user from repository")
+                        .arg(Arg::with_name("repo").required(true))
+                        .arg(Arg::with_name("user").takes_value(true))
+                        .arg(
+                            Arg::with_name("clear-all")
+                                .long("clear-all")
+                                .conflicts_with("user"),
+                        )
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Show all repositories or only show specify repository detail")
+                        .arg(Arg::with_name("repo").takes_value(true))
+                        .display_order(0),
+                )
+                .display_order(0),
+        )
+        .subcommand(
+            SubCommand::with_name("group")
+                .about("Group rated commands")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add group to database")
+                        .arg(Arg::with_name("group").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("del")
+                        .about("Delete group from database")
+                        .arg(Arg::with_name("group").required(true))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("grant")
+                        .about("Grant group access to repository")
+                        .arg(Arg::with_name("group").required(true))
+                        .arg(Arg::with_name("repo").required(true))
+                        .arg(Arg::with_name("revoke").long("revoke"))
+                        .display_order(0),
+                )
+                .subcommand(
+                    SubCommand::with_name("member")
+                        .about("Add or remove user of group")
+                        .arg(Arg::with_name("group").required(true))
+                        .arg(Arg::with_name("user").required(true))
+                        .arg(Arg::with_name("remove").long("remove"))
+                        .display_order(0),
+                )
+                .display_order(0),
+        )
+}
+
+pub(crate) fn get_arg_matches(arguments: Option<Vec<&str>>) -> ArgMatches<'_> {
+    let app = build_app();
+    let matches = if let Some(args) = arguments {
+        app.get_matches_from(args)
+    } else {
+        app.get_matches()
+    };
+
+    matches
+}
+
+pub(crate) fn cmd_buildinfo<W: Write>(writer: &mut W) -> Result<()> {
+    writeln!(writer, "version: {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(writer, "commit: {}", env!("GIT_COMMIT"))?;
+    writeln!(writer, "target: {}", env!("BUILD_TARGET"))?;
+    writeln!(writer, "profile: {}", env!("BUILD_PROFILE"))?;
+    // Backends are always compiled in, redis 0.17 has no TLS usable on this runtime.
+    writeln!(writer, "session backends: redis sqlite")?;
+    writeln!(writer, "redis tls: no")?;
+    writeln!(writer, "pam: yes")?;
+    Ok(())
+}
+
+pub(crate) fn cmd_completions<W: Write>(shell: &str, writer: &mut W) -> Result<()> {
+    let shell = Shell::from_str(shell).map_err(anyhow::Error::msg)?;
+    build_app().gen_completions_to(env!("CARGO_PKG_NAME"), shell, writer);
+    Ok(())
+}
+
+/// cgit reads exit status of filter calls as authentication result.
+pub(crate) fn is_filter_call(matches: &ArgMatches<'_>) -> bool {
+    matches!(
+        matches.subcommand_name(),
+        Some("authenticate-cookie") | Some("authenticate-post") | Some("body")
+    )
+}
+
+// Management commands hashing or verifying passwords, only they read argon2 secret file up front.
+pub(crate) fn uses_argon2_secret(matches: &ArgMatches<'_>) -> bool {
+    match matches.subcommand() {
+        ("user", Some(matches)) => matches!(
+            matches.subcommand_name(),
+            Some("add") | Some("passwd") | Some("verify") | Some("import")
+        ),
+        ("database", Some(matches)) => matches.subcommand_name() == Some("init"),
+        ("batch", _) | ("serve", _) => true,
+        _ => false,
+    }
+}
+
+// Filter calls serve a single request, so they always run on current thread.
+pub(crate) fn get_worker_threads(matches: &ArgMatches<'_>) -> Result<Option<usize>> {
+    if is_filter_call(matches) {
+        return Ok(None);
+    }
+    matches
+        .value_of("worker-threads")
+        .map(|value| match value.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow::Error::msg(format!(
+                "worker-threads should be a positive number, got {}",
+                value
+            ))),
+        })
+        .transpose()
+}
+
+pub(crate) fn build_runtime(worker_threads: Option<usize>) -> Result<tokio::runtime::Runtime> {
+    let mut builder = match worker_threads {
+        Some(n) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(n);
+            builder
+        }
+        None => tokio::runtime::Builder::new_current_thread(),
+    };
+    Ok(builder.enable_all().build()?)
+}
+
+pub(crate) fn process_arguments(matches: ArgMatches<'_>) -> Result<()> {
+    // Need neither configuration nor runtime, work before cgitrc exists.
+    match matches.subcommand() {
+        ("completions", Some(matches)) => {
+            return cmd_completions(matches.value_of("shell").unwrap(), &mut std::io::stdout())
+        }
+        ("buildinfo", Some(_matches)) => return cmd_buildinfo(&mut std::io::stdout()),
+        _ => {}
+    }
+    let cfg = if std::env::args().any(|x| x.eq("--test")) {
+        Config::generate_test_config()
+    } else if let Some(profile) = matches.value_of("profile") {
+        Config::load_profile_from_path(datastructures::DEFAULT_CONFIG_LOCATION, profile)
+    } else {
+        Config::new()
+    };
+    let ret = build_runtime(get_worker_threads(&matches)?)?.block_on(async_main(matches, cfg))?;
+    if ret == 1 {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+const LOG_LEVELS: &[log::LevelFilter] = &[
+    log::LevelFilter::Off,
+    log::LevelFilter::Error,
+    log::LevelFilter::Warn,
+    log::LevelFilter::Info,
+    log::LevelFilter::Debug,
+    log::LevelFilter::Trace,
+];
+
+// RUST_LOG selects base level (Default is info), each -v or -q moves it one level.
+pub(crate) fn get_log_level(rust_log: Option<&str>, verbose: u64, quiet: u64) -> log::LevelFilter {
+    let base = rust_log
+        .and_then(|level| log::LevelFilter::from_str(level.trim()).ok())
+        .unwrap_or(log::LevelFilter::Info);
+    let index = LOG_LEVELS.iter().position(|level| *level == base).unwrap() as i64 + verbose as i64
+        - quiet as i64;
+    LOG_LEVELS[index.clamp(0, LOG_LEVELS.len() as i64 - 1) as usize]
+}
+
+/// Parse command line, set up logging and run the command.
+pub fn run() -> Result<()> {
+    let matches = get_arg_matches(None);
+    let level = get_log_level(
+        env::var("RUST_LOG").ok().as_deref(),
+        matches.occurrences_of("verbose"),
+        matches.occurrences_of("quiet"),
+    );
+
+    let config = build_log_config(
+        LogTarget::from(env::var("LOG_TARGET").unwrap_or_default().as_str()),
+        level,
+        &env::var("LOG_FILE").unwrap_or_else(|_| "/var/cache/cgit/auth.log".to_string()),
+    )?;
+
+    log4rs::init_config(config)?;
+
+    // Only management commands report failure through exit code.
+    let is_filter_call = is_filter_call(&matches);
+    if let Err(e) = process_arguments(matches) {
+        log::error!("{:?}", e);
+        if !is_filter_call {
+            eprintln!("Error: {}", e);
+            std::process::exit(AuthError::exit_code_of(&e));
+        }
+    };
+
+    Ok(())
+}
@@ -78,22 +78,266 @@ pub mod v3 {
         PRIMARY KEY("repo")
     );
 
+    CREATE TABLE "groups" (
+        "group"	TEXT NOT NULL,
+        PRIMARY KEY("group")
+    );
+
+    CREATE TABLE "group_members" (
+        "group"	TEXT NOT NULL,
+        "user"	TEXT NOT NULL,
+        PRIMARY KEY("group", "user")
+    );
+
+    CREATE TABLE "group_repos" (
+        "group"	TEXT NOT NULL,
+        "repo"	TEXT NOT NULL,
+        PRIMARY KEY("group", "repo")
+    );
+
     INSERT INTO "auth_meta" VALUES ('version', '3');
     "#;
 
+    // Group tables were added after v3 released, `database init` create them on existing database.
+    pub const CREATE_GROUP_TABLES: &str = r#"
+    CREATE TABLE IF NOT EXISTS "groups" (
+        "group"	TEXT NOT NULL,
+        PRIMARY KEY("group")
+    );
+
+    CREATE TABLE IF NOT EXISTS "group_members" (
+        "group"	TEXT NOT NULL,
+        "user"	TEXT NOT NULL,
+        PRIMARY KEY("group", "user")
+    );
+
+    CREATE TABLE IF NOT EXISTS "group_repos" (
+        "group"	TEXT NOT NULL,
+        "repo"	TEXT NOT NULL,
+        PRIMARY KEY("group", "repo")
+    );
+    "#;
+
+    // Only used by sqlite session backend, created on first use.
+    pub const CREATE_SESSION_TABLES: &str = r#"
+    CREATE TABLE IF NOT EXISTS "sessions" (
+        "key"	TEXT NOT NULL,
+        "body"	TEXT NOT NULL,
+        "expires_at"	INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE IF NOT EXISTS "login_attempts" (
+        "key"	TEXT NOT NULL,
+        "timestamp"	INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS "user_sessions" (
+        "user"	TEXT NOT NULL,
+        "key"	TEXT NOT NULL,
+        PRIMARY KEY("user", "key")
+    );
+
+    CREATE TABLE IF NOT EXISTS "counters" (
+        "key"	TEXT NOT NULL,
+        "value"	INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+    "#;
+
+    // Previous password hashes by uid, only used when `password-history-size` is set, created on first use.
+    pub const CREATE_PASSWORD_HISTORY_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS "password_history" (
+        "uid"	TEXT NOT NULL,
+        "password"	TEXT NOT NULL,
+        "changed_at"	INTEGER NOT NULL
+    );
+    "#;
+
+    // v2 repository ACLs are keyed by uid and can not be converted, accounts are kept as is.
+    pub const MIGRATE_FROM_V2: &str = r#"
+    DROP TABLE "repo";
+
+    CREATE TABLE "repos" (
+        "repo"	TEXT NOT NULL,
+        "users" TEXT NOT NULL,
+        PRIMARY KEY("repo")
+    );
+    "#;
+
     pub const DROP_TABLES: &str = r#"
 
     DROP TABLE "accounts";
 
     DROP TABLE "repos";
 
+    DROP TABLE IF EXISTS "groups";
+
+    DROP TABLE IF EXISTS "group_members";
+
+    DROP TABLE IF EXISTS "group_repos";
+
+    DROP TABLE IF EXISTS "password_history";
+
     DROP TABLE "auth_meta";
     "#;
 
     pub const VERSION: &str = "3";
 }
 
+#[allow(dead_code, unused_imports)]
+pub mod v4 {
+    pub use super::v3::{CREATE_GROUP_TABLES, CREATE_SESSION_TABLES};
+
+    pub const CREATE_TABLES: &str = r#"
+    CREATE TABLE "accounts" (
+        "user"	TEXT NOT NULL,
+        "password"	TEXT NOT NULL,
+        "uid" TEXT NOT NULL,
+        "expires_at"	INTEGER,
+        "disabled"	INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY("user")
+    );
+
+    CREATE TABLE "auth_meta" (
+        "key"	TEXT NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "repos" (
+        "repo"	TEXT NOT NULL,
+        "users" TEXT NOT NULL,
+        PRIMARY KEY("repo")
+    );
+
+    CREATE TABLE "groups" (
+        "group"	TEXT NOT NULL,
+        PRIMARY KEY("group")
+    );
+
+    CREATE TABLE "group_members" (
+        "group"	TEXT NOT NULL,
+        "user"	TEXT NOT NULL,
+        PRIMARY KEY("group", "user")
+    );
+
+    CREATE TABLE "group_repos" (
+        "group"	TEXT NOT NULL,
+        "repo"	TEXT NOT NULL,
+        PRIMARY KEY("group", "repo")
+    );
+
+    INSERT INTO "auth_meta" VALUES ('version', '4');
+    "#;
+
+    pub const MIGRATE_FROM_V3: &str = r#"
+    ALTER TABLE "accounts" ADD COLUMN "expires_at" INTEGER;
+
+    ALTER TABLE "accounts" ADD COLUMN "disabled" INTEGER NOT NULL DEFAULT 0;
+    "#;
+
+    pub use super::v3::DROP_TABLES;
+
+    pub const VERSION: &str = "4";
+}
+
+pub mod v5 {
+    pub use super::v3::{
+        CREATE_GROUP_TABLES, CREATE_PASSWORD_HISTORY_TABLE, CREATE_SESSION_TABLES,
+    };
+
+    pub const CREATE_TABLES: &str = r#"
+    CREATE TABLE "accounts" (
+        "user"	TEXT NOT NULL,
+        "password"	TEXT NOT NULL,
+        "uid" TEXT NOT NULL,
+        "expires_at"	INTEGER,
+        "disabled"	INTEGER NOT NULL DEFAULT 0,
+        "last_login"	INTEGER,
+        "last_host"	TEXT,
+        PRIMARY KEY("user")
+    );
+
+    CREATE TABLE "auth_meta" (
+        "key"	TEXT NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "repos" (
+        "repo"	TEXT NOT NULL,
+        "users" TEXT NOT NULL,
+        PRIMARY KEY("repo")
+    );
+
+    CREATE TABLE "groups" (
+        "group"	TEXT NOT NULL,
+        PRIMARY KEY("group")
+    );
+
+    CREATE TABLE "group_members" (
+        "group"	TEXT NOT NULL,
+        "user"	TEXT NOT NULL,
+        PRIMARY KEY("group", "user")
+    );
+
+    CREATE TABLE "group_repos" (
+        "group"	TEXT NOT NULL,
+        "repo"	TEXT NOT NULL,
+        PRIMARY KEY("group", "repo")
+    );
+
+    INSERT INTO "auth_meta" VALUES ('version', '5');
+    "#;
+
+    pub const MIGRATE_FROM_V4: &str = r#"
+    ALTER TABLE "accounts" ADD COLUMN "last_login" INTEGER;
+
+    ALTER TABLE "accounts" ADD COLUMN "last_host" TEXT;
+    "#;
+
+    pub use super::v3::DROP_TABLES;
+
+    pub const VERSION: &str = "5";
+}
+
+/// Schema written by a later release, this binary can not tell what its tables mean.
+pub fn is_newer_than_current(version: &str) -> bool {
+    match (version.parse::<u32>(), current::VERSION.parse::<u32>()) {
+        (Ok(version), Ok(current)) => version > current,
+        _ => false,
+    }
+}
+
+pub struct Migration {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+// Applied in order until database reaches current version.
 #[allow(deprecated)]
-pub use v2 as previous;
-pub use v3 as current;
-pub use v3::VERSION;
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: v2::VERSION,
+        to: v3::VERSION,
+        statements: &[v3::MIGRATE_FROM_V2, v3::CREATE_GROUP_TABLES],
+    },
+    Migration {
+        from: v3::VERSION,
+        to: v4::VERSION,
+        statements: &[v3::CREATE_GROUP_TABLES, v4::MIGRATE_FROM_V3],
+    },
+    Migration {
+        from: v4::VERSION,
+        to: v5::VERSION,
+        statements: &[v5::MIGRATE_FROM_V4],
+    },
+];
+
+#[allow(unused_imports)]
+pub use v4 as previous;
+pub use v5 as current;
+#[allow(unused_imports)]
+pub use v5::VERSION;
@@ -0,0 +1,75 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+/// Error kinds of user management commands, they are returned wrapped in `anyhow::Error`
+/// and can be told apart with `downcast_ref::<AuthError>()`.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("User already exists!")]
+    UserExists,
+    #[error("User {0} not found")]
+    UserNotFound(String),
+    #[error("{0}")]
+    InvalidUsername(String),
+    #[error("{0}")]
+    WeakPassword(String),
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    InvalidConfig(Vec<String>),
+    #[error(
+        "Database schema version {0} is newer than version {} supported by this binary, \
+        binary is too old for this database",
+        crate::database::current::VERSION
+    )]
+    SchemaTooNew(String),
+    #[error("Database is in an inconsistent state, {0}")]
+    InconsistentDatabase(String),
+    #[error(transparent)]
+    DbError(#[from] sqlx::Error),
+    #[error(transparent)]
+    RedisError(#[from] redis::RedisError),
+}
+
+impl AuthError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AuthError::InvalidUsername(_) | AuthError::WeakPassword(_) => 2,
+            AuthError::UserExists => 3,
+            AuthError::UserNotFound(_) => 4,
+            AuthError::DbError(_)
+            | AuthError::SchemaTooNew(_)
+            | AuthError::InconsistentDatabase(_) => 5,
+            AuthError::RedisError(_) => 6,
+            AuthError::InvalidConfig(_) => 7,
+        }
+    }
+
+    /// Exit code for command failure, database and Redis errors count even if not wrapped.
+    pub fn exit_code_of(err: &anyhow::Error) -> i32 {
+        if let Some(err) = err.downcast_ref::<AuthError>() {
+            err.exit_code()
+        } else if err.is::<sqlx::Error>() {
+            5
+        } else if err.is::<redis::RedisError>() {
+            6
+        } else {
+            1
+        }
+    }
+}
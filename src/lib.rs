@@ -0,0 +1,518 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Authentication filter for cgit, the binary is a thin wrapper over [`run`].
+//!
+//! Cookie validation and password verification can also be embedded directly,
+//! see [`validate_cookie`] and [`verify_login`].
+
+mod cli;
+mod database;
+mod datastructures;
+mod error;
+mod logger;
+mod metrics;
+mod session;
+mod test;
+
+pub use crate::cli::run;
+pub use crate::datastructures::{Config, Cookie, FormData, RequestPools, WrapConfigure};
+pub use crate::error::AuthError;
+pub use crate::logger::{build_log_config, LogTarget};
+pub use crate::session::{SessionBackend, SessionStore};
+
+use crate::datastructures::MaintenanceMode;
+use crate::datastructures::{CSRF_TOKEN_LENGTH, CSRF_TOKEN_TTL};
+use crate::session::SESSIONS_NOT_BEFORE_KEY;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::env;
+use std::result::Result::Ok;
+use std::time::Duration;
+use tokio_stream::StreamExt as _;
+
+// Stalled Redis or database must not hang cgit.
+async fn with_timeout<T>(
+    timeout: Duration,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| anyhow::Error::msg(format!("Operation timed out after {:?}", timeout)))?
+}
+
+// Management commands only use session backend to clean up sessions and cached ACL,
+// database work should not fail because Redis is unreachable or skipped by `--no-redis`.
+async fn connect_optional_session_store(cfg: &Config) -> Option<Box<dyn SessionStore>> {
+    if cfg.no_redis && cfg.get_session_backend() == SessionBackend::Redis {
+        return None;
+    }
+    match with_timeout(cfg.get_operation_timeout(), cfg.connect_session_store()).await {
+        Ok(store) => Some(store),
+        Err(e) => {
+            log::warn!("Connect session backend failure: {:?}", e);
+            None
+        }
+    }
+}
+
+const MAINTENANCE_KEY: &str = "cgit_maintenance_mode";
+
+// cgitrc and `maintenance on` can both enable maintenance, the stricter one wins.
+async fn get_maintenance_mode(
+    cfg: &Config,
+    store: &mut dyn SessionStore,
+) -> Result<MaintenanceMode> {
+    let stored = MaintenanceMode::from_counter(store.get_counter(MAINTENANCE_KEY).await?);
+    let configured = cfg.get_maintenance_mode();
+    Ok(if stored > configured {
+        stored
+    } else {
+        configured
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoginDenial {
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    MissingFields,
+    InvalidForm,
+    /// Seconds until login is allowed again.
+    TooManyAttempts(u64),
+    BadCredentials,
+    HostNotAllowed,
+    /// Session backend unreachable or too slow.
+    Unavailable,
+    /// Database or session backend failed while checking login.
+    InternalError,
+    Maintenance,
+    /// Nonce does not solve proof of work of the form.
+    InsufficientProof,
+}
+
+impl LoginDenial {
+    fn get_status(&self) -> &'static str {
+        match self {
+            LoginDenial::MethodNotAllowed => "405 Method Not Allowed",
+            LoginDenial::RequestTimeout => "408 Request Timeout",
+            LoginDenial::PayloadTooLarge => "413 Payload Too Large",
+            LoginDenial::MissingFields | LoginDenial::HostNotAllowed => "400 Bad Request",
+            LoginDenial::InvalidForm
+            | LoginDenial::BadCredentials
+            | LoginDenial::InsufficientProof => "403 Forbidden",
+            LoginDenial::TooManyAttempts(_) => "429 Too Many Requests",
+            LoginDenial::Unavailable | LoginDenial::Maintenance => "503 Service Unavailable",
+            LoginDenial::InternalError => "500 Internal Server Error",
+        }
+    }
+
+    // Label of `cgit_simple_auth_logins_total`, see `metrics::LOGIN_OUTCOMES`.
+    fn get_metric_outcome(&self) -> &'static str {
+        match self {
+            LoginDenial::BadCredentials => "failure",
+            LoginDenial::TooManyAttempts(_) => "locked_out",
+            LoginDenial::Unavailable | LoginDenial::InternalError => "error",
+            _ => "rejected",
+        }
+    }
+
+    fn get_message(&self) -> &'static str {
+        match self {
+            LoginDenial::MethodNotAllowed => "Login form must be submitted with POST",
+            LoginDenial::RequestTimeout => "Login form was not received in time",
+            LoginDenial::PayloadTooLarge => "Login form is too large",
+            LoginDenial::MissingFields => "Login form must post username and password fields",
+            LoginDenial::InvalidForm => "Login form expired, please reload the page and try again",
+            LoginDenial::TooManyAttempts(_) => "Too many failed login attempts",
+            LoginDenial::BadCredentials => "Invalid username or password",
+            LoginDenial::HostNotAllowed => "Login is not accepted on this host",
+            LoginDenial::Unavailable => {
+                "Authentication service is unavailable, please try again later"
+            }
+            LoginDenial::InternalError => {
+                "Authentication failed because of an internal error, please try again later"
+            }
+            LoginDenial::Maintenance => "Login is disabled for maintenance, please try again later",
+            LoginDenial::InsufficientProof => {
+                "Login form was not verified by browser, please enable JavaScript and try again"
+            }
+        }
+    }
+}
+
+// Return cookie of new session, or the reason why login is denied.
+async fn login(
+    cfg: &WrapConfigure,
+    data: &FormData,
+    host: &str,
+    store: &mut dyn SessionStore,
+) -> Result<std::result::Result<Cookie, LoginDenial>> {
+    if !data.has_credential_fields() {
+        return Ok(Err(LoginDenial::MissingFields));
+    }
+
+    // Such name is no account of any authorizer, and must never reach rate limit keys,
+    // audit log or a session.
+    if data.get_user().chars().any(char::is_control) {
+        log::warn!(
+            "Reject login of {:?}, username has control characters",
+            data.get_user()
+        );
+        return Ok(Err(LoginDenial::BadCredentials));
+    }
+
+    // Refused before CSRF token is consumed or any account is looked up.
+    if get_maintenance_mode(cfg.get_config(), store).await? != MaintenanceMode::Off {
+        log::info!("Reject login of {} during maintenance", data.get_user());
+        return Ok(Err(LoginDenial::Maintenance));
+    }
+
+    if !consume_csrf_token(store, data.get_csrf_token()).await? {
+        log::warn!("Reject login post without valid CSRF token");
+        return Ok(Err(LoginDenial::InvalidForm));
+    }
+
+    // CSRF token is the challenge, so each proof is good for one attempt only.
+    if !datastructures::verify_proof_of_work(
+        data.get_csrf_token(),
+        data.get_pow_nonce(),
+        cfg.get_config().get_pow_difficulty()?,
+    ) {
+        log::warn!("Reject login of {} without proof of work", data.get_user());
+        return Ok(Err(LoginDenial::InsufficientProof));
+    }
+
+    let client = get_client_identity(cfg.get_config(), host);
+    if let Some(remaining) =
+        check_rate_limited(cfg.get_config(), store, &client, data.get_user()).await?
+    {
+        log::warn!(
+            "Reject login of {} from {}, too many failed attempts",
+            data.get_user(),
+            client
+        );
+        return Ok(Err(LoginDenial::TooManyAttempts(remaining)));
+    }
+
+    // Empty username or password is never checked against authorizer.
+    if data.has_empty_credentials() || !verify_login(cfg, data).await? {
+        record_failed_attempt(cfg.get_config(), store, &client, data.get_user()).await?;
+        return Ok(Err(LoginDenial::BadCredentials));
+    }
+
+    // Failing to record login metadata should not reject a valid login.
+    if let Err(e) = record_login(cfg, data.get_user(), &client).await {
+        log::warn!("Unable to record login of {}: {:?}", data.get_user(), e);
+    }
+
+    let cookie = Cookie::generate(data.get_user());
+    // Stateless session is not refreshed on use, it is kept until cookie expires.
+    let ttl = if cfg.get_config().is_stateless_sessions() {
+        cfg.get_config().get_cookie_max_age()
+    } else {
+        cfg.get_config().cookie_ttl
+    };
+    store
+        .set_session(
+            &format!("cgit_auth_{}", cookie.get_key()),
+            &cookie.get_body(),
+            ttl,
+        )
+        .await?;
+    store
+        .add_user_session(data.get_user(), &cookie.get_key())
+        .await?;
+    Ok(Ok(cookie))
+}
+
+// Authorizer only reads through the replica pool, login metadata is written through the primary one.
+async fn record_login(cfg: &WrapConfigure, user: &str, host: &str) -> Result<()> {
+    let pool = cfg.get_pools().primary(cfg.get_config()).await?;
+    sqlx::query(r#"UPDATE "accounts" SET "last_login" = ?, "last_host" = ? WHERE "user" = ?"#)
+        .bind(datastructures::get_current_timestamp() as i64)
+        .bind(host)
+        .bind(user)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Remove every session recorded for user, returns how many were still alive.
+// Stateless cookie stays valid without stored session, so a marker is kept for as long as
+// the session would have lived to reject it.
+async fn revoke_session(store: &mut dyn SessionStore, key: &str) -> Result<bool> {
+    let session_key = format!("cgit_auth_{}", key);
+    let ttl = store.get_session_ttl(&session_key).await?;
+    let existed = store.delete_session(&session_key).await?;
+    if let Some(ttl) = ttl.filter(|ttl| *ttl > 0) {
+        store
+            .set_session(&format!("cgit_revoked_{}", key), "", ttl)
+            .await?;
+    }
+    Ok(existed)
+}
+
+async fn kill_user_sessions(store: &mut dyn SessionStore, user: &str) -> Result<usize> {
+    let keys = store.get_user_sessions(user).await?;
+    let mut killed = 0;
+    for key in &keys {
+        if revoke_session(store, key).await? {
+            killed += 1;
+        }
+    }
+    store.remove_user_sessions(user, &keys).await?;
+    Ok(killed)
+}
+
+/// Check `cgit_auth` cookie in a `Cookie` header against session store,
+/// return user of the session if it may read `repo` (empty for pages outside repositories).
+/// Accounts are read through the replica pool of `pools`, opened at most once per request.
+pub async fn validate_cookie(
+    cfg: &Config,
+    cookies: &str,
+    repo: &str,
+    store: &mut dyn SessionStore,
+    pools: &RequestPools,
+) -> Result<Option<String>> {
+    if !repo.is_empty() && !store.has_repo_set(repo).await? {
+        let pool = pools.replica(cfg).await?;
+        let users = get_repo_users(pool, repo).await?;
+        store
+            .set_repo_set(repo, &users, cfg.get_repo_cache_ttl())
+            .await?;
+    }
+
+    let cookie = match Cookie::load_from_request(cookies) {
+        Ok(Some(cookie)) => cookie,
+        _ => return Ok(None),
+    };
+    let session_key = format!("cgit_auth_{}", cookie.get_key());
+    let secret = cfg.get_cookie_secret()?;
+    let valid = match secret.filter(|_| cfg.is_stateless_sessions()) {
+        // Signature replaces session lookup, so sessions survive flush of session store
+        // and only revoked cookies and time of last global logout have to be looked up.
+        // Cookie issued in the same second as global logout is rejected too.
+        Some(secret) => {
+            cookie
+                .verify_signature(&secret)
+                .is_some_and(|expires_at| expires_at > datastructures::get_current_timestamp())
+                && cookie.get_timestamp() > store.get_counter(SESSIONS_NOT_BEFORE_KEY).await?
+                && store
+                    .get_session(&format!("cgit_revoked_{}", cookie.get_key()))
+                    .await?
+                    .is_none()
+        }
+        None => match store.get_session(&session_key).await? {
+            Some(r) => {
+                store.refresh_session(&session_key, cfg.cookie_ttl).await?;
+                cookie.eq_body(r.as_str())
+            }
+            None => false,
+        },
+    };
+    if valid && (repo.is_empty() || store.is_repo_allowed(repo, cookie.get_user()).await?) {
+        let pool = pools.replica(cfg).await?;
+        if is_account_blocked(pool, cookie.get_user()).await? {
+            log::warn!(
+                "Reject session of disabled or expired user {}",
+                cookie.get_user()
+            );
+            store.delete_session(&session_key).await?;
+            return Ok(None);
+        }
+        return Ok(Some(cookie.get_user().to_string()));
+    }
+    if cfg.log_sensitive {
+        log::debug!("{:?}", cookie);
+    }
+    Ok(None)
+}
+
+pub async fn verify_login(cfg: &WrapConfigure, data: &FormData) -> Result<bool> {
+    data.authorize(cfg.get_authorizer()).await
+}
+
+#[derive(Serialize)]
+pub struct Meta<'a> {
+    action: &'a str,
+    redirect: &'a str,
+    version: &'a str,
+    custom_warning: Option<String>,
+    csrf_token: &'a str,
+    user_field: &'a str,
+    password_field: &'a str,
+    theme: &'a str,
+    pow_difficulty: u32,
+}
+
+async fn generate_csrf_token(store: &mut dyn SessionStore) -> Result<String> {
+    let token = datastructures::rand_str(CSRF_TOKEN_LENGTH);
+    store
+        .set_session(&format!("cgit_csrf_{}", token), "", CSRF_TOKEN_TTL)
+        .await?;
+    Ok(token)
+}
+
+// Token is removed on use, so each rendered login form can only be posted once.
+async fn consume_csrf_token(store: &mut dyn SessionStore, token: &str) -> Result<bool> {
+    if token.is_empty() {
+        return Ok(false);
+    }
+    store.delete_session(&format!("cgit_csrf_{}", token)).await
+}
+
+// Client is identified by configured header (e.g. X-Forwarded-For behind proxy), then REMOTE_ADDR,
+// fallback to `http-host` if cgit passes neither.
+fn get_client_identity(cfg: &Config, host: &str) -> String {
+    let header = cfg
+        .get_rate_limit()
+        .client_ip_header
+        .as_ref()
+        .map(|header| format!("HTTP_{}", header.to_uppercase().replace('-', "_")))
+        .and_then(|name| env::var(name).ok());
+    header
+        .as_deref()
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .or_else(|| env::var("REMOTE_ADDR").ok())
+        .unwrap_or_else(|| host.to_string())
+}
+
+// Seconds until enough attempts leave the window for `key` to be allowed again, None if not limited.
+async fn get_lockout_remaining(
+    store: &mut dyn SessionStore,
+    key: &str,
+    window: u64,
+    max_attempts: u64,
+) -> Result<Option<u64>> {
+    if max_attempts == 0 {
+        return Ok(None);
+    }
+    let attempts = store.list_attempts(key, window).await?;
+    if (attempts.len() as u64) < max_attempts {
+        return Ok(None);
+    }
+    let blocking = attempts[attempts.len() - max_attempts as usize];
+    Ok(Some(
+        (blocking + window)
+            .saturating_sub(datastructures::get_current_timestamp())
+            .max(1),
+    ))
+}
+
+// Return remaining lockout seconds if client or user has too many failed attempts.
+async fn check_rate_limited(
+    cfg: &Config,
+    store: &mut dyn SessionStore,
+    client: &str,
+    user: &str,
+) -> Result<Option<u64>> {
+    let limit = cfg.get_rate_limit();
+    let host = get_lockout_remaining(
+        store,
+        &format!("cgit_attempt_host_{}", client),
+        limit.window,
+        limit.max_attempts_per_host,
+    )
+    .await?;
+    let user = get_lockout_remaining(
+        store,
+        &format!("cgit_attempt_user_{}", user),
+        limit.window,
+        limit.max_attempts_per_user,
+    )
+    .await?;
+    Ok(host.max(user))
+}
+
+async fn record_failed_attempt(
+    cfg: &Config,
+    store: &mut dyn SessionStore,
+    client: &str,
+    user: &str,
+) -> Result<()> {
+    let limit = cfg.get_rate_limit();
+    if limit.max_attempts_per_host > 0 {
+        store
+            .record_attempt(&format!("cgit_attempt_host_{}", client), limit.window)
+            .await?;
+    }
+    if limit.max_attempts_per_user > 0 {
+        store
+            .record_attempt(&format!("cgit_attempt_user_{}", user), limit.window)
+            .await?;
+    }
+    Ok(())
+}
+
+// Users without local account (e.g. from PAM) are not blocked.
+async fn is_account_blocked(pool: &SqlitePool, user: &str) -> Result<bool> {
+    let row = sqlx::query_as::<_, (bool, Option<i64>)>(
+        r#"SELECT "disabled", "expires_at" FROM "accounts" WHERE "user" = ?"#,
+    )
+    .bind(user)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some_and(|(disabled, expires_at)| {
+        !datastructures::is_account_usable(disabled, expires_at)
+    }))
+}
+
+// Effective users of repository are its ACL users and members of groups granted to it.
+async fn get_repo_users(pool: &SqlitePool, repo: &str) -> Result<Vec<String>> {
+    let mut users =
+        sqlx::query_as::<_, (String,)>(r#"SELECT "users" FROM "repos" WHERE "repo" = ? "#)
+            .bind(repo)
+            .fetch_optional(pool)
+            .await?
+            .map(|(users,)| {
+                users
+                    .split_whitespace()
+                    .map(|user| user.to_string())
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+
+    // Database initialized before groups support may not have group tables.
+    let rows = sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+        .bind("group_repos")
+        .fetch_all(pool)
+        .await?;
+    if rows.is_empty() {
+        return Ok(users);
+    }
+
+    let mut iter = sqlx::query_as::<_, (String,)>(
+        r#"SELECT DISTINCT "user" FROM "group_members" JOIN "group_repos" USING ("group") WHERE "repo" = ?"#,
+    )
+    .bind(repo)
+    .fetch(pool);
+    while let Some((user,)) = iter.next().await.transpose()? {
+        if !users.contains(&user) {
+            users.push(user);
+        }
+    }
+
+    Ok(users)
+}
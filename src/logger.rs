@@ -0,0 +1,144 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::append::Append;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::fs::File;
+use std::path::Path;
+
+const LOG_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)}- {h({l})} - {m}{n}";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogTarget {
+    #[default]
+    File,
+    Stderr,
+    Syslog,
+}
+
+impl From<&str> for LogTarget {
+    fn from(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "stderr" => Self::Stderr,
+            "syslog" => Self::Syslog,
+            _ => Self::File,
+        }
+    }
+}
+
+/// Send records to local syslog daemon through /dev/log, using authpriv facility.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct SyslogAppender {
+    socket: std::os::unix::net::UnixDatagram,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl SyslogAppender {
+    const FACILITY_AUTHPRIV: u8 = 10;
+
+    pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            socket: std::os::unix::net::UnixDatagram::unbound()?,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    fn get_severity(level: log::Level) -> u8 {
+        match level {
+            log::Level::Error => 3,
+            log::Level::Warn => 4,
+            log::Level::Info => 6,
+            log::Level::Debug | log::Level::Trace => 7,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Append for SyslogAppender {
+    fn append(&self, record: &log::Record) -> Result<()> {
+        let message = format!(
+            "<{}>{}[{}]: {}",
+            Self::FACILITY_AUTHPRIV * 8 + Self::get_severity(record.level()),
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            record.args()
+        );
+        self.socket.send_to(message.as_bytes(), &self.path)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+// Log may contain usernames and client addresses, create it readable by owner only.
+pub(crate) fn create_private_file<P: AsRef<Path>>(path: P) -> Result<File> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    Ok(options.open(path)?)
+}
+
+fn build_appender(target: LogTarget, log_file: &str) -> Result<Box<dyn Append>> {
+    Ok(match target {
+        LogTarget::File => Box::new({
+            create_private_file(log_file)?;
+            FileAppender::builder()
+                .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+                .build(log_file)?
+        }),
+        LogTarget::Stderr => Box::new(
+            ConsoleAppender::builder()
+                .target(Target::Stderr)
+                .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+                .build(),
+        ),
+        #[cfg(unix)]
+        LogTarget::Syslog => Box::new(SyslogAppender::new("/dev/log")?),
+        #[cfg(not(unix))]
+        LogTarget::Syslog => {
+            return Err(anyhow::Error::msg(
+                "Syslog log target is only supported on unix",
+            ))
+        }
+    })
+}
+
+pub fn build_log_config(
+    target: LogTarget,
+    level: log::LevelFilter,
+    log_file: &str,
+) -> Result<log4rs::Config> {
+    Ok(log4rs::Config::builder()
+        .appender(Appender::builder().build("log", build_appender(target, log_file)?))
+        .logger(Logger::builder().build("handlebars::render", log::LevelFilter::Warn))
+        .logger(Logger::builder().build("handlebars::context", log::LevelFilter::Warn))
+        .build(Root::builder().appender("log").build(level))?)
+}
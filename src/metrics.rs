@@ -0,0 +1,88 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::session::SessionStore;
+use anyhow::Result;
+use std::io::Write;
+
+/// Outcomes of posted login forms.
+pub const LOGIN_OUTCOMES: &[&str] = &["success", "failure", "locked_out", "rejected", "error"];
+/// Outcomes of cookies checked by `authenticate-cookie`.
+pub const COOKIE_OUTCOMES: &[&str] = &["valid", "invalid"];
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    key: &'static str,
+    outcomes: &'static [&'static str],
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "cgit_simple_auth_logins_total",
+        help: "Login attempts by outcome.",
+        key: "login",
+        outcomes: LOGIN_OUTCOMES,
+    },
+    Metric {
+        name: "cgit_simple_auth_cookie_validations_total",
+        help: "Session cookie validations by outcome.",
+        key: "cookie",
+        outcomes: COOKIE_OUTCOMES,
+    },
+];
+
+fn get_counter_key(kind: &str, outcome: &str) -> String {
+    format!("cgit_metrics_{}_{}", kind, outcome)
+}
+
+// Metrics must never decide authentication, so failure to count is only logged.
+async fn count(store: &mut dyn SessionStore, kind: &str, outcome: &str) {
+    if let Err(e) = store.incr_counter(&get_counter_key(kind, outcome)).await {
+        log::warn!("Unable to count {} {}: {:?}", kind, outcome, e);
+    }
+}
+
+pub async fn count_login(store: &mut dyn SessionStore, outcome: &str) {
+    count(store, "login", outcome).await
+}
+
+pub async fn count_cookie(store: &mut dyn SessionStore, valid: bool) {
+    count(store, "cookie", if valid { "valid" } else { "invalid" }).await
+}
+
+/// Print counters in Prometheus text exposition format.
+pub async fn write_metrics<W: Write>(store: &mut dyn SessionStore, writer: &mut W) -> Result<()> {
+    for metric in METRICS {
+        writeln!(writer, "# HELP {} {}", metric.name, metric.help)?;
+        writeln!(writer, "# TYPE {} counter", metric.name)?;
+        for outcome in metric.outcomes {
+            let value = store
+                .get_counter(&get_counter_key(metric.key, outcome))
+                .await?;
+            writeln!(
+                writer,
+                "{}{{outcome=\"{}\"}} {}",
+                metric.name, outcome, value
+            )?;
+        }
+    }
+    Ok(())
+}
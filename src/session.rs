@@ -0,0 +1,677 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of cgit-simple-authentication and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::database;
+use crate::datastructures::{get_current_timestamp, rand_str};
+use anyhow::Result;
+use redis::AsyncCommands;
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+
+/// Counter holding time of last global logout, stateless cookies issued until then are rejected.
+pub const SESSIONS_NOT_BEFORE_KEY: &str = "cgit_sessions_not_before";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SessionBackend {
+    #[default]
+    Redis,
+    Sqlite,
+}
+
+impl std::str::FromStr for SessionBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "redis" => Ok(Self::Redis),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(anyhow::Error::msg(format!("Unknown session backend {}", s))),
+        }
+    }
+}
+
+// Keys returned by each SCAN call when sessions are removed in bulk.
+const SCAN_BATCH_SIZE: usize = 100;
+
+/// Storage of login sessions, CSRF tokens and cached repository ACL.
+#[async_trait::async_trait]
+pub trait SessionStore: Send {
+    async fn set_session(&mut self, key: &str, body: &str, ttl: u64) -> Result<()>;
+
+    async fn get_session(&mut self, key: &str) -> Result<Option<String>>;
+
+    async fn refresh_session(&mut self, key: &str, ttl: u64) -> Result<()>;
+
+    /// Remaining seconds of session, None if it does not exist.
+    async fn get_session_ttl(&mut self, key: &str) -> Result<Option<u64>>;
+
+    /// Return true if session existed before deletion.
+    async fn delete_session(&mut self, key: &str) -> Result<bool>;
+
+    async fn has_repo_set(&mut self, repo: &str) -> Result<bool>;
+
+    /// Cache users allowed to read repo for `ttl` seconds, so grant changes are picked up
+    /// even when cache was not invalidated.
+    async fn set_repo_set(&mut self, repo: &str, users: &[String], ttl: u64) -> Result<()>;
+
+    async fn is_repo_allowed(&mut self, repo: &str, user: &str) -> Result<bool>;
+
+    async fn clear_repo_sets(&mut self, repos: &[String]) -> Result<()>;
+
+    /// Count attempts recorded under key within last `window` seconds.
+    async fn count_attempts(&mut self, key: &str, window: u64) -> Result<u64>;
+
+    /// Timestamps of attempts recorded under key within last `window` seconds, oldest first.
+    async fn list_attempts(&mut self, key: &str, window: u64) -> Result<Vec<u64>>;
+
+    async fn record_attempt(&mut self, key: &str, window: u64) -> Result<()>;
+
+    /// Remember cookie key of user, so all sessions of user can be found later.
+    async fn add_user_session(&mut self, user: &str, key: &str) -> Result<()>;
+
+    async fn get_user_sessions(&mut self, user: &str) -> Result<Vec<String>>;
+
+    async fn remove_user_sessions(&mut self, user: &str, keys: &[String]) -> Result<()>;
+
+    /// Log out every user, return number of removed sessions. Time of logout is kept in
+    /// `SESSIONS_NOT_BEFORE_KEY`, since stateless cookies have no session to remove.
+    async fn delete_all_sessions(&mut self) -> Result<u64>;
+
+    /// Atomically add one to a counter, it never expires.
+    async fn incr_counter(&mut self, key: &str) -> Result<()>;
+
+    /// Counter value, zero if it was never incremented.
+    async fn get_counter(&mut self, key: &str) -> Result<u64>;
+
+    /// Overwrite counter, also used to keep small flags which never expire.
+    async fn set_counter(&mut self, key: &str, value: u64) -> Result<()>;
+}
+
+pub struct RedisSessionStore {
+    conn: redis::aio::Connection,
+}
+
+impl RedisSessionStore {
+    pub fn new(conn: redis::aio::Connection) -> Self {
+        Self { conn }
+    }
+
+    // Walk keys with SCAN in small batches, KEYS would block Redis on large databases.
+    async fn delete_matching(&mut self, pattern: &str) -> Result<u64> {
+        let mut cursor: u64 = 0;
+        let mut deleted = 0;
+        loop {
+            let (next, keys) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(SCAN_BATCH_SIZE)
+                .query_async::<_, (u64, Vec<String>)>(&mut self.conn)
+                .await?;
+            if !keys.is_empty() {
+                deleted += self.conn.del::<_, u64>(keys).await?;
+            }
+            if next == 0 {
+                return Ok(deleted);
+            }
+            cursor = next;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn set_session(&mut self, key: &str, body: &str, ttl: u64) -> Result<()> {
+        self.conn
+            .set_ex::<_, _, String>(key, body, ttl as usize)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_session(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.conn.get::<_, Option<String>>(key).await?)
+    }
+
+    async fn refresh_session(&mut self, key: &str, ttl: u64) -> Result<()> {
+        self.conn.expire::<_, bool>(key, ttl as usize).await?;
+        Ok(())
+    }
+
+    async fn get_session_ttl(&mut self, key: &str) -> Result<Option<u64>> {
+        // -2 means key does not exist, -1 means it has no expiry
+        let ttl = self.conn.ttl::<_, i64>(key).await?;
+        Ok((ttl != -2).then(|| ttl.max(0) as u64))
+    }
+
+    async fn delete_session(&mut self, key: &str) -> Result<bool> {
+        Ok(self.conn.del::<_, i32>(key).await? == 1)
+    }
+
+    async fn has_repo_set(&mut self, repo: &str) -> Result<bool> {
+        Ok(self.conn.exists(format!("cgit_repo_{}", repo)).await?)
+    }
+
+    async fn set_repo_set(&mut self, repo: &str, users: &[String], ttl: u64) -> Result<()> {
+        if !users.is_empty() {
+            let key = format!("cgit_repo_{}", repo);
+            self.conn.sadd::<_, _, i32>(&key, users).await?;
+            self.conn.expire::<_, bool>(&key, ttl as usize).await?;
+        }
+        Ok(())
+    }
+
+    async fn is_repo_allowed(&mut self, repo: &str, user: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .sismember::<_, _, i32>(format!("cgit_repo_{}", repo), user)
+            .await?
+            == 1)
+    }
+
+    async fn clear_repo_sets(&mut self, repos: &[String]) -> Result<()> {
+        if !repos.is_empty() {
+            self.conn
+                .del::<_, i32>(
+                    repos
+                        .iter()
+                        .map(|repo| format!("cgit_repo_{}", repo))
+                        .collect::<Vec<String>>(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn count_attempts(&mut self, key: &str, window: u64) -> Result<u64> {
+        let now = get_current_timestamp();
+        self.conn
+            .zrembyscore::<_, _, _, i32>(key, 0, now.saturating_sub(window))
+            .await?;
+        Ok(self.conn.zcard::<_, u64>(key).await?)
+    }
+
+    async fn list_attempts(&mut self, key: &str, window: u64) -> Result<Vec<u64>> {
+        let now = get_current_timestamp();
+        self.conn
+            .zrembyscore::<_, _, _, i32>(key, 0, now.saturating_sub(window))
+            .await?;
+        Ok(self
+            .conn
+            .zrange_withscores::<_, Vec<(String, u64)>>(key, 0, -1)
+            .await?
+            .into_iter()
+            .map(|(_, timestamp)| timestamp)
+            .collect())
+    }
+
+    async fn record_attempt(&mut self, key: &str, window: u64) -> Result<()> {
+        let now = get_current_timestamp();
+        // Member must be unique, otherwise attempts within same second are merged.
+        self.conn
+            .zadd::<_, _, _, i32>(key, format!("{}-{}", now, rand_str(8)), now)
+            .await?;
+        self.conn.expire::<_, bool>(key, window as usize).await?;
+        Ok(())
+    }
+
+    async fn add_user_session(&mut self, user: &str, key: &str) -> Result<()> {
+        self.conn
+            .sadd::<_, _, i32>(format!("cgit_user_sessions_{}", user), key)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_sessions(&mut self, user: &str) -> Result<Vec<String>> {
+        Ok(self
+            .conn
+            .smembers::<_, Vec<String>>(format!("cgit_user_sessions_{}", user))
+            .await?)
+    }
+
+    async fn remove_user_sessions(&mut self, user: &str, keys: &[String]) -> Result<()> {
+        if !keys.is_empty() {
+            self.conn
+                .srem::<_, _, i32>(format!("cgit_user_sessions_{}", user), keys)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&mut self) -> Result<u64> {
+        self.set_counter(SESSIONS_NOT_BEFORE_KEY, get_current_timestamp())
+            .await?;
+        let deleted = self.delete_matching("cgit_auth_*").await?;
+        self.delete_matching("cgit_user_sessions_*").await?;
+        Ok(deleted)
+    }
+
+    async fn incr_counter(&mut self, key: &str) -> Result<()> {
+        self.conn.incr::<_, _, u64>(key, 1).await?;
+        Ok(())
+    }
+
+    async fn get_counter(&mut self, key: &str) -> Result<u64> {
+        Ok(self.conn.get::<_, Option<u64>>(key).await?.unwrap_or(0))
+    }
+
+    async fn set_counter(&mut self, key: &str, value: u64) -> Result<()> {
+        self.conn.set::<_, _, String>(key, value).await?;
+        Ok(())
+    }
+}
+
+/// Keep sessions in `sessions` table of authentication database.
+///
+/// Repository ACL is read from the same database, so it is only cached for current process.
+pub struct SqliteSessionStore {
+    pool: SqlitePool,
+    repo_sets: HashMap<String, HashSet<String>>,
+}
+
+impl SqliteSessionStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self> {
+        sqlx::query(database::current::CREATE_SESSION_TABLES)
+            .execute(&pool)
+            .await?;
+        Ok(Self {
+            pool,
+            repo_sets: Default::default(),
+        })
+    }
+
+    async fn remove_expired_sessions(&self) -> Result<()> {
+        sqlx::query(r#"DELETE FROM "sessions" WHERE "expires_at" <= ?"#)
+            .bind(get_current_timestamp() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn set_session(&mut self, key: &str, body: &str, ttl: u64) -> Result<()> {
+        // Every new session also cleans up expired ones, so the table will not grow forever.
+        self.remove_expired_sessions().await?;
+        sqlx::query(r#"INSERT OR REPLACE INTO "sessions" VALUES (?, ?, ?)"#)
+            .bind(key)
+            .bind(body)
+            .bind((get_current_timestamp() + ttl) as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_session(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(sqlx::query_as::<_, (String,)>(
+            r#"SELECT "body" FROM "sessions" WHERE "key" = ? AND "expires_at" > ?"#,
+        )
+        .bind(key)
+        .bind(get_current_timestamp() as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|(body,)| body))
+    }
+
+    async fn refresh_session(&mut self, key: &str, ttl: u64) -> Result<()> {
+        sqlx::query(
+            r#"UPDATE "sessions" SET "expires_at" = ? WHERE "key" = ? AND "expires_at" > ?"#,
+        )
+        .bind((get_current_timestamp() + ttl) as i64)
+        .bind(key)
+        .bind(get_current_timestamp() as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_session_ttl(&mut self, key: &str) -> Result<Option<u64>> {
+        let now = get_current_timestamp() as i64;
+        Ok(sqlx::query_as::<_, (i64,)>(
+            r#"SELECT "expires_at" FROM "sessions" WHERE "key" = ? AND "expires_at" > ?"#,
+        )
+        .bind(key)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|(expires_at,)| (expires_at - now) as u64))
+    }
+
+    async fn delete_session(&mut self, key: &str) -> Result<bool> {
+        let result = sqlx::query(r#"DELETE FROM "sessions" WHERE "key" = ? AND "expires_at" > ?"#)
+            .bind(key)
+            .bind(get_current_timestamp() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn has_repo_set(&mut self, repo: &str) -> Result<bool> {
+        Ok(self.repo_sets.contains_key(repo))
+    }
+
+    // Cache only lives as long as this store (one request), it never outlives ttl.
+    async fn set_repo_set(&mut self, repo: &str, users: &[String], _ttl: u64) -> Result<()> {
+        self.repo_sets
+            .insert(repo.to_string(), users.iter().cloned().collect());
+        Ok(())
+    }
+
+    async fn is_repo_allowed(&mut self, repo: &str, user: &str) -> Result<bool> {
+        Ok(self
+            .repo_sets
+            .get(repo)
+            .map(|users| users.contains(user))
+            .unwrap_or(false))
+    }
+
+    async fn clear_repo_sets(&mut self, repos: &[String]) -> Result<()> {
+        for repo in repos {
+            self.repo_sets.remove(repo);
+        }
+        Ok(())
+    }
+
+    async fn count_attempts(&mut self, key: &str, window: u64) -> Result<u64> {
+        let (count,) = sqlx::query_as::<_, (i64,)>(
+            r#"SELECT COUNT(*) FROM "login_attempts" WHERE "key" = ? AND "timestamp" > ?"#,
+        )
+        .bind(key)
+        .bind(get_current_timestamp().saturating_sub(window) as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count as u64)
+    }
+
+    async fn list_attempts(&mut self, key: &str, window: u64) -> Result<Vec<u64>> {
+        Ok(sqlx::query_as::<_, (i64,)>(
+            r#"SELECT "timestamp" FROM "login_attempts" WHERE "key" = ? AND "timestamp" > ? ORDER BY "timestamp""#,
+        )
+        .bind(key)
+        .bind(get_current_timestamp().saturating_sub(window) as i64)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(timestamp,)| timestamp as u64)
+        .collect())
+    }
+
+    async fn record_attempt(&mut self, key: &str, window: u64) -> Result<()> {
+        let now = get_current_timestamp();
+        sqlx::query(r#"DELETE FROM "login_attempts" WHERE "key" = ? AND "timestamp" <= ?"#)
+            .bind(key)
+            .bind(now.saturating_sub(window) as i64)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(r#"INSERT INTO "login_attempts" VALUES (?, ?)"#)
+            .bind(key)
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_user_session(&mut self, user: &str, key: &str) -> Result<()> {
+        sqlx::query(r#"INSERT OR IGNORE INTO "user_sessions" VALUES (?, ?)"#)
+            .bind(user)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_sessions(&mut self, user: &str) -> Result<Vec<String>> {
+        Ok(
+            sqlx::query_as::<_, (String,)>(r#"SELECT "key" FROM "user_sessions" WHERE "user" = ?"#)
+                .bind(user)
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .map(|(key,)| key)
+                .collect(),
+        )
+    }
+
+    async fn remove_user_sessions(&mut self, user: &str, keys: &[String]) -> Result<()> {
+        for key in keys {
+            sqlx::query(r#"DELETE FROM "user_sessions" WHERE "user" = ? AND "key" = ?"#)
+                .bind(user)
+                .bind(key)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&mut self) -> Result<u64> {
+        self.remove_expired_sessions().await?;
+        let mut tx = self.pool.begin().await?;
+        let result =
+            sqlx::query(r#"DELETE FROM "sessions" WHERE "key" LIKE 'cgit\_auth\_%' ESCAPE '\'"#)
+                .execute(&mut tx)
+                .await?;
+        sqlx::query(r#"DELETE FROM "user_sessions""#)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(r#"INSERT OR REPLACE INTO "counters" VALUES (?, ?)"#)
+            .bind(SESSIONS_NOT_BEFORE_KEY)
+            .bind(get_current_timestamp() as i64)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn incr_counter(&mut self, key: &str) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO "counters" VALUES (?, 1) ON CONFLICT("key") DO UPDATE SET "value" = "value" + 1"#,
+        )
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_counter(&mut self, key: &str) -> Result<u64> {
+        Ok(
+            sqlx::query_as::<_, (i64,)>(r#"SELECT "value" FROM "counters" WHERE "key" = ?"#)
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|(value,)| value as u64)
+                .unwrap_or(0),
+        )
+    }
+
+    async fn set_counter(&mut self, key: &str, value: u64) -> Result<()> {
+        sqlx::query(r#"INSERT OR REPLACE INTO "counters" VALUES (?, ?)"#)
+            .bind(key)
+            .bind(value as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// In memory store, so authentication logic can be tested without Redis.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: HashMap<String, (String, u64)>,
+    repo_sets: HashMap<String, (HashSet<String>, u64)>,
+    attempts: HashMap<String, Vec<u64>>,
+    user_sessions: HashMap<String, HashSet<String>>,
+    counters: HashMap<String, u64>,
+}
+
+#[cfg(test)]
+impl MemorySessionStore {
+    fn get_alive_session(&mut self, key: &str) -> Option<&mut (String, u64)> {
+        let now = get_current_timestamp();
+        self.sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        self.sessions.get_mut(key)
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn set_session(&mut self, key: &str, body: &str, ttl: u64) -> Result<()> {
+        self.sessions.insert(
+            key.to_string(),
+            (body.to_string(), get_current_timestamp() + ttl),
+        );
+        Ok(())
+    }
+
+    async fn get_session(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.get_alive_session(key).map(|(body, _)| body.clone()))
+    }
+
+    async fn refresh_session(&mut self, key: &str, ttl: u64) -> Result<()> {
+        if let Some((_, expires_at)) = self.get_alive_session(key) {
+            *expires_at = get_current_timestamp() + ttl;
+        }
+        Ok(())
+    }
+
+    async fn get_session_ttl(&mut self, key: &str) -> Result<Option<u64>> {
+        let now = get_current_timestamp();
+        Ok(self
+            .get_alive_session(key)
+            .map(|(_, expires_at)| *expires_at - now))
+    }
+
+    async fn delete_session(&mut self, key: &str) -> Result<bool> {
+        let exists = self.get_alive_session(key).is_some();
+        self.sessions.remove(key);
+        Ok(exists)
+    }
+
+    async fn has_repo_set(&mut self, repo: &str) -> Result<bool> {
+        let now = get_current_timestamp();
+        self.repo_sets
+            .retain(|_, (_, expires_at)| *expires_at > now);
+        Ok(self.repo_sets.contains_key(repo))
+    }
+
+    async fn set_repo_set(&mut self, repo: &str, users: &[String], ttl: u64) -> Result<()> {
+        self.repo_sets.insert(
+            repo.to_string(),
+            (
+                users.iter().cloned().collect(),
+                get_current_timestamp() + ttl,
+            ),
+        );
+        Ok(())
+    }
+
+    async fn is_repo_allowed(&mut self, repo: &str, user: &str) -> Result<bool> {
+        Ok(self
+            .repo_sets
+            .get(repo)
+            .map(|(users, _)| users.contains(user))
+            .unwrap_or(false))
+    }
+
+    async fn clear_repo_sets(&mut self, repos: &[String]) -> Result<()> {
+        for repo in repos {
+            self.repo_sets.remove(repo);
+        }
+        Ok(())
+    }
+
+    async fn count_attempts(&mut self, key: &str, window: u64) -> Result<u64> {
+        let since = get_current_timestamp().saturating_sub(window);
+        Ok(self
+            .attempts
+            .get(key)
+            .map(|attempts| attempts.iter().filter(|x| **x > since).count() as u64)
+            .unwrap_or(0))
+    }
+
+    async fn list_attempts(&mut self, key: &str, window: u64) -> Result<Vec<u64>> {
+        let since = get_current_timestamp().saturating_sub(window);
+        Ok(self
+            .attempts
+            .get(key)
+            .map(|attempts| attempts.iter().copied().filter(|x| *x > since).collect())
+            .unwrap_or_default())
+    }
+
+    async fn record_attempt(&mut self, key: &str, window: u64) -> Result<()> {
+        let now = get_current_timestamp();
+        let attempts = self.attempts.entry(key.to_string()).or_default();
+        attempts.retain(|x| *x > now.saturating_sub(window));
+        attempts.push(now);
+        Ok(())
+    }
+
+    async fn add_user_session(&mut self, user: &str, key: &str) -> Result<()> {
+        self.user_sessions
+            .entry(user.to_string())
+            .or_default()
+            .insert(key.to_string());
+        Ok(())
+    }
+
+    async fn get_user_sessions(&mut self, user: &str) -> Result<Vec<String>> {
+        Ok(self
+            .user_sessions
+            .get(user)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn remove_user_sessions(&mut self, user: &str, keys: &[String]) -> Result<()> {
+        if let Some(sessions) = self.user_sessions.get_mut(user) {
+            for key in keys {
+                sessions.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&mut self) -> Result<u64> {
+        let now = get_current_timestamp();
+        self.counters
+            .insert(SESSIONS_NOT_BEFORE_KEY.to_string(), now);
+        self.sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|key, _| !key.starts_with("cgit_auth_"));
+        self.user_sessions.clear();
+        Ok((before - self.sessions.len()) as u64)
+    }
+
+    async fn incr_counter(&mut self, key: &str) -> Result<()> {
+        *self.counters.entry(key.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    async fn get_counter(&mut self, key: &str) -> Result<u64> {
+        Ok(self.counters.get(key).copied().unwrap_or(0))
+    }
+
+    async fn set_counter(&mut self, key: &str, value: u64) -> Result<()> {
+        self.counters.insert(key.to_string(), value);
+        Ok(())
+    }
+}
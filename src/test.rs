@@ -20,15 +20,42 @@
 
 #[cfg(test)]
 mod core {
-    use crate::datastructures::{rand_str, Config, TestSuite};
-    use crate::{cmd_add_user, cmd_authenticate_cookie, cmd_init, cmd_repo_user_control};
-    use crate::{get_arg_matches, IOModule};
+    use crate::cli::{
+        add_user, async_main, build_app, build_runtime, change_password, check_bypass,
+        cmd_add_group, cmd_add_user, cmd_authenticate_cookie, cmd_batch, cmd_change_password,
+        cmd_check_database, cmd_count_user, cmd_delete_group, cmd_delete_user, cmd_examples,
+        cmd_export, cmd_export_to_file, cmd_group_grant, cmd_group_member, cmd_healthcheck,
+        cmd_import_users, cmd_init, cmd_init_admin, cmd_inspect_hash, cmd_kill_sessions,
+        cmd_list_sessions, cmd_list_user, cmd_maintenance, cmd_repo_user_control,
+        cmd_reset_database, cmd_restore_users, cmd_schema_version, cmd_serve,
+        cmd_set_user_disabled, cmd_show_access, cmd_upgrade_database, cmd_vacuum_database,
+        cmd_verify_password, collect_user_access, collect_user_sessions, format_arguments,
+        get_arg_matches, get_log_level, get_worker_threads, parse_expires, read_input_with_timeout,
+        verify_export_checksum, IOModule, Interrupted,
+    };
+    use crate::datastructures::verify_proof_of_work;
+    use crate::datastructures::{build_argon2, glob_match, hmac_sha256, is_hash_outdated};
+    use crate::datastructures::{parse_cookie_ttl, MaintenanceMode, COOKIE_TTL_RANGE};
+    use crate::datastructures::{rand_str, Config, Cookie, FormData, FormFields, TestSuite};
+    use crate::datastructures::{validate_password, verify_password_blocking, PasswordPolicy};
+    use crate::datastructures::{RequestPools, WrapConfigure};
+    use crate::datastructures::{DEFAULT_ARGON2_OUTPUT_LEN, DEFAULT_ARGON2_SALT_LEN};
+    use crate::logger::{build_log_config, LogTarget};
+    use crate::metrics::write_metrics;
+    use crate::session::{MemorySessionStore, RedisSessionStore, SessionStore, SqliteSessionStore};
+    use crate::{
+        connect_optional_session_store, generate_csrf_token, get_maintenance_mode, get_repo_users,
+        kill_user_sessions, validate_cookie, verify_login, AuthError, MAINTENANCE_KEY,
+    };
     use argon2::{
         password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
         Argon2,
     };
+    use clap::ArgMatches;
     use redis::AsyncCommands;
+    use sqlx::Connection;
     use std::borrow::BorrowMut;
+    use std::future::Future;
     use std::io::{Read, Write};
     use std::path::Path;
     use std::path::PathBuf;
@@ -54,6 +81,7 @@ mod core {
         assert!(argon2.verify_password(passwd, &parsed_hash).is_ok())
     }
 
+    #[allow(clippy::bool_assert_comparison, dependency_on_unit_never_type_fallback)]
     async fn async_test_redis() -> anyhow::Result<()> {
         let redis_conn = redis::Client::open("redis://127.0.0.1/")?;
         let mut conn = redis_conn.get_async_connection().await?;
@@ -81,12 +109,50 @@ mod core {
             .unwrap();
     }
 
+    fn issue_csrf_token() -> String {
+        block_on(async {
+            let mut store = Config::generate_test_config()
+                .connect_session_store()
+                .await?;
+            generate_csrf_token(store.as_mut()).await
+        })
+        .unwrap()
+    }
+
     fn test_auth_post() -> String {
-        let correct_input = br#"redirect=/&username=hunter2&password=hunter2"#;
+        let correct_input = format!(
+            "redirect=/&username=hunter2&password=hunter2&csrf_token={}",
+            issue_csrf_token()
+        );
+        post_login(correct_input.as_bytes(), Config::generate_test_config())
+    }
+
+    fn post_login(input: &[u8], cfg: Config) -> String {
+        block_on(async {
+            let mut store = cfg.connect_session_store().await?;
+            post_login_with_store(input, cfg, store.as_mut()).await
+        })
+        .unwrap()
+    }
+
+    async fn post_login_with_store(
+        input: &[u8],
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<String> {
+        post_with_cookie(input, "", cfg, store).await
+    }
+
+    async fn post_with_cookie(
+        input: &[u8],
+        cookie: &str,
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<String> {
         let matches = get_arg_matches(Some(vec![
             "a",
             "authenticate-post",
-            "",
+            cookie,
             "POST",
             "p=login",
             "https://git.example.com/?p=login",
@@ -100,30 +166,29 @@ mod core {
         ]));
         let mut output = Vec::new();
         let mut module = IOModule {
-            reader: &correct_input[..],
+            reader: input,
             writer: &mut output,
         };
 
-        let cfg = Config::generate_test_config();
-
-        match matches.subcommand() {
-            ("authenticate-post", Some(matches)) => tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(module.cmd_authenticate_post(matches, cfg))
-                .unwrap(),
-            _ => {}
+        if let ("authenticate-post", Some(matches)) = matches.subcommand() {
+            module
+                .cmd_authenticate_post(matches, cfg, store, &Default::default())
+                .await?;
         }
 
-        String::from_utf8(output).unwrap()
+        Ok(String::from_utf8(output)?)
     }
 
     #[test]
     fn test_01_auth_failure() {
-        let out = test_auth_post();
+        lock(&PathBuf::from("test/DATABASE_INITED"), 3);
+        let input = format!(
+            "redirect=/&username=hunter2&password=wrong&csrf_token={}",
+            issue_csrf_token()
+        );
+        let out = post_login(input.as_bytes(), Config::generate_test_config());
         assert!(out.starts_with("Status: 403"));
-        assert!(out.ends_with("\n\n"));
+        assert!(out.ends_with("\n\nInvalid username or password\n"));
     }
 
     #[test]
@@ -143,7 +208,7 @@ mod core {
         std::fs::File::create("test/DATABASE_INITED").unwrap();
     }
 
-    fn lock(path: &std::path::PathBuf, sleep_length: usize) {
+    fn lock(path: &Path, sleep_length: usize) {
         for _ in 0..(sleep_length * 100) {
             sleep(Duration::from_millis(10));
             if path.exists() {
@@ -159,7 +224,8 @@ mod core {
     #[test]
     fn test_02_insert_user() {
         lock(&PathBuf::from("test/DATABASE_INITED"), 3);
-        let matches = crate::get_arg_matches(Some(vec!["a", "user", "add", "hunter2", "hunter2"]));
+        let matches =
+            crate::cli::get_arg_matches(Some(vec!["a", "user", "add", "hunter2", "hunter2"]));
         match matches.subcommand() {
             ("user", Some(matches)) => match matches.subcommand() {
                 ("add", Some(matches)) => {
@@ -186,7 +252,7 @@ mod core {
             vec!["a", "repo", "add", "repo", "hunter"],
         ];
         for x in args {
-            let matches = crate::get_arg_matches(Some(x));
+            let matches = crate::cli::get_arg_matches(Some(x));
             match matches.subcommand() {
                 ("repo", Some(matches)) => match matches.subcommand() {
                     ("add", Some(matches)) => {
@@ -225,6 +291,7 @@ mod core {
         let mut file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open("test/RESPONSE")
             .unwrap();
         file.write_all(s.as_bytes()).unwrap();
@@ -286,10 +353,11 @@ mod core {
                 .enable_all()
                 .build()
                 .unwrap()
-                .block_on(cmd_authenticate_cookie(
-                    matches,
-                    Config::generate_test_config(),
-                ))
+                .block_on(async {
+                    let cfg = Config::generate_test_config();
+                    let mut store = cfg.connect_session_store().await?;
+                    authenticate_cookie(matches, cfg, store.as_mut()).await
+                })
                 .unwrap(),
             _ => unreachable!(),
         };
@@ -310,6 +378,49 @@ mod core {
         Ok(())
     }
 
+    // Default test config with its own database in `dir`, `options` are cgitrc lines on top
+    // of the built-in defaults when not empty.
+    fn temp_database_config(dir: &Path, options: &[u8]) -> Config {
+        let mut cfg = if options.is_empty() {
+            Config::generate_test_config()
+        } else {
+            write_to_specify_file(&dir.join("CFG"), options).unwrap();
+            let mut cfg = Config::load_from_path(dir.join("CFG"));
+            cfg.test = true;
+            cfg
+        };
+        cfg.set_database_location(dir.join("auth.db").to_str().unwrap());
+        cfg
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    // Run `test` against a fresh database in a temporary directory, which is removed afterwards.
+    fn run_with_temp_database<F, Fut>(name: &str, test: F)
+    where
+        F: FnOnce(Config) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        run_with_temp_config(name, b"", test)
+    }
+
+    // Same as `run_with_temp_database`, but config is loaded from `options`.
+    fn run_with_temp_config<F, Fut>(name: &str, options: &[u8], test: F)
+    where
+        F: FnOnce(Config) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let tmpdir = tempdir::TempDir::new(name).unwrap();
+        block_on(test(temp_database_config(tmpdir.path(), options))).unwrap();
+        tmpdir.close().unwrap();
+    }
+
     #[test]
     fn test_protected_repo_parser() {
         let tmpdir = tempdir::TempDir::new("test").unwrap();
@@ -380,4 +491,5273 @@ mod core {
             .set_credentials(user, password);
         assert!(auth.authenticate().is_ok() && auth.open_session().is_ok())
     }
+
+    async fn async_test_external_auth(cfg: Config) -> anyhow::Result<()> {
+        let cfg = WrapConfigure::from(cfg);
+        for (form, expected) in [
+            ("username=trent&password=s3cret", true),
+            ("username=trent&password=wrong", false),
+            ("username=walter&password=s3cret", false),
+            // Line break must not smuggle another user or password to the command
+            ("username=trent%0As3cret&password=wrong", false),
+            ("username=trent&password=s3cret%0Aextra", false),
+            ("username=trent&password=s3cret%0D", false),
+        ] {
+            let data = FormData::from(form.to_string());
+            assert_eq!(verify_login(&cfg, &data).await?, expected, "{}", form);
+        }
+
+        // Command hanging longer than timeout is killed and counts as failure
+        let start = std::time::Instant::now();
+        let data = FormData::from("username=sleepy&password=s3cret".to_string());
+        assert!(!verify_login(&cfg, &data).await?);
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        // Username with control characters is refused before any authorizer runs
+        let mut store = MemorySessionStore::default();
+        let cfg = cfg.get_config();
+        for user in ["trent%0As3cret", "trent%00", "trent%09"] {
+            let out = post_with_new_token(user, "s3cret", cfg, &mut store).await?;
+            assert!(out.starts_with("Status: 403"), "{}", out);
+            assert!(!out.contains("Set-Cookie"));
+        }
+        let out = post_with_new_token("trent", "s3cret", cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_auth() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmpdir = tempdir::TempDir::new("external_auth").unwrap();
+        let script = tmpdir.path().join("auth.sh");
+        write_to_specify_file(
+            &script,
+            b"#!/bin/sh\n\
+            read -r user\n\
+            read -r password\n\
+            [ \"$user\" = sleepy ] && sleep 10\n\
+            [ \"$user\" = trent ] && [ \"$password\" = s3cret ]\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-external-auth-command={}\n\
+                cgit-simple-auth-external-auth-timeout=1",
+                script.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg.validate().is_ok());
+
+        block_on(async_test_external_auth(cfg)).unwrap();
+
+        // Timeout must leave room to answer the login
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-external-auth-command={}\n\
+                cgit-simple-auth-external-auth-timeout=5",
+                script.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg.get_external_auth_timeout().is_err());
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_safe_redirect() {
+        use crate::datastructures::get_safe_redirect;
+        let host = "git.example.com";
+
+        assert_eq!(get_safe_redirect("/", host), "/");
+        assert_eq!(get_safe_redirect("/?p=login", host), "/?p=login");
+        assert_eq!(get_safe_redirect("/repo/log/", host), "/repo/log/");
+        assert_eq!(
+            get_safe_redirect("https://git.example.com/?p=login", host),
+            "https://git.example.com/?p=login"
+        );
+
+        assert_eq!(get_safe_redirect("https://evil.com/", host), "/");
+        assert_eq!(
+            get_safe_redirect("http://git.example.com.evil.com/", host),
+            "/"
+        );
+        assert_eq!(
+            get_safe_redirect("https://git.example.com:8443/", host),
+            "/"
+        );
+        assert_eq!(get_safe_redirect("javascript:alert(1)", host), "/");
+        assert_eq!(get_safe_redirect("//evil.com", host), "/");
+        assert_eq!(get_safe_redirect("//evil.com/?p=login", host), "/");
+        assert_eq!(get_safe_redirect("/\\evil.com", host), "/");
+        assert_eq!(get_safe_redirect("/\r\nSet-Cookie: a=b", host), "/");
+        assert_eq!(get_safe_redirect("", host), "/");
+        assert_eq!(get_safe_redirect("https://git.example.com/", ""), "/");
+    }
+
+    #[test]
+    fn test_cookie_header() {
+        let cookie = Cookie::generate("trudy");
+        let auth = format!("cgit_auth={}", cookie);
+        for header in &[
+            format!("{}; a=1; b=2", auth),
+            format!("a=1; {};b=2", auth),
+            format!("a=1;  b=2 ;{} ", auth),
+            // Unrelated, empty, malformed and stale duplicate segments are skipped
+            format!(
+                "cgit_auth_x=1; xcgit_auth=2; ; flag; cgit_auth=broken; {}",
+                auth
+            ),
+            format!("{}; cgit_auth=broken", auth),
+        ] {
+            let parsed = Cookie::load_from_request(header).unwrap().unwrap();
+            assert_eq!(parsed.get_key(), cookie.get_key(), "{}", header);
+            assert_eq!(parsed.get_user(), "trudy");
+        }
+        for header in &[
+            "",
+            "a=1; b=2",
+            "cgit_auth=",
+            "cgit_auth",
+            "cgit_auth=broken;;",
+        ] {
+            assert!(Cookie::load_from_request(header).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_cookie_domain() {
+        use crate::datastructures::get_cookie_domain;
+
+        assert_eq!(
+            get_cookie_domain("git.example.com"),
+            Some("git.example.com")
+        );
+        assert_eq!(
+            get_cookie_domain("git.example.com:8080"),
+            Some("git.example.com")
+        );
+        assert_eq!(get_cookie_domain("localhost"), Some("localhost"));
+
+        assert_eq!(get_cookie_domain(""), None);
+        assert_eq!(get_cookie_domain("*"), None);
+        assert_eq!(
+            get_cookie_domain("git.example.com\r\nSet-Cookie: a=b"),
+            None
+        );
+        assert_eq!(get_cookie_domain("git.example.com; Secure"), None);
+        assert_eq!(get_cookie_domain("git.example.com:80; Path=/"), None);
+        assert_eq!(
+            get_cookie_domain("git.example.com:"),
+            Some("git.example.com")
+        );
+        assert_eq!(get_cookie_domain(".example.com"), None);
+        assert_eq!(get_cookie_domain("[::1]:8080"), None);
+    }
+
+    fn check_database(cfg: &Config, fix: bool) -> bool {
+        let mut args = vec!["a", "database", "check"];
+        if fix {
+            args.push("--fix");
+        }
+        let matches = get_arg_matches(Some(args));
+        match matches.subcommand() {
+            ("database", Some(matches)) => match matches.subcommand() {
+                ("check", Some(matches)) => {
+                    block_on(cmd_check_database(matches, cfg.clone())).unwrap()
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    async fn seed_broken_database(cfg: &Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"INSERT INTO "accounts" ("user", "password", "uid") VALUES (?, ?, ?)"#)
+            .bind("alice")
+            .bind(FormData::gen_string_argon2_hash(
+                "hunter2",
+                argon2::Algorithm::default(),
+                DEFAULT_ARGON2_OUTPUT_LEN,
+                DEFAULT_ARGON2_SALT_LEN,
+                None,
+            )?)
+            .bind("uid-alice")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(r#"INSERT INTO "accounts" ("user", "password", "uid") VALUES (?, ?, ?)"#)
+            .bind("bob")
+            .bind("not a hash")
+            .bind("uid-bob")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(r#"INSERT INTO "repos" VALUES (?, ?), (?, ?)"#)
+            .bind("shared")
+            .bind("alice ghost")
+            .bind("orphan")
+            .bind("ghost")
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn fetch_repos(cfg: &Config) -> anyhow::Result<Vec<(String, String)>> {
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        Ok(
+            sqlx::query_as::<_, (String, String)>(r#"SELECT * FROM "repos" ORDER BY "repo""#)
+                .fetch_all(&mut conn)
+                .await?,
+        )
+    }
+
+    #[test]
+    fn test_check_database() {
+        let tmpdir = tempdir::TempDir::new("check").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(seed_broken_database(&cfg)).unwrap();
+
+        assert!(check_database(&cfg, false));
+        assert_eq!(rt.block_on(fetch_repos(&cfg)).unwrap().len(), 2);
+
+        // Orphaned ACL entries are fixed, but the broken hash is still reported.
+        assert!(check_database(&cfg, true));
+        assert_eq!(
+            rt.block_on(fetch_repos(&cfg)).unwrap(),
+            vec![("shared".to_string(), "alice".to_string())]
+        );
+
+        tmpdir.close().unwrap();
+    }
+
+    fn add_user_matches<'a>(args: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+        match args.subcommand() {
+            ("user", Some(matches)) => match matches.subcommand() {
+                ("add", Some(matches)) => matches,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    async fn async_test_login_while_adding_user(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let alice = get_arg_matches(Some(vec!["a", "user", "add", "alice", "hunter2"]));
+        cmd_add_user(add_user_matches(&alice), cfg.clone()).await?;
+
+        let bob = get_arg_matches(Some(vec!["a", "user", "add", "bob", "hunter3"]));
+        let wrap_cfg = WrapConfigure::from(cfg.clone());
+        let data = FormData::from("username=alice&password=hunter2".to_string());
+
+        let (login, add) = tokio::join!(
+            verify_login(&wrap_cfg, &data),
+            cmd_add_user(add_user_matches(&bob), cfg.clone())
+        );
+        assert!(login?);
+        add?;
+
+        let pool = cfg.connect_read_only_database().await?;
+        let (integrity,) = sqlx::query_as::<_, (String,)>("PRAGMA integrity_check")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(integrity, "ok");
+
+        let users =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "user" FROM "accounts" ORDER BY "user""#)
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(users, vec![("alice".to_string(),), ("bob".to_string(),)]);
+
+        let data = FormData::from("username=bob&password=hunter3".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_while_adding_user() {
+        run_with_temp_database("concurrent", async_test_login_while_adding_user);
+    }
+
+    fn cookie_matches<'a>(args: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+        match args.subcommand() {
+            ("authenticate-cookie", Some(matches)) => matches,
+            _ => unreachable!(),
+        }
+    }
+
+    // Run `authenticate-cookie` as a request of its own, sharing no pools with other calls.
+    async fn authenticate_cookie(
+        matches: &ArgMatches<'_>,
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<bool> {
+        cmd_authenticate_cookie(matches, cfg, store, &RequestPools::default()).await
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let tmpdir = tempdir::TempDir::new("audit").unwrap();
+        let audit_path = tmpdir.path().join("audit.log");
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-audit-log={}\ncgit-simple-auth-protect=full",
+                audit_path.to_str().unwrap()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+
+        let args = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-cookie",
+            "",
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "private",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(!rt
+            .block_on(authenticate_cookie(
+                cookie_matches(&args),
+                cfg,
+                &mut MemorySessionStore::default()
+            ))
+            .unwrap());
+
+        let mut buffer = String::new();
+        std::fs::File::open(&audit_path)
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        let lines = buffer.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 1);
+        let event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(event["user"], "");
+        assert_eq!(event["source"], "git.example.com");
+        assert_eq!(event["repo"], "private");
+        assert_eq!(event["outcome"], "deny");
+        assert!(event["timestamp"].as_u64().unwrap() > 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&audit_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Unwritable audit log must not affect authentication result
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-audit-log={}\ncgit-simple-auth-protect=full",
+                tmpdir.path().join("CFG/audit.log").to_str().unwrap()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(!rt
+            .block_on(authenticate_cookie(
+                cookie_matches(&args),
+                cfg,
+                &mut MemorySessionStore::default()
+            ))
+            .unwrap());
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_add_user_with_hash(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+
+        let invalid = get_arg_matches(Some(vec!["a", "user", "add", "--hash", "alice", "hunter2"]));
+        assert!(cmd_add_user(add_user_matches(&invalid), cfg.clone())
+            .await
+            .is_err());
+
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "add",
+            "--hash",
+            "alice",
+            "$argon2id$v=19$m=4096,t=3,p=1$szYDnoQSVPmXq+RD2LneBw$fRETH//iCQuIX+SgjYPdZ9iIbM8gEy9fBjTJ/KFFJNM",
+        ]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let wrap_cfg = WrapConfigure::from(cfg);
+        let data = FormData::from("username=alice&password=hunter2".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        let data = FormData::from("username=alice&password=hunter3".to_string());
+        assert!(!verify_login(&wrap_cfg, &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_user_with_hash() {
+        run_with_temp_database("hash", async_test_add_user_with_hash);
+    }
+
+    fn import_matches<'a>(args: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+        match args.subcommand() {
+            ("user", Some(matches)) => match matches.subcommand() {
+                ("import", Some(matches)) => matches,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    async fn fetch_users(cfg: &Config) -> anyhow::Result<Vec<String>> {
+        let pool = cfg.connect_read_only_database().await?;
+        Ok(
+            sqlx::query_as::<_, (String,)>(r#"SELECT "user" FROM "accounts" ORDER BY "user""#)
+                .fetch_all(&pool)
+                .await?
+                .into_iter()
+                .map(|(user,)| user)
+                .collect(),
+        )
+    }
+
+    async fn async_test_import_users(cfg: Config, file: &str) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let existing = get_arg_matches(Some(vec!["a", "user", "add", "alice", "hunter2"]));
+        cmd_add_user(add_user_matches(&existing), cfg.clone()).await?;
+
+        let strict = get_arg_matches(Some(vec!["a", "user", "import", "--strict", file]));
+        assert!(cmd_import_users(
+            import_matches(&strict),
+            cfg.clone(),
+            &Interrupted::default()
+        )
+        .await
+        .is_err());
+        assert_eq!(fetch_users(&cfg).await?, vec!["alice"]);
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "import", file]));
+        cmd_import_users(
+            import_matches(&matches),
+            cfg.clone(),
+            &Interrupted::default(),
+        )
+        .await?;
+        assert_eq!(fetch_users(&cfg).await?, vec!["alice", "bob", "carol"]);
+
+        let wrap_cfg = WrapConfigure::from(cfg);
+        let data = FormData::from("username=bob&password=pass,word".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        let data = FormData::from("username=alice&password=hunter2".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_users() {
+        let tmpdir = tempdir::TempDir::new("import").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        let file = tmpdir.path().join("users.csv");
+        write_to_specify_file(
+            &file,
+            b"# user,password\n\
+            bob,pass,word\n\
+            carol\thunter3\n\
+            alice,duplicate\n\
+            bob,again\n\
+            bad user,hunter2\n\
+            averyveryverylongusername,hunter2\n\
+            dave,\n\
+            eve\n",
+        )
+        .unwrap();
+
+        block_on(async_test_import_users(cfg, file.to_str().unwrap())).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    #[cfg(unix)]
+    async fn async_test_import_interrupted(cfg: Config, file: &str) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        // Handler is installed before signal is sent, so test process is not terminated.
+        let interrupted = Interrupted::listen()?;
+        let pid = std::process::id();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            std::process::Command::new("kill")
+                .args(["-TERM", &pid.to_string()])
+                .status()
+                .unwrap();
+        });
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "import", "--hash", file]));
+        let err = cmd_import_users(import_matches(&matches), cfg.clone(), &interrupted)
+            .await
+            .unwrap_err();
+        assert!(interrupted.is_set());
+        assert!(err.to_string().starts_with("Interrupted at line"));
+        assert!(fetch_users(&cfg).await?.is_empty());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_import_interrupted() {
+        let tmpdir = tempdir::TempDir::new("import_interrupted").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        let hash = "$argon2id$v=19$m=4096,t=3,p=1$szYDnoQSVPmXq+RD2LneBw$fRETH//iCQuIX+SgjYPdZ9iIbM8gEy9fBjTJ/KFFJNM";
+        let file = tmpdir.path().join("users.csv");
+        let context = (0..200_000)
+            .map(|nth| format!("user{},{}\n", nth, hash))
+            .collect::<String>();
+        write_to_specify_file(&file, context.as_bytes()).unwrap();
+
+        block_on(async_test_import_interrupted(cfg, file.to_str().unwrap())).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_export_restore(cfg: Config, path: &Path) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for (user, passwd) in &[("alice", "hunter2"), ("bob", "hunter3")] {
+            let matches = get_arg_matches(Some(vec!["a", "user", "add", user, passwd]));
+            cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        }
+        let pool = cfg.connect_database().await?;
+        sqlx::query(r#"INSERT INTO "repos" VALUES (?, ?), (?, ?)"#)
+            .bind("export_a")
+            .bind("alice")
+            .bind("export_b")
+            .bind("bob alice")
+            .execute(&pool)
+            .await?;
+        sqlx::query(r#"UPDATE "accounts" SET "expires_at" = 4102444800 WHERE "user" = 'alice'"#)
+            .execute(&pool)
+            .await?;
+        sqlx::query(r#"UPDATE "accounts" SET "disabled" = 1 WHERE "user" = 'bob'"#)
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+        run_group_command(&cfg, &["add", "crew"]).await?;
+        run_group_command(&cfg, &["add", "idle"]).await?;
+        run_group_command(&cfg, &["member", "crew", "alice"]).await?;
+        run_group_command(&cfg, &["grant", "crew", "export_c"]).await?;
+
+        let mut exported = Vec::new();
+        cmd_export(cfg.clone(), &mut exported).await?;
+
+        let document: serde_json::Value =
+            serde_json::from_str(verify_export_checksum(std::str::from_utf8(&exported)?)?)?;
+        assert_eq!(document["accounts"][0]["user"], "alice");
+        assert_eq!(
+            document["accounts"][0]["repos"],
+            serde_json::json!(["export_a", "export_b"])
+        );
+        assert_eq!(document["accounts"][0]["disabled"], false);
+        assert_eq!(document["accounts"][0]["expires_at"], 4102444800i64);
+        assert_eq!(document["accounts"][1]["user"], "bob");
+        assert_eq!(
+            document["accounts"][1]["repos"],
+            serde_json::json!(["export_b"])
+        );
+        assert_eq!(document["accounts"][1]["disabled"], true);
+        assert!(document["accounts"][1].get("expires_at").is_none());
+        assert_eq!(
+            document["groups"],
+            serde_json::json!([
+                {"group": "crew", "members": ["alice"], "repos": ["export_c"]},
+                {"group": "idle", "members": [], "repos": []}
+            ])
+        );
+
+        write_to_specify_file(&path.to_path_buf(), &exported)?;
+
+        let reset = get_arg_matches(Some(vec!["a", "database", "reset", "--confirm"]));
+        match reset.subcommand() {
+            ("database", Some(matches)) => match matches.subcommand() {
+                ("reset", Some(matches)) => cmd_reset_database(matches, cfg.clone()).await?,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+        assert!(fetch_users(&cfg).await?.is_empty());
+
+        let restore = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "import",
+            "--restore",
+            path.to_str().unwrap(),
+        ]));
+        cmd_restore_users(
+            import_matches(&restore),
+            cfg.clone(),
+            &Interrupted::default(),
+        )
+        .await?;
+
+        let mut restored = Vec::new();
+        cmd_export(cfg.clone(), &mut restored).await?;
+        assert_eq!(String::from_utf8(exported)?, String::from_utf8(restored)?);
+
+        // Group grants and disabled accounts survive the round trip
+        let pool = cfg.connect_read_only_database().await?;
+        assert_eq!(get_repo_users(&pool, "export_c").await?, vec!["alice"]);
+        pool.close().await;
+        let wrap_cfg = WrapConfigure::from(cfg);
+        let data = FormData::from("username=alice&password=hunter2".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        let data = FormData::from("username=bob&password=hunter3".to_string());
+        assert!(!verify_login(&wrap_cfg, &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_restore() {
+        let tmpdir = tempdir::TempDir::new("export").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_export_restore(
+            cfg,
+            &tmpdir.path().join("export.json"),
+        ))
+        .unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_export_file(cfg: Config, dir: &Path) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for (user, passwd) in &[("alice", "hunter2"), ("bob", "hunter3")] {
+            let matches = get_arg_matches(Some(vec!["a", "user", "add", user, passwd]));
+            cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        }
+
+        let backup = dir.join("backup.json");
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "export",
+            "--output",
+            backup.to_str().unwrap(),
+        ]));
+        let output = matches
+            .subcommand_matches("user")
+            .and_then(|m| m.subcommand_matches("export"))
+            .and_then(|m| m.value_of("output"))
+            .unwrap();
+        cmd_export_to_file(cfg.clone(), output).await?;
+        let content = std::fs::read_to_string(&backup)?;
+        assert!(content.lines().last().unwrap().starts_with("sha256 "));
+        let mut exported = Vec::new();
+        cmd_export(cfg.clone(), &mut exported).await?;
+        assert_eq!(content, String::from_utf8(exported)?);
+        // Temporary file is renamed over the target, nothing else is left
+        assert_eq!(std::fs::read_dir(dir)?.count(), 1);
+
+        // Failed export leaves neither target nor temporary file
+        let missing = dir.join("missing").join("backup.json");
+        assert!(cmd_export_to_file(cfg.clone(), missing.to_str().unwrap())
+            .await
+            .is_err());
+        assert!(!missing.exists());
+
+        let reset = get_arg_matches(Some(vec!["a", "database", "reset", "--confirm"]));
+        match reset.subcommand() {
+            ("database", Some(matches)) => match matches.subcommand() {
+                ("reset", Some(matches)) => cmd_reset_database(matches, cfg.clone()).await?,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+
+        let restore = |path: PathBuf| {
+            let cfg = cfg.clone();
+            async move {
+                let matches = get_arg_matches(Some(vec![
+                    "a",
+                    "user",
+                    "import",
+                    "--restore",
+                    "--input",
+                    path.to_str().unwrap(),
+                ]));
+                cmd_restore_users(import_matches(&matches), cfg, &Interrupted::default()).await
+            }
+        };
+
+        let corrupted = dir.join("corrupted.json");
+        std::fs::write(&corrupted, content.replacen("alice", "alicf", 1))?;
+        let err = restore(corrupted).await.unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+        assert!(fetch_users(&cfg).await?.is_empty());
+
+        restore(backup).await?;
+        assert_eq!(fetch_users(&cfg).await?.len(), 2);
+        let data = FormData::from("username=alice&password=hunter2".to_string());
+        assert!(verify_login(&WrapConfigure::from(cfg), &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_file() {
+        let tmpdir = tempdir::TempDir::new("export_file").unwrap();
+        let backups = tmpdir.path().join("backups");
+        std::fs::create_dir(&backups).unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_export_file(cfg, &backups)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn run_group_command(cfg: &Config, args: &[&str]) -> anyhow::Result<()> {
+        let args = get_arg_matches(Some([&["a", "group"], args].concat()));
+        match args.subcommand() {
+            ("group", Some(matches)) => match matches.subcommand() {
+                ("add", Some(matches)) => cmd_add_group(matches, cfg.clone()).await,
+                ("del", Some(matches)) => cmd_delete_group(matches, cfg.clone()).await,
+                ("grant", Some(matches)) => cmd_group_grant(matches, cfg.clone()).await,
+                ("member", Some(matches)) => cmd_group_member(matches, cfg.clone()).await,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    async fn async_test_show_access(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for user in ["uma", "ben"] {
+            let add = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(add_user_matches(&add), cfg.clone()).await?;
+        }
+        for (repo, user) in [("access_direct", "uma"), ("access_other", "ben")] {
+            let args = get_arg_matches(Some(vec!["a", "repo", "add", repo, user]));
+            if let ("repo", Some(matches)) = args.subcommand() {
+                if let ("add", Some(matches)) = matches.subcommand() {
+                    cmd_repo_user_control(matches, cfg.clone(), false).await?;
+                }
+            }
+        }
+        run_group_command(&cfg, &["add", "crew"]).await?;
+        run_group_command(&cfg, &["member", "crew", "uma"]).await?;
+        run_group_command(&cfg, &["grant", "crew", "access_group"]).await?;
+
+        let mut store = MemorySessionStore::default();
+        store
+            .set_repo_set("access_direct", &["uma".to_string()], 300)
+            .await?;
+        // Cache built before uma joined crew, and before uma lost access to access_other
+        store.set_repo_set("access_group", &[], 300).await?;
+        store
+            .set_repo_set("access_other", &["ben".to_string(), "uma".to_string()], 300)
+            .await?;
+
+        let pool = cfg.connect_read_only_database().await?;
+        let access = collect_user_access(&pool, &mut store, "uma").await?;
+        let summary = access
+            .iter()
+            .map(|x| (x.repo.as_str(), x.direct, x.groups.len(), x.cached, x.stale))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("access_direct", true, 0, Some(true), false),
+                ("access_group", false, 1, Some(false), true),
+                ("access_other", false, 0, Some(true), true),
+            ]
+        );
+        assert!(collect_user_access(&pool, &mut store, "nobody")
+            .await
+            .is_err());
+        pool.close().await;
+
+        let args = get_arg_matches(Some(vec!["a", "user", "access", "uma", "--json"]));
+        let mut output = Vec::new();
+        cmd_show_access(user_matches(&args), cfg.clone(), &mut store, &mut output).await?;
+        let json: serde_json::Value = serde_json::from_slice(&output)?;
+        assert_eq!(json[1]["groups"][0], "crew");
+        assert_eq!(json[1]["stale"], true);
+
+        let args = get_arg_matches(Some(vec!["a", "user", "access", "uma"]));
+        let mut output = Vec::new();
+        cmd_show_access(user_matches(&args), cfg, &mut store, &mut output).await?;
+        let output = String::from_utf8(output)?;
+        assert!(output.starts_with("User uma can access 3 repositories\n"));
+        assert!(output.contains("access_group\tgranted by group crew\tcache denied\tSTALE"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_access() {
+        run_with_temp_database("show_access", async_test_show_access);
+    }
+
+    async fn async_test_group_access(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let carol = get_arg_matches(Some(vec!["a", "user", "add", "carol", "hunter2"]));
+        cmd_add_user(add_user_matches(&carol), cfg.clone()).await?;
+
+        let cookie = Cookie::generate("carol");
+        let mut conn = cfg.connect_redis().await?;
+        conn.set_ex::<_, _, String>(
+            format!("cgit_auth_{}", cookie.get_key()),
+            cookie.get_body(),
+            60,
+        )
+        .await?;
+
+        let cookie = format!("cgit_auth={}", cookie);
+        let args = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-cookie",
+            &cookie,
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "group_repo",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+
+        let mut store = cfg.connect_session_store().await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), store.as_mut()).await?);
+
+        run_group_command(&cfg, &["add", "team"]).await?;
+        run_group_command(&cfg, &["member", "team", "carol"]).await?;
+        assert!(run_group_command(&cfg, &["member", "team", "nobody"])
+            .await
+            .is_err());
+        run_group_command(&cfg, &["grant", "team", "group_repo"]).await?;
+
+        // Carol has no direct grant, access only comes from group
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), store.as_mut()).await?);
+
+        // Membership change must invalidate cached repository ACL
+        run_group_command(&cfg, &["member", "team", "carol", "--remove"]).await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), store.as_mut()).await?);
+
+        run_group_command(&cfg, &["member", "team", "carol"]).await?;
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), store.as_mut()).await?);
+
+        run_group_command(&cfg, &["del", "team"]).await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), store.as_mut()).await?);
+
+        conn.del::<_, i32>("cgit_repo_group_repo").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_access() {
+        run_with_temp_config(
+            "group",
+            b"cgit-simple-auth-protect=full",
+            async_test_group_access,
+        );
+    }
+
+    fn render_body(cfg: Config) -> String {
+        block_on(async {
+            let mut store = cfg.connect_session_store().await?;
+            render_body_with_store(cfg, store.as_mut()).await
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_body_escape_urls() {
+        let payload = "/\"><script>alert(1)</script>";
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "body",
+            "",
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "",
+            "",
+            payload,
+            payload,
+        ]));
+        let mut output = Vec::new();
+        let mut module = IOModule {
+            reader: &b""[..],
+            writer: &mut output,
+        };
+        if let ("body", Some(matches)) = matches.subcommand() {
+            block_on(module.cmd_body(
+                matches,
+                Config::generate_test_config(),
+                &mut MemorySessionStore::default(),
+            ))
+            .unwrap();
+        }
+        let body = String::from_utf8(output).unwrap();
+        assert!(!body.contains("<script>"));
+        assert!(body.contains(r#"action="/&quot;&gt;&lt;script&gt;"#));
+        assert!(body.contains(r#"name="redirect" value="/&quot;&gt;&lt;script&gt;"#));
+    }
+
+    async fn async_test_redirect_query(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "zelda", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+
+        // cgit passes current url without query string
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "body",
+            "",
+            "GET",
+            "h=branch&id=abc",
+            "",
+            "/repo/log/",
+            "git.example.com",
+            "on",
+            "repo",
+            "log",
+            "/repo/log/",
+            "/?p=login",
+        ]));
+        let mut output = Vec::new();
+        let mut module = IOModule {
+            reader: &b""[..],
+            writer: &mut output,
+        };
+        if let ("body", Some(matches)) = matches.subcommand() {
+            module.cmd_body(matches, cfg.clone(), &mut store).await?;
+        }
+        let body = String::from_utf8(output)?;
+        let target = "/repo/log/?h=branch&id=abc";
+        assert!(body.contains(&format!(
+            r#"name="redirect" value="{}""#,
+            handlebars::html_escape(target)
+        )));
+
+        let post = |redirect: &str, csrf_token: String| {
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("redirect", redirect)
+                .append_pair("username", "zelda")
+                .append_pair("password", "hunter2")
+                .append_pair("csrf_token", &csrf_token)
+                .finish()
+        };
+        for (redirect, location) in [
+            (target, target),
+            (
+                "https://git.example.com/repo/?h=a&id=b",
+                "https://git.example.com/repo/?h=a&id=b",
+            ),
+            ("//evil.example.com/?h=branch", "/"),
+            // Form without the field falls back to referer
+            ("", "https://git.example.com/?p=login"),
+        ] {
+            let input = post(redirect, generate_csrf_token(&mut store).await?);
+            let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+            assert!(out.starts_with("Status: 302"), "{}", out);
+            assert!(
+                out.contains(&format!("\nLocation: {}\n", location)),
+                "{}",
+                out
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_query() {
+        run_with_temp_database("redirect_query", async_test_redirect_query);
+    }
+
+    async fn async_test_proof_of_work(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "pat", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+
+        let body = render_body_with_store(cfg.clone(), &mut store).await?;
+        assert!(body.contains(r#"name="pow_nonce""#));
+        assert!(body.contains("var difficulty = 8;"));
+        let plain = render_body_with_store(Config::generate_test_config(), &mut store).await?;
+        assert!(!plain.contains("pow_nonce"));
+        assert!(!plain.contains("<script>"));
+
+        let find_nonce = |challenge: &str, solved: bool| {
+            (0..)
+                .map(|nonce: u32| nonce.to_string())
+                .find(|nonce| verify_proof_of_work(challenge, nonce, 8) == solved)
+                .unwrap()
+        };
+        let post = |csrf_token: &str, nonce: Option<String>| {
+            let mut input = format!("username=pat&password=hunter2&csrf_token={}", csrf_token);
+            if let Some(nonce) = nonce {
+                input.push_str(&format!("&pow_nonce={}", nonce));
+            }
+            input
+        };
+
+        let token = generate_csrf_token(&mut store).await?;
+        let missing = post(&token, None);
+        let token = generate_csrf_token(&mut store).await?;
+        let insufficient = post(&token, Some(find_nonce(&token, false)));
+        for input in [missing, insufficient] {
+            let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+            assert!(out.starts_with("Status: 403"), "{}", out);
+            assert!(out.ends_with(
+                "\n\nLogin form was not verified by browser, please enable JavaScript and try again\n"
+            ));
+        }
+
+        let token = generate_csrf_token(&mut store).await?;
+        let input = post(&token, Some(find_nonce(&token, true)));
+        let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+        assert!(out.starts_with("Status: 302"), "{}", out);
+        // Proof is bound to the consumed token, replaying it fails
+        let out = post_login_with_store(input.as_bytes(), cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_proof_of_work() {
+        assert!(verify_proof_of_work("challenge", "", 0));
+        assert!(!verify_proof_of_work("challenge", "", 1));
+
+        let tmpdir = tempdir::TempDir::new("proof_of_work").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-pow-difficulty=25",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("pow-difficulty 25 is larger than 24"));
+
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-pow-difficulty=8");
+
+        block_on(async_test_proof_of_work(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn render_body_with_store(
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<String> {
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "body",
+            "",
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+        let mut output = Vec::new();
+        let mut module = IOModule {
+            reader: &b""[..],
+            writer: &mut output,
+        };
+
+        if let ("body", Some(matches)) = matches.subcommand() {
+            module.cmd_body(matches, cfg, store).await?;
+        }
+
+        Ok(String::from_utf8(output)?)
+    }
+
+    #[test]
+    fn test_login_template() {
+        let tmpdir = tempdir::TempDir::new("template").unwrap();
+        let template = tmpdir.path().join("login.html");
+        write_to_specify_file(
+            &template,
+            b"<form action=\"{{action}}\">custom-login-marker v{{version}}</form>",
+        )
+        .unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-login-template={}",
+                template.to_str().unwrap()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let output = render_body(Config::load_from_path(tmpdir.path().join("CFG")));
+        assert!(output.contains("custom-login-marker"));
+        assert!(output.contains("action=\"/?p"));
+        assert!(output.contains(env!("CARGO_PKG_VERSION")));
+        assert!(!output.contains("Authentication Required"));
+
+        // Unreadable template falls back to embedded page
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-login-template={}",
+                tmpdir.path().join("missing.html").to_str().unwrap()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let output = render_body(Config::load_from_path(tmpdir.path().join("CFG")));
+        assert!(output.contains("Authentication Required"));
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_login_message() {
+        let tmpdir = tempdir::TempDir::new("message").unwrap();
+        let cfg_path = tmpdir.path().join("CFG");
+
+        write_to_specify_file(&cfg_path, b"").unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(!output.contains("class=\"error\""));
+
+        write_to_specify_file(
+            &cfg_path,
+            b"cgit-simple-auth-login-message=<b>Authorized users only</b>",
+        )
+        .unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(output.contains("&lt;b&gt;Authorized users only&lt;/b&gt;"));
+        assert!(!output.contains("<b>Authorized users only</b>"));
+
+        write_to_specify_file(
+            &cfg_path,
+            b"cgit-simple-auth-login-message=<b>Authorized users only</b>\n\
+            cgit-simple-auth-login-message-raw=true",
+        )
+        .unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(output.contains("<b>Authorized users only</b>"));
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_login_theme() {
+        let tmpdir = tempdir::TempDir::new("login_theme").unwrap();
+        let cfg_path = tmpdir.path().join("CFG");
+
+        write_to_specify_file(&cfg_path, b"").unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(output.contains("class=\"cgit-auth cgit-auth-auto\""));
+        assert!(output.contains("prefers-color-scheme: dark"));
+
+        write_to_specify_file(&cfg_path, b"cgit-simple-auth-login-theme=dark").unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(output.contains("class=\"cgit-auth cgit-auth-dark\""));
+
+        // Custom template receives theme too
+        let template = tmpdir.path().join("login.html");
+        write_to_specify_file(&template, b"<body data-theme=\"{{theme}}\"></body>").unwrap();
+        write_to_specify_file(
+            &cfg_path,
+            format!(
+                "cgit-simple-auth-login-theme=\"><script>\ncgit-simple-auth-login-template={}",
+                template.display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let output = render_body(Config::load_from_path(&cfg_path));
+        assert!(output.contains("<body data-theme=\"&quot;&gt;&lt;script&gt;\"></body>"));
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_csrf_token() {
+        let tmpdir = tempdir::TempDir::new("csrf").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(cmd_init(cfg.clone())).unwrap();
+        let dave = get_arg_matches(Some(vec!["a", "user", "add", "dave", "hunter2"]));
+        rt.block_on(cmd_add_user(add_user_matches(&dave), cfg.clone()))
+            .unwrap();
+
+        // Missing token is rejected even with correct password
+        let out = post_login(b"username=dave&password=hunter2", cfg.clone());
+        assert!(out.starts_with("Status: 403"));
+
+        let out = post_login(
+            b"username=dave&password=hunter2&csrf_token=forged",
+            cfg.clone(),
+        );
+        assert!(out.starts_with("Status: 403"));
+
+        let body = render_body(cfg.clone());
+        let token = get_csrf_token(&body);
+        assert!(!token.is_empty());
+
+        let input = format!("username=dave&password=hunter2&csrf_token={}", token);
+        let out = post_login(input.as_bytes(), cfg.clone());
+        assert!(out.starts_with("Status: 302"));
+
+        // Replayed token has been consumed by previous post
+        let out = post_login(input.as_bytes(), cfg);
+        assert!(out.starts_with("Status: 403"));
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_redis_database_index(first: Config, second: Config) -> anyhow::Result<()> {
+        let key = format!("cgit_auth_test_{}", rand_str(8));
+        let mut first = first.connect_redis().await?;
+        let mut second = second.connect_redis().await?;
+
+        first.set_ex::<_, _, String>(&key, "value", 60).await?;
+        assert!(first.exists::<_, bool>(&key).await?);
+        assert!(!second.exists::<_, bool>(&key).await?);
+
+        first.del::<_, i32>(&key).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_redis_database_index() {
+        let tmpdir = tempdir::TempDir::new("redis").unwrap();
+        let load_config = |url: &str| {
+            let path = tmpdir.path().join("CFG");
+            write_to_specify_file(
+                &path,
+                format!("cgit-simple-auth-redis-url={}", url).as_bytes(),
+            )
+            .unwrap();
+            Config::load_from_path(path)
+        };
+        let first = load_config("redis://127.0.0.1/3");
+        let second = load_config("redis://127.0.0.1/4");
+
+        block_on(async_test_redis_database_index(first, second)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_redis_url_schemes() {
+        let tmpdir = tempdir::TempDir::new("redis_url").unwrap();
+        let load_info = |url: &str| {
+            let path = tmpdir.path().join("CFG");
+            write_to_specify_file(
+                &path,
+                format!("cgit-simple-auth-redis-url={}", url).as_bytes(),
+            )
+            .unwrap();
+            let info = Config::load_from_path(path).get_redis_connection_info();
+            if let Ok(info) = &info {
+                assert!(redis::Client::open(info.clone()).is_ok());
+            }
+            info
+        };
+
+        let info = load_info("redis://cache.example.com:6380/2").unwrap();
+        assert_eq!(
+            *info.addr,
+            redis::ConnectionAddr::Tcp("cache.example.com".to_string(), 6380)
+        );
+        assert_eq!(info.db, 2);
+        assert_eq!(info.passwd, None);
+
+        let info = load_info("redis://[::1]/").unwrap();
+        assert_eq!(
+            *info.addr,
+            redis::ConnectionAddr::Tcp("::1".to_string(), 6379)
+        );
+
+        let info = load_info("redis://:hunter2@127.0.0.1/").unwrap();
+        assert_eq!(info.username, None);
+        assert_eq!(info.passwd.as_deref(), Some("hunter2"));
+
+        let info = load_info("redis://cgit:p%40ss@[2001:db8::1]:6380/1").unwrap();
+        assert_eq!(
+            *info.addr,
+            redis::ConnectionAddr::Tcp("2001:db8::1".to_string(), 6380)
+        );
+        assert_eq!(info.username.as_deref(), Some("cgit"));
+        assert_eq!(info.passwd.as_deref(), Some("p@ss"));
+
+        let info = load_info("redis+unix:///run/redis/redis.sock").unwrap();
+        assert_eq!(
+            *info.addr,
+            redis::ConnectionAddr::Unix(PathBuf::from("/run/redis/redis.sock"))
+        );
+
+        let err = load_info("rediss://127.0.0.1/").unwrap_err().to_string();
+        assert!(err.contains("tokio-tls-comp"));
+
+        tmpdir.close().unwrap();
+    }
+
+    fn get_set_cookie(response: &str) -> String {
+        let line = response
+            .lines()
+            .find(|line| line.starts_with("Set-Cookie"))
+            .unwrap();
+        let (_, value) = line.split_once(':').unwrap();
+        let (value, _) = value.split_once(';').unwrap();
+        value.trim().to_string()
+    }
+
+    #[test]
+    fn test_sqlite_session_backend() {
+        let tmpdir = tempdir::TempDir::new("session").unwrap();
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-session-backend=sqlite\ncgit-simple-auth-protect=full",
+        );
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(cmd_init(cfg.clone())).unwrap();
+        let erin = get_arg_matches(Some(vec!["a", "user", "add", "erin", "hunter2"]));
+        rt.block_on(cmd_add_user(add_user_matches(&erin), cfg.clone()))
+            .unwrap();
+        let repo = get_arg_matches(Some(vec!["a", "repo", "add", "sqlite_repo", "erin"]));
+        if let ("repo", Some(matches)) = repo.subcommand() {
+            if let ("add", Some(matches)) = matches.subcommand() {
+                rt.block_on(cmd_repo_user_control(matches, cfg.clone(), false))
+                    .unwrap();
+            }
+        }
+
+        let body = render_body(cfg.clone());
+        let token = get_csrf_token(&body);
+
+        let input = format!("username=erin&password=hunter2&csrf_token={}", token);
+        let out = post_login(input.as_bytes(), cfg.clone());
+        assert!(out.starts_with("Status: 302"));
+        assert!(post_login(input.as_bytes(), cfg.clone()).starts_with("Status: 403"));
+
+        let cookie = get_set_cookie(&out);
+        let check_cookie = |repo: &str| {
+            let args = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                &cookie,
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "on",
+                repo,
+                "",
+                "/",
+                "/?p=login",
+            ]));
+            let opened = cfg.get_session_store_connections();
+            let ret = rt.block_on(async_main(args, cfg.clone())).unwrap();
+            // Repository ACL cache, session lookup and metrics share one connection.
+            assert_eq!(cfg.get_session_store_connections(), opened + 1);
+            ret == 1
+        };
+        assert!(check_cookie("sqlite_repo"));
+        assert!(!check_cookie("other_repo"));
+
+        let (count,) = rt
+            .block_on(async {
+                let pool = cfg.connect_read_only_database().await?;
+                Ok::<_, anyhow::Error>(
+                    sqlx::query_as::<_, (i32,)>(r#"SELECT COUNT(*) FROM "sessions""#)
+                        .fetch_one(&pool)
+                        .await?,
+                )
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let sessions = rt
+            .block_on(async {
+                let mut store = cfg.connect_session_store().await?;
+                collect_user_sessions(store.as_mut(), "erin").await
+            })
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].ttl > 0 && sessions[0].ttl <= cfg.cookie_ttl);
+
+        let killed = rt
+            .block_on(async {
+                let mut store = cfg.connect_session_store().await?;
+                kill_user_sessions(store.as_mut(), "erin").await
+            })
+            .unwrap();
+        assert_eq!(killed, 1);
+        assert!(!check_cookie("sqlite_repo"));
+
+        let attempts = rt
+            .block_on(async {
+                let mut store = cfg.connect_session_store().await?;
+                store.record_attempt("cgit_attempt_user_erin", 60).await?;
+                store.record_attempt("cgit_attempt_user_erin", 60).await?;
+                store.list_attempts("cgit_attempt_user_erin", 60).await
+            })
+            .unwrap();
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[0] <= attempts[1]);
+
+        tmpdir.close().unwrap();
+    }
+
+    fn get_csrf_token(body: &str) -> &str {
+        let (_, token) = body.split_once(r#"name="csrf_token" value=""#).unwrap();
+        token.split_once('"').unwrap().0
+    }
+
+    async fn async_test_memory_session_store(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let frank = get_arg_matches(Some(vec!["a", "user", "add", "frank", "hunter2"]));
+        cmd_add_user(add_user_matches(&frank), cfg.clone()).await?;
+        let pool = cfg.connect_database().await?;
+        sqlx::query(r#"INSERT INTO "repos" VALUES (?, ?)"#)
+            .bind("memory_repo")
+            .bind("frank")
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+
+        let mut store = MemorySessionStore::default();
+
+        let body = render_body_with_store(cfg.clone(), &mut store).await?;
+        let input = format!(
+            "username=frank&password=hunter2&csrf_token={}",
+            get_csrf_token(&body)
+        );
+        let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+
+        let response = post_login_with_store(
+            format!(
+                "username=frank&password=hunter2&csrf_token={}",
+                get_csrf_token(&render_body_with_store(cfg.clone(), &mut store).await?)
+            )
+            .as_bytes(),
+            cfg.clone(),
+            &mut store,
+        )
+        .await?;
+        let cookie = get_set_cookie(&response);
+        for (repo, expected) in &[("memory_repo", true), ("other_repo", false)] {
+            let args = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                &cookie,
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "on",
+                repo,
+                "",
+                "/",
+                "/?p=login",
+            ]));
+            assert_eq!(
+                authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?,
+                *expected
+            );
+        }
+
+        // Expired session is not returned
+        store.set_session("cgit_auth_expired", "body", 0).await?;
+        assert!(store.get_session("cgit_auth_expired").await?.is_none());
+        assert!(!store.delete_session("cgit_auth_expired").await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_session_store() {
+        run_with_temp_config(
+            "memory",
+            b"cgit-simple-auth-protect=full",
+            async_test_memory_session_store,
+        );
+    }
+
+    #[test]
+    fn test_validate_password() {
+        let policy = PasswordPolicy::default();
+        assert!(validate_password(&policy, "a").is_ok());
+
+        let policy = PasswordPolicy {
+            min_length: 8,
+            ..Default::default()
+        };
+        let err = validate_password(&policy, "short").unwrap_err().to_string();
+        assert!(err.contains("at least 8 characters"));
+        assert!(validate_password(&policy, "longenough").is_ok());
+
+        let policy = PasswordPolicy {
+            require_mixed_case: true,
+            ..Default::default()
+        };
+        let err = validate_password(&policy, "lowercase")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("upper and lower case"));
+        assert!(validate_password(&policy, "UPPERCASE").is_err());
+        assert!(validate_password(&policy, "MixedCase").is_ok());
+
+        let policy = PasswordPolicy {
+            require_digit: true,
+            ..Default::default()
+        };
+        let err = validate_password(&policy, "nodigit")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("digit"));
+        assert!(validate_password(&policy, "digit1").is_ok());
+
+        let policy = PasswordPolicy {
+            min_length: 8,
+            require_mixed_case: true,
+            require_digit: true,
+            history_size: 0,
+        };
+        let err = validate_password(&policy, "abc").unwrap_err().to_string();
+        assert!(err.contains("at least 8 characters"));
+        assert!(err.contains("upper and lower case"));
+        assert!(err.contains("digit"));
+        let err = validate_password(&policy, "abcdefgh1")
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains("characters"));
+        assert!(!err.contains("digit"));
+        assert!(validate_password(&policy, "Abcdefg1").is_ok());
+    }
+
+    async fn async_test_change_password(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+
+        let weak = get_arg_matches(Some(vec!["a", "user", "add", "grace", "weak"]));
+        assert!(cmd_add_user(add_user_matches(&weak), cfg.clone())
+            .await
+            .is_err());
+        let grace = get_arg_matches(Some(vec!["a", "user", "add", "grace", "Hunter22"]));
+        cmd_add_user(add_user_matches(&grace), cfg.clone()).await?;
+
+        let passwd = |password: &'static str| {
+            get_arg_matches(Some(vec!["a", "user", "passwd", "grace", password]))
+        };
+        let run_passwd = |args: ArgMatches<'static>| {
+            let cfg = cfg.clone();
+            async move {
+                match args.subcommand() {
+                    ("user", Some(matches)) => match matches.subcommand() {
+                        ("passwd", Some(matches)) => {
+                            cmd_change_password(
+                                matches,
+                                cfg,
+                                Some(&mut MemorySessionStore::default()),
+                            )
+                            .await
+                        }
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                }
+            }
+        };
+
+        assert!(run_passwd(passwd("weak")).await.is_err());
+        run_passwd(passwd("Hunter33")).await?;
+
+        let wrap_cfg = WrapConfigure::from(cfg.clone());
+        let old = FormData::from("username=grace&password=Hunter22".to_string());
+        let new = FormData::from("username=grace&password=Hunter33".to_string());
+        assert!(!verify_login(&wrap_cfg, &old).await?);
+        assert!(verify_login(&wrap_cfg, &new).await?);
+
+        let missing = get_arg_matches(Some(vec!["a", "user", "passwd", "nobody", "Hunter33"]));
+        assert!(run_passwd(missing).await.is_err());
+        Ok(())
+    }
+
+    async fn async_test_password_history(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "heidi", "first"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let passwd = |password: &'static str| {
+            let matches = get_arg_matches(Some(vec!["a", "user", "passwd", "heidi", password]));
+            let cfg = cfg.clone();
+            async move { change_password(user_matches(&matches), cfg, None, &mut Vec::new()).await }
+        };
+        let is_weak = |ret: anyhow::Result<()>| {
+            matches!(
+                ret.unwrap_err().downcast_ref::<AuthError>(),
+                Some(AuthError::WeakPassword(_))
+            )
+        };
+
+        // Current password and the last 2 before it are refused
+        assert!(is_weak(passwd("first").await));
+        passwd("second").await?;
+        passwd("third").await?;
+        assert!(is_weak(passwd("third").await));
+        assert!(is_weak(passwd("second").await));
+        assert!(is_weak(passwd("first").await));
+        passwd("fourth").await?;
+        // first has fallen out of the history
+        passwd("first").await?;
+        let form = FormData::from("username=heidi&password=first".to_string());
+        assert!(verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+
+        // Given hash is not checked
+        let hash = FormData::gen_string_argon2_hash(
+            "first",
+            Default::default(),
+            DEFAULT_ARGON2_OUTPUT_LEN,
+            DEFAULT_ARGON2_SALT_LEN,
+            None,
+        )?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "passwd", "heidi", "--hash", &hash]));
+        change_password(user_matches(&matches), cfg.clone(), None, &mut Vec::new()).await?;
+
+        // History goes away with the account
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "heidi"]));
+        cmd_delete_user(user_matches(&del), cfg.clone(), None).await?;
+        let pool = cfg.connect_database().await?;
+        let (count,) = sqlx::query_as::<_, (i64,)>(r#"SELECT COUNT(*) FROM "password_history""#)
+            .fetch_one(&pool)
+            .await?;
+        pool.close().await;
+        assert_eq!(count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_history() {
+        run_with_temp_config(
+            "password_history",
+            b"cgit-simple-auth-password-history-size=3",
+            async_test_password_history,
+        );
+    }
+
+    #[test]
+    fn test_change_password() {
+        run_with_temp_config(
+            "passwd",
+            b"cgit-simple-auth-password-min-length=8\n\
+            cgit-simple-auth-password-require-mixed-case=true\n\
+            cgit-simple-auth-password-require-digit=true",
+            async_test_change_password,
+        );
+    }
+
+    async fn post_with_new_token(
+        user: &str,
+        password: &str,
+        cfg: &Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<String> {
+        let input = format!(
+            "username={}&password={}&csrf_token={}",
+            user,
+            password,
+            generate_csrf_token(store).await?
+        );
+        post_login_with_store(input.as_bytes(), cfg.clone(), store).await
+    }
+
+    async fn async_test_allowed_hosts(mut cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "yolanda", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+
+        // Empty list accepts any host with host-only cookie
+        let out = post_with_new_token("yolanda", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        assert!(!out.contains("Domain="));
+
+        // Request host is git.example.com, port is ignored
+        cfg.set_allowed_hosts(&["other.example.com", "Git.Example.com"]);
+        let out = post_with_new_token("yolanda", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        assert!(out.contains("; Domain=git.example.com;"));
+        assert_eq!(
+            cfg.get_cookie_domain_for("git.example.com:8080"),
+            Some("git.example.com")
+        );
+
+        cfg.set_allowed_hosts(&["other.example.com"]);
+        let sessions = store.get_user_sessions("yolanda").await?.len();
+        let out = post_with_new_token("yolanda", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 400"));
+        assert!(!out.contains("Set-Cookie"));
+        assert_eq!(store.get_user_sessions("yolanda").await?.len(), sessions);
+        assert!(!cfg.is_host_allowed("evil.example.com"));
+        assert!(!cfg.is_host_allowed("other.example.com; Domain=evil.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        run_with_temp_database("allowed_hosts", async_test_allowed_hosts);
+    }
+
+    async fn async_test_rate_limit(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for user in &["heidi", "ivan"] {
+            let matches = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        }
+
+        // Host limit is 3, user limit is 2
+        let mut store = MemorySessionStore::default();
+        for user in &["heidi", "ivan", "nobody"] {
+            let out = post_with_new_token(user, "wrong", &cfg, &mut store).await?;
+            assert!(out.starts_with("Status: 403"));
+        }
+        // Spraying from same host is blocked even for a correct password of another user
+        let out = post_with_new_token("heidi", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 429"));
+        // Remaining lockout is reported instead of whole window
+        let retry_after: u64 = out
+            .lines()
+            .find_map(|line| line.strip_prefix("Retry-After: "))
+            .unwrap()
+            .parse()?;
+        assert!((1..=60).contains(&retry_after));
+
+        // Same user from different hosts is still locked by per-user limit
+        let mut store = MemorySessionStore::default();
+        std::env::set_var("HTTP_X_RATE_LIMIT_TEST", "192.0.2.1");
+        let out = post_with_new_token("ivan", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        std::env::set_var("HTTP_X_RATE_LIMIT_TEST", "192.0.2.2, 10.0.0.1");
+        let out = post_with_new_token("ivan", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        std::env::set_var("HTTP_X_RATE_LIMIT_TEST", "192.0.2.3");
+        let out = post_with_new_token("ivan", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 429"));
+        let out = post_with_new_token("heidi", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        std::env::remove_var("HTTP_X_RATE_LIMIT_TEST");
+
+        // Attempts fall out of sliding window
+        store.record_attempt("cgit_attempt_user_old", 0).await?;
+        assert_eq!(store.count_attempts("cgit_attempt_user_old", 0).await?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let tmpdir = tempdir::TempDir::new("ratelimit").unwrap();
+        let audit_path = tmpdir.path().join("audit.log");
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            format!(
+                "cgit-simple-auth-max-attempts-per-host=3\n\
+                cgit-simple-auth-max-attempts-per-user=2\n\
+                cgit-simple-auth-attempts-window=60\n\
+                cgit-simple-auth-client-ip-header=X-Rate-Limit-Test\n\
+                cgit-simple-auth-audit-log={}",
+                audit_path.to_str().unwrap()
+            )
+            .as_bytes(),
+        );
+
+        block_on(async_test_rate_limit(cfg)).unwrap();
+
+        // Logins refused by rate limit are audited as lockout, not as bad credentials
+        let outcomes = std::fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["outcome"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            ["deny", "deny", "deny", "lockout", "deny", "deny", "lockout", "allow"]
+        );
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_redis_attempts() -> anyhow::Result<()> {
+        let cfg = Config::generate_test_config();
+        let mut store = cfg.connect_session_store().await?;
+        let key = format!("cgit_attempt_host_test_{}", rand_str(8));
+        for _ in 0..3 {
+            store.record_attempt(&key, 60).await?;
+        }
+        assert_eq!(store.count_attempts(&key, 60).await?, 3);
+        store.delete_session(&key).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_redis_attempts() {
+        block_on(async_test_redis_attempts()).unwrap();
+    }
+
+    async fn async_test_denial_body(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let judy = get_arg_matches(Some(vec!["a", "user", "add", "judy", "hunter2"]));
+        cmd_add_user(add_user_matches(&judy), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+
+        let out = post_login_with_store(b"username=judy&password=hunter2", cfg.clone(), &mut store)
+            .await?;
+        assert!(out.starts_with("Status: 403"));
+        assert!(out.contains("Content-Type: text/plain"));
+        assert!(out.ends_with("\n\nLogin form expired, please reload the page and try again\n"));
+
+        // Wrong password and unknown user must not be distinguishable
+        let wrong_password = post_with_new_token("judy", "wrong", &cfg, &mut store).await?;
+        let unknown_user = post_with_new_token("nobody", "wrong", &cfg, &mut store).await?;
+        assert!(wrong_password.starts_with("Status: 403"));
+        assert!(wrong_password.ends_with("\n\nInvalid username or password\n"));
+        assert_eq!(wrong_password, unknown_user);
+
+        let out = post_with_new_token("judy", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 429"));
+        // Remaining time is counted from first of the two failures, window is 300 seconds
+        let remaining: u64 = out
+            .strip_suffix(" seconds\n")
+            .and_then(|x| x.rsplit_once("\n\nToo many failed login attempts, please try again in "))
+            .unwrap()
+            .1
+            .parse()?;
+        assert!((1..=300).contains(&remaining));
+        assert!(out.contains(&format!("Retry-After: {}\n", remaining)));
+
+        let mut broken = cfg.clone();
+        broken.set_database_location("/nonexistent/auth.db");
+        let out = post_with_new_token(
+            "judy",
+            "hunter2",
+            &broken,
+            &mut MemorySessionStore::default(),
+        )
+        .await?;
+        assert!(out.starts_with("Status: 500 Internal Server Error\n"));
+        assert!(out.ends_with(
+            "\n\nAuthentication failed because of an internal error, please try again later\n"
+        ));
+
+        // Database without tables fails the query, still not reported as bad credentials
+        let uninitialized = cfg.get_database_location().replace("auth.db", "empty.db");
+        std::fs::File::create(&uninitialized)?;
+        broken.set_database_location(&uninitialized);
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("judy", "hunter2", &broken, &mut store).await?;
+        assert!(out.starts_with("Status: 500"));
+        let out = post_with_new_token("judy", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_denial_body() {
+        run_with_temp_config(
+            "denial",
+            b"cgit-simple-auth-max-attempts-per-host=2",
+            async_test_denial_body,
+        );
+    }
+
+    async fn async_test_maintenance(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "niaj", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+
+        let out = post_with_new_token("niaj", "hunter2", &cfg, &mut store).await?;
+        let cookie = get_set_cookie(&out);
+        let args = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-cookie",
+            &cookie,
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+
+        let mut output = Vec::new();
+        for state in [vec!["on"], vec!["status"]] {
+            let matches = get_arg_matches(Some([vec!["a", "maintenance"], state].concat()));
+            let (_, matches) = matches.subcommand();
+            cmd_maintenance(matches.unwrap(), &cfg, &mut store, &mut output).await?;
+        }
+        assert_eq!(String::from_utf8(output)?, "deny-logins\n");
+
+        // Login is denied even with right password, existing session still works
+        let out = post_with_new_token("niaj", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 503 Service Unavailable\n"));
+        assert!(out.ends_with("\n\nLogin is disabled for maintenance, please try again later\n"));
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+
+        let matches = get_arg_matches(Some(vec!["a", "maintenance", "on", "--deny-sessions"]));
+        let (_, matches) = matches.subcommand();
+        cmd_maintenance(matches.unwrap(), &cfg, &mut store, &mut Vec::new()).await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+
+        let matches = get_arg_matches(Some(vec!["a", "maintenance", "off"]));
+        let (_, matches) = matches.subcommand();
+        cmd_maintenance(matches.unwrap(), &cfg, &mut store, &mut Vec::new()).await?;
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+        let out = post_with_new_token("niaj", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let tmpdir = tempdir::TempDir::new("maintenance").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-protect=full");
+
+        block_on(async_test_maintenance(cfg)).unwrap();
+
+        // Maintenance enabled in cgitrc can not be turned off at runtime
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-maintenance-mode=true\ncgit-simple-auth-maintenance-deny-sessions=true",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert_eq!(cfg.get_maintenance_mode(), MaintenanceMode::DenyAll);
+        let mut store = MemorySessionStore::default();
+        let mode = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                store.set_counter(MAINTENANCE_KEY, 0).await?;
+                get_maintenance_mode(&cfg, &mut store).await
+            })
+            .unwrap();
+        assert_eq!(mode, MaintenanceMode::DenyAll);
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_batch(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let input = r#"[
+            {"op": "adduser", "user": "victor", "password": "hunter2"},
+            {"op": "adduser", "user": "victor", "password": "other"},
+            {"op": "adduser", "user": "wendy", "password": "-dash"},
+            {"op": "grant", "repo": "batch_repo", "user": "victor"},
+            {"op": "deluser", "user": "nobody"},
+            {"op": "passwd", "user": "victor", "password": "hunter3"},
+            {"op": "addgroup", "group": "ops"},
+            {"op": "addmember", "group": "ops", "user": "wendy"},
+            {"op": "grantgroup", "group": "ops", "repo": "batch_repo"},
+            {"op": "grantgroup", "group": "ops", "repo": "batch_repo"},
+            {"op": "addmember", "group": "ops", "user": "nobody"},
+            {"op": "frobnicate"}
+        ]"#;
+        let mut output = Vec::new();
+        assert!(!cmd_batch(cfg.clone(), input.as_bytes(), &mut output).await?);
+
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&output)?;
+        let summary = results
+            .iter()
+            .map(|x| {
+                (
+                    x["op"].as_str().unwrap(),
+                    x["ok"].as_bool().unwrap(),
+                    x["code"].as_i64(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("adduser", true, None),
+                ("adduser", false, Some(3)),
+                ("adduser", true, None),
+                ("grant", true, None),
+                ("deluser", false, Some(4)),
+                ("passwd", true, None),
+                ("addgroup", true, None),
+                ("addmember", true, None),
+                ("grantgroup", true, None),
+                ("grantgroup", false, Some(1)),
+                ("addmember", false, Some(4)),
+                ("frobnicate", false, Some(1)),
+            ]
+        );
+        assert_eq!(results[1]["error"], "User already exists!");
+
+        assert_eq!(fetch_users(&cfg).await?, vec!["victor", "wendy"]);
+        let pool = cfg.connect_read_only_database().await?;
+        assert_eq!(
+            get_repo_users(&pool, "batch_repo").await?,
+            vec!["victor", "wendy"]
+        );
+        pool.close().await;
+        let cfg = WrapConfigure::from(cfg);
+        for (form, expected) in [
+            ("username=victor&password=hunter3", true),
+            ("username=wendy&password=-dash", true),
+        ] {
+            let data = FormData::from(form.to_string());
+            assert_eq!(verify_login(&cfg, &data).await?, expected, "{}", form);
+        }
+
+        // Empty batch succeeds, invalid document fails as a whole
+        let mut output = Vec::new();
+        assert!(cmd_batch(cfg.get_config().clone(), &b"[]"[..], &mut output).await?);
+        assert_eq!(output, b"[]\n");
+        assert!(cmd_batch(cfg.get_config().clone(), &b"{"[..], &mut output)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch() {
+        let tmpdir = tempdir::TempDir::new("batch").unwrap();
+        let mut cfg = temp_database_config(tmpdir.path(), b"");
+        cfg.no_redis = true;
+
+        block_on(async_test_batch(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_serve(cfg: Config, socket: &Path) -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "oscar", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        // Socket of a process which is gone is replaced
+        drop(std::os::unix::net::UnixListener::bind(socket)?);
+        assert!(socket.exists());
+
+        let interrupted = Interrupted::default();
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "serve",
+            "--socket",
+            socket.to_str().unwrap(),
+        ]));
+        let serve = cmd_serve(matches.subcommand().1.unwrap(), cfg.clone(), &interrupted);
+        let client = async {
+            let stream = loop {
+                match tokio::net::UnixStream::connect(socket).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            assert_eq!(
+                std::fs::metadata(socket)?.permissions().mode() & 0o777,
+                0o600
+            );
+            // Private directory the socket was bound in is gone
+            let prefix = format!("{}.", socket.file_name().unwrap().to_str().unwrap());
+            for entry in std::fs::read_dir(socket.parent().unwrap())? {
+                assert!(!entry?.file_name().to_str().unwrap().starts_with(&prefix));
+            }
+
+            // Socket of running serve is never taken over
+            let second = Interrupted::default();
+            assert!(
+                cmd_serve(matches.subcommand().1.unwrap(), cfg.clone(), &second)
+                    .await
+                    .is_err()
+            );
+            assert!(socket.exists());
+            let (reader, mut writer) = stream.into_split();
+            writer
+                .write_all(
+                    b"user list\n\
+                    user add oscar other\n\
+                    authenticate-cookie a GET q r / host on repo page / /?p=login\n\
+                    user list --count\n\
+                    count\n",
+                )
+                .await?;
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            let mut responses = Vec::new();
+            let mut current = String::new();
+            while responses.len() < 5 {
+                let line = lines.next_line().await?.unwrap();
+                current.push_str(&line);
+                current.push('\n');
+                if line.eq("OK") || line.starts_with("ERR ") {
+                    responses.push(std::mem::take(&mut current));
+                }
+            }
+            interrupted.set();
+            anyhow::Result::<Vec<String>>::Ok(responses)
+        };
+        let (served, responses) = tokio::join!(serve, client);
+        served?;
+        let responses = responses?;
+
+        assert_eq!(responses[0], "There is 1 user in database\noscar\nOK\n");
+        assert_eq!(responses[1], "ERR 3 User already exists!\n");
+        assert_eq!(
+            responses[2],
+            "ERR 1 auth-filter subcommand authenticate-cookie is not served\n"
+        );
+        assert_eq!(responses[3], "1\nOK\n");
+        assert_eq!(responses[4], "1\nOK\n");
+        assert!(!socket.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() {
+        let tmpdir = tempdir::TempDir::new("serve").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_serve(cfg, &tmpdir.path().join("admin.sock"))).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_login_failure_delay(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "mallory", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let start = std::time::Instant::now();
+        let out = post_with_new_token("mallory", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+        let out = post_with_new_token("mallory", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_failure_delay() {
+        let tmpdir = tempdir::TempDir::new("delay").unwrap();
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-login-failure-delay-ms=300",
+        );
+        assert_eq!(
+            cfg.get_login_failure_delay().unwrap(),
+            std::time::Duration::from_millis(300)
+        );
+
+        block_on(async_test_login_failure_delay(cfg)).unwrap();
+
+        // Delay must leave room within operation timeout
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-login-failure-delay-ms=5000\ncgit-simple-auth-operation-timeout=5",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg.get_login_failure_delay().is_err());
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_schema_version(cfg: Config) -> anyhow::Result<()> {
+        // Missing database is reported as uninitialized
+        assert!(cmd_schema_version(cfg.clone()).await?);
+
+        drop(std::fs::File::create(cfg.get_database_location())?);
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        #[allow(deprecated)]
+        sqlx::query(crate::database::previous::CREATE_TABLES)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        assert!(cmd_schema_version(cfg.clone()).await?);
+
+        std::fs::remove_file(cfg.get_database_location())?;
+        cmd_init(cfg.clone()).await?;
+        assert!(!cmd_schema_version(cfg).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_version() {
+        run_with_temp_database("schema", async_test_schema_version);
+    }
+
+    async fn async_test_inspect_hash(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for user in ["xavier", "yvonne"] {
+            let matches = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        }
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"UPDATE "accounts" SET "password" = ? WHERE "user" = 'xavier'"#)
+            .bind(
+                "$argon2i$v=19$m=8192,t=4,p=2$c29tZXNhbHRzb21lc2FsdA\
+                $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            )
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let inspect = |user: &'static str| {
+            let cfg = cfg.clone();
+            async move {
+                let matches = get_arg_matches(Some(vec!["a", "user", "inspect", user]));
+                let matches = match matches.subcommand() {
+                    ("user", Some(matches)) => matches.subcommand().1.unwrap().clone(),
+                    _ => unreachable!(),
+                };
+                let mut output = Vec::new();
+                cmd_inspect_hash(&matches, cfg, &mut output).await?;
+                anyhow::Result::<String>::Ok(String::from_utf8(output)?)
+            }
+        };
+        assert_eq!(
+            inspect("xavier").await?,
+            "algorithm: argon2i\nversion: 19\nm_cost: 8192\nt_cost: 4\np_cost: 2\n\
+            output length: 32\nsalt length: 16\noutdated: yes\n"
+        );
+        let output = inspect("yvonne").await?;
+        assert!(output.starts_with("algorithm: argon2id\nversion: 19\n"));
+        assert!(output.ends_with("output length: 32\nsalt length: 16\noutdated: no\n"));
+        assert!(!output.contains('$'));
+
+        let err = inspect("nobody").await.unwrap_err();
+        assert_eq!(AuthError::exit_code_of(&err), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect_hash() {
+        run_with_temp_database("inspect_hash", async_test_inspect_hash);
+    }
+
+    async fn async_test_future_schema(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"UPDATE "auth_meta" SET "value" = '99' WHERE "key" = 'version'"#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let is_too_new = |ret: anyhow::Result<()>| {
+            let err = ret.unwrap_err();
+            assert_eq!(AuthError::exit_code_of(&err), 5);
+            matches!(
+                err.downcast_ref::<AuthError>(),
+                Some(AuthError::SchemaTooNew(version)) if version.eq("99")
+            )
+        };
+        assert!(is_too_new(cfg.connect_database().await.map(|_| ())));
+        assert!(is_too_new(cfg.connect_accounts_replica().await.map(|_| ())));
+        assert!(is_too_new(
+            cmd_list_user(cfg.clone(), false, false, &mut Vec::new()).await
+        ));
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "peggy", "hunter2"]));
+        assert!(is_too_new(
+            cmd_add_user(add_user_matches(&matches), cfg.clone()).await
+        ));
+        assert!(is_too_new(
+            cmd_upgrade_database(cfg.clone(), &Interrupted::default()).await
+        ));
+        assert!(!cfg.get_copied_database_location().exists());
+        assert!(cmd_schema_version(cfg).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_future_schema() {
+        run_with_temp_database("future_schema", async_test_future_schema);
+    }
+
+    async fn async_test_missing_schema_version(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"DELETE FROM "auth_meta" WHERE "key" = 'version'"#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let is_inconsistent = |err: anyhow::Error, hint: &str| {
+            assert_eq!(AuthError::exit_code_of(&err), 5);
+            assert!(err.to_string().contains(hint), "{}", err);
+            matches!(
+                err.downcast_ref::<AuthError>(),
+                Some(AuthError::InconsistentDatabase(_))
+            )
+        };
+        let err = cmd_upgrade_database(cfg.clone(), &Interrupted::default())
+            .await
+            .unwrap_err();
+        assert!(is_inconsistent(err, "`database reset`"));
+        assert!(!cfg.get_copied_database_location().exists());
+        let err = cmd_schema_version(cfg.clone()).await.unwrap_err();
+        assert!(is_inconsistent(err, "`database reset`"));
+        let err = cmd_init(cfg.clone()).await.unwrap_err();
+        assert!(is_inconsistent(err, "`database reset`"));
+
+        let mut output = Vec::new();
+        assert!(!cmd_healthcheck(cfg.clone(), &mut output).await?);
+        assert!(String::from_utf8(output)?.contains("auth_meta has no schema version"));
+
+        // Database file created by something else, table is missing altogether
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"DROP TABLE "auth_meta""#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        let err = cmd_upgrade_database(cfg.clone(), &Interrupted::default())
+            .await
+            .unwrap_err();
+        assert!(is_inconsistent(err, "`database init`"));
+        assert!(cmd_schema_version(cfg).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_schema_version() {
+        run_with_temp_database("missing_schema_version", async_test_missing_schema_version);
+    }
+
+    async fn async_test_upgrade_database(cfg: Config) -> anyhow::Result<()> {
+        drop(std::fs::File::create(cfg.get_database_location())?);
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        #[allow(deprecated)]
+        sqlx::query(crate::database::v2::CREATE_TABLES)
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(r#"INSERT INTO "accounts" VALUES ('alice', 'hash', 'uid-alice')"#)
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(r#"INSERT INTO "repo" VALUES ('uid-alice', 'test', NULL)"#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        cmd_upgrade_database(cfg.clone(), &Interrupted::default()).await?;
+        assert!(!cmd_schema_version(cfg.clone()).await?);
+
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        let accounts = sqlx::query_as::<_, (String, String, String)>(
+            r#"SELECT "user", "password", "uid" FROM "accounts""#,
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        assert_eq!(
+            accounts,
+            vec![(
+                "alice".to_string(),
+                "hash".to_string(),
+                "uid-alice".to_string()
+            )]
+        );
+        for table in &["repos", "groups", "group_members", "group_repos"] {
+            let rows =
+                sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND name=?"#)
+                    .bind(table)
+                    .fetch_all(&mut conn)
+                    .await?;
+            assert_eq!(rows.len(), 1, "missing table {}", table);
+        }
+        conn.close().await?;
+
+        // Upgrading a current database changes nothing
+        cmd_upgrade_database(cfg.clone(), &Interrupted::default()).await?;
+        assert!(!cmd_schema_version(cfg).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_database() {
+        run_with_temp_database("upgrade", async_test_upgrade_database);
+    }
+
+    async fn async_test_upgrade_failure(cfg: Config) -> anyhow::Result<()> {
+        drop(std::fs::File::create(cfg.get_database_location())?);
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        #[allow(deprecated)]
+        sqlx::query(crate::database::v2::CREATE_TABLES)
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(r#"INSERT INTO "accounts" VALUES ('alice', 'hash', 'uid-alice')"#)
+            .execute(&mut conn)
+            .await?;
+        // Conflicts with table created by v2 to v3 migration
+        sqlx::query(r#"CREATE TABLE "repos" ("repo" TEXT)"#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        assert!(cmd_upgrade_database(cfg.clone(), &Interrupted::default())
+            .await
+            .is_err());
+        assert!(!Path::new(&format!("{}.upgrade", cfg.get_database_location())).exists());
+
+        // Original database is left as it was
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        let (version,) = sqlx::query_as::<_, (String,)>(
+            r#"SELECT "value" FROM "auth_meta" WHERE "key" = 'version' "#,
+        )
+        .fetch_one(&mut conn)
+        .await?;
+        #[allow(deprecated)]
+        let v2 = crate::database::v2::VERSION;
+        assert_eq!(version, v2);
+        let rows = sqlx::query(r#"SELECT * FROM "repo""#)
+            .fetch_all(&mut conn)
+            .await?;
+        assert!(rows.is_empty());
+        let accounts = sqlx::query(r#"SELECT "user", "password", "uid" FROM "accounts""#)
+            .fetch_all(&mut conn)
+            .await?;
+        assert_eq!(accounts.len(), 1);
+        conn.close().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_failure() {
+        run_with_temp_database("upgrade_failure", async_test_upgrade_failure);
+    }
+
+    async fn async_test_upgrade_scratch_dir(cfg: Config) -> anyhow::Result<()> {
+        drop(std::fs::File::create(cfg.get_database_location())?);
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        #[allow(deprecated)]
+        sqlx::query(crate::database::v2::CREATE_TABLES)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        // Copy is made in scratch directory, so upgrade can not start while it is missing,
+        // other commands do not use it
+        let scratch = cfg.get_scratch_dir();
+        assert!(cfg.check_scratch_dir().is_err());
+        cfg.validate()?;
+        assert!(cmd_upgrade_database(cfg.clone(), &Interrupted::default())
+            .await
+            .is_err());
+        assert!(cmd_schema_version(cfg.clone()).await?);
+
+        std::fs::create_dir(&scratch)?;
+        cfg.check_scratch_dir()?;
+        cmd_upgrade_database(cfg.clone(), &Interrupted::default()).await?;
+        assert!(!cmd_schema_version(cfg.clone()).await?);
+        assert_eq!(std::fs::read_dir(&scratch)?.count(), 0);
+        assert!(!Path::new(&format!("{}.upgrade", cfg.get_database_location())).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_scratch_dir() {
+        let tmpdir = tempdir::TempDir::new("upgrade_scratch").unwrap();
+        let mut cfg = temp_database_config(tmpdir.path(), b"");
+        assert_eq!(
+            cfg.get_copied_database_location(),
+            tmpdir.path().join("auth.db.upgrade")
+        );
+        let scratch = tmpdir.path().join("scratch");
+        cfg.set_scratch_dir(scratch.to_str().unwrap());
+        assert_eq!(
+            cfg.get_copied_database_location(),
+            scratch.join("auth.db.upgrade")
+        );
+
+        block_on(async_test_upgrade_scratch_dir(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_upgrade_wal(cfg: Config) -> anyhow::Result<()> {
+        drop(std::fs::File::create(cfg.get_database_location())?);
+        // Stays open during upgrade, so its writes are still in WAL and not checkpointed
+        let mut holder = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        #[allow(deprecated)]
+        sqlx::query(crate::database::v2::CREATE_TABLES)
+            .execute(&mut holder)
+            .await?;
+        sqlx::query(r#"INSERT INTO "accounts" VALUES ('alice', 'hash', 'uid-alice')"#)
+            .execute(&mut holder)
+            .await?;
+        let wal = format!("{}-wal", cfg.get_database_location());
+        assert!(std::fs::metadata(&wal)?.len() > 0);
+
+        cmd_upgrade_database(cfg.clone(), &Interrupted::default()).await?;
+        holder.close().await?;
+
+        assert!(!cmd_schema_version(cfg.clone()).await?);
+        assert_eq!(fetch_users(&cfg).await?, vec!["alice"]);
+        let dir = Path::new(cfg.get_database_location()).parent().unwrap();
+        let mut files = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|name| !name.ends_with("-wal") && !name.ends_with("-shm"));
+        assert_eq!(files, vec!["auth.db"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_wal() {
+        run_with_temp_database("upgrade_wal", async_test_upgrade_wal);
+    }
+
+    async fn async_test_vacuum_database(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        for i in 0..200 {
+            sqlx::query(r#"INSERT INTO "accounts" ("user", "password", "uid") VALUES (?, ?, ?)"#)
+                .bind(format!("bloat{}", i))
+                .bind("x".repeat(4096))
+                .bind(i.to_string())
+                .execute(&mut conn)
+                .await?;
+        }
+        sqlx::query(r#"DELETE FROM "accounts""#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        let before = std::fs::metadata(cfg.get_database_location())?.len();
+
+        let mut output = Vec::new();
+        cmd_vacuum_database(cfg.clone(), &mut output).await?;
+        let after = std::fs::metadata(cfg.get_database_location())?.len();
+        assert!(after < before / 10, "{} -> {}", before, after);
+        assert!(String::from_utf8(output)?.contains(&format!("{} bytes after", after)));
+        let wal = format!("{}-wal", cfg.get_database_location());
+        assert!(std::fs::metadata(wal).map(|x| x.len()).unwrap_or(0) == 0);
+
+        // Database is still usable
+        assert!(!cmd_schema_version(cfg).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vacuum_database() {
+        let tmpdir = tempdir::TempDir::new("vacuum").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_vacuum_database(cfg)).unwrap();
+
+        let matches = get_arg_matches(Some(vec!["a", "database", "vacuum"]));
+        assert!(matches
+            .subcommand_matches("database")
+            .and_then(|x| x.subcommand_matches("vacuum"))
+            .is_some());
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_list_broken_user(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(
+            r#"INSERT INTO "accounts" ("user", "password", "uid") VALUES ('alice', 'hash', 'uid-alice'), (X'FF', 'hash', 'uid-broken'), ('bob', 'hash', 'uid-bob')"#,
+        )
+        .execute(&mut conn)
+        .await?;
+        conn.close().await?;
+
+        let mut output = Vec::new();
+        let ret = cmd_list_user(cfg, false, false, &mut output).await;
+        assert!(ret.is_err());
+        assert_eq!(
+            String::from_utf8(output)?,
+            "There is 3 users in database\nalice\nbob\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_broken_user() {
+        run_with_temp_database("list_user", async_test_list_broken_user);
+    }
+
+    #[test]
+    fn test_missing_database() {
+        let tmpdir = tempdir::TempDir::new("missing_database").unwrap();
+        let mut cfg = Config::generate_test_config();
+        let location = tmpdir.path().join("auth.db");
+        cfg.set_database_location(location.to_str().unwrap());
+
+        let err = block_on(cmd_list_user(cfg, false, false, &mut Vec::new())).unwrap_err();
+        assert!(err.to_string().contains("run `database init` first"));
+        // Failed command must not leave an empty database behind
+        assert!(!location.exists());
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_examples() {
+        let mut cfg = Config::generate_test_config();
+        cfg.set_database_location("/srv/cgit/auth.db");
+        let exe = std::env::current_exe().unwrap();
+        let mut output = Vec::new();
+        cmd_examples(&cfg, &exe, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output
+            .lines()
+            .any(|line| line == format!("auth-filter={}", exe.display())));
+        assert!(output
+            .lines()
+            .any(|line| line == "cgit-simple-auth-database=/srv/cgit/auth.db"));
+        assert!(get_arg_matches(Some(vec!["a", "examples"]))
+            .subcommand_matches("examples")
+            .is_some());
+    }
+
+    async fn async_test_verify_password(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "niaj", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "verify", "niaj"]));
+        let verify = user_matches(&matches);
+        assert!(cmd_verify_password(verify, cfg.clone(), &mut "hunter2\n".as_bytes()).await?);
+        assert!(cmd_verify_password(verify, cfg.clone(), &mut "hunter2".as_bytes()).await?);
+        assert!(!cmd_verify_password(verify, cfg.clone(), &mut "wrong\n".as_bytes()).await?);
+        assert!(!cmd_verify_password(verify, cfg.clone(), &mut "".as_bytes()).await?);
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "verify", "nobody"]));
+        let err = cmd_verify_password(user_matches(&matches), cfg, &mut "hunter2".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(AuthError::exit_code_of(&err), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_password() {
+        run_with_temp_database("verify", async_test_verify_password);
+    }
+
+    async fn async_test_random_password(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let verify = |password: String| {
+            let cfg = cfg.clone();
+            async move {
+                let matches = get_arg_matches(Some(vec!["a", "user", "verify", "quinn"]));
+                cmd_verify_password(user_matches(&matches), cfg, &mut password.as_bytes()).await
+            }
+        };
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "quinn", "--random", "24"]));
+        let mut output = Vec::new();
+        let mut messages = Vec::new();
+        add_user(
+            add_user_matches(&matches),
+            cfg.clone(),
+            &mut output,
+            &mut messages,
+        )
+        .await?;
+        let first = String::from_utf8(output)?;
+        assert_eq!(first.trim_end().len(), 24);
+        assert!(first.trim_end().chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(String::from_utf8(messages)?.contains("shown only once"));
+        // Only hash is stored
+        assert_ne!(read_password_hash(&cfg, "quinn").await?, first.trim_end());
+        assert!(verify(first.clone()).await?);
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "passwd", "quinn", "--random"]));
+        let mut output = Vec::new();
+        change_password(user_matches(&matches), cfg.clone(), None, &mut output).await?;
+        let second = String::from_utf8(output)?;
+        assert_eq!(second.trim_end().len(), 20);
+        assert!(verify(second).await?);
+        assert!(!verify(first).await?);
+
+        let matches = get_arg_matches(Some(vec!["a", "user", "passwd", "quinn", "--random", "4"]));
+        assert!(
+            change_password(user_matches(&matches), cfg.clone(), None, &mut Vec::new())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_password() {
+        let tmpdir = tempdir::TempDir::new("random_password").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        let password = crate::datastructures::random_password(100);
+        assert_eq!(password.len(), 100);
+        assert_ne!(password, crate::datastructures::random_password(100));
+
+        block_on(async_test_random_password(cfg)).unwrap();
+
+        // Random password can not be combined with given password or hash
+        for args in [
+            vec!["a", "user", "add", "quinn", "hunter2", "--random"],
+            vec!["a", "user", "add", "quinn", "--hash", "--random"],
+            vec!["a", "user", "passwd", "quinn"],
+        ] {
+            assert!(build_app().get_matches_from_safe(args).is_err());
+        }
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_add_user_output(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "olivia", "hunter2"]));
+        let mut messages = Vec::new();
+        add_user(
+            add_user_matches(&matches),
+            cfg.clone(),
+            &mut std::io::sink(),
+            &mut messages,
+        )
+        .await?;
+        let messages = String::from_utf8(messages)?;
+        assert!(messages.starts_with("Insert olivia ("));
+        assert!(messages.ends_with(") to database\n"));
+
+        // Failed insert reports error only, no status message is written
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "peggy", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        let mut messages = Vec::new();
+        let err = add_user(
+            add_user_matches(&matches),
+            cfg,
+            &mut std::io::sink(),
+            &mut messages,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(AuthError::exit_code_of(&err), 3);
+        assert!(messages.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_user_output() {
+        run_with_temp_database("add_output", async_test_add_user_output);
+    }
+
+    async fn async_test_accounts_replica(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "quentin", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        // Snapshot primary into replica, later writes only reach primary
+        let replica = cfg.get_accounts_read_path().to_string();
+        let pool = cfg.connect_database().await?;
+        sqlx::query("VACUUM INTO ?")
+            .bind(&replica)
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+        // Replica is opened read-only, it has to be in WAL mode already
+        sqlx::SqliteConnection::connect(&replica)
+            .await?
+            .close()
+            .await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "rupert", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let wrap_cfg = WrapConfigure::from(cfg.clone());
+        let data = FormData::from("username=quentin&password=hunter2".to_string());
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        let data = FormData::from("username=rupert&password=hunter2".to_string());
+        assert!(!verify_login(&wrap_cfg, &data).await?);
+
+        let pool = cfg.connect_database().await?;
+        let (count,) = sqlx::query_as::<_, (i64,)>(r#"SELECT COUNT(*) FROM "accounts""#)
+            .fetch_one(&pool)
+            .await?;
+        pool.close().await;
+        assert_eq!(count, 2);
+
+        // Without replica reads go to primary
+        let mut primary = cfg;
+        primary.set_accounts_read_path(None);
+        let wrap_cfg = WrapConfigure::from(primary);
+        assert!(verify_login(&wrap_cfg, &data).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_replica() {
+        let tmpdir = tempdir::TempDir::new("replica").unwrap();
+        let mut cfg = temp_database_config(tmpdir.path(), b"");
+        cfg.set_accounts_read_path(tmpdir.path().join("replica.db").to_str());
+
+        block_on(async_test_accounts_replica(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_metrics(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "sybil", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("sybil", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        let out = post_with_new_token("sybil", "hunter2", &cfg, &mut store).await?;
+        let cookie = get_set_cookie(&out);
+
+        for cookie in &[cookie.as_str(), "cgit_auth=forged"] {
+            let args = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                cookie,
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "",
+                "",
+                "",
+                "/",
+                "/?p=login",
+            ]));
+            authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?;
+        }
+
+        let mut output = Vec::new();
+        write_metrics(&mut store, &mut output).await?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "# HELP cgit_simple_auth_logins_total Login attempts by outcome.\n\
+            # TYPE cgit_simple_auth_logins_total counter\n\
+            cgit_simple_auth_logins_total{outcome=\"success\"} 1\n\
+            cgit_simple_auth_logins_total{outcome=\"failure\"} 1\n\
+            cgit_simple_auth_logins_total{outcome=\"locked_out\"} 0\n\
+            cgit_simple_auth_logins_total{outcome=\"rejected\"} 0\n\
+            cgit_simple_auth_logins_total{outcome=\"error\"} 0\n\
+            # HELP cgit_simple_auth_cookie_validations_total Session cookie validations by outcome.\n\
+            # TYPE cgit_simple_auth_cookie_validations_total counter\n\
+            cgit_simple_auth_cookie_validations_total{outcome=\"valid\"} 1\n\
+            cgit_simple_auth_cookie_validations_total{outcome=\"invalid\"} 1\n"
+        );
+
+        // Sqlite backend keeps counters in authentication database
+        let mut store = SqliteSessionStore::new(cfg.connect_database().await?).await?;
+        store.incr_counter("cgit_metrics_login_failure").await?;
+        store.incr_counter("cgit_metrics_login_failure").await?;
+        assert_eq!(store.get_counter("cgit_metrics_login_failure").await?, 2);
+        assert_eq!(store.get_counter("cgit_metrics_login_success").await?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() {
+        run_with_temp_database("metrics", async_test_metrics);
+    }
+
+    #[test]
+    fn test_cookie_ttl_range() {
+        assert_eq!(parse_cookie_ttl("600"), 600);
+        assert_eq!(parse_cookie_ttl("60"), 60);
+        assert_eq!(parse_cookie_ttl("2592000"), 2_592_000);
+        // Below and above range are clamped to nearest bound
+        assert_eq!(parse_cookie_ttl("0"), *COOKIE_TTL_RANGE.start());
+        assert_eq!(parse_cookie_ttl("59"), *COOKIE_TTL_RANGE.start());
+        assert_eq!(parse_cookie_ttl("2592001"), *COOKIE_TTL_RANGE.end());
+        assert_eq!(
+            parse_cookie_ttl("18446744073709551615"),
+            *COOKIE_TTL_RANGE.end()
+        );
+        // Negative or garbage value falls back to default
+        assert_eq!(parse_cookie_ttl("-1"), 1200);
+        assert_eq!(parse_cookie_ttl("ten"), 1200);
+
+        let tmpdir = tempdir::TempDir::new("cookie_ttl").unwrap();
+        write_to_specify_file(&tmpdir.path().join("CFG"), b"cgit-simple-auth-cookie-ttl=5")
+            .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert_eq!(cfg.cookie_ttl, 60);
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_runtime_flavor() {
+        for (args, worker_threads) in [
+            (vec!["a", "examples"], None),
+            (vec!["a", "--worker-threads", "2", "examples"], Some(2)),
+        ] {
+            let matches = get_arg_matches(Some(args));
+            assert_eq!(get_worker_threads(&matches).unwrap(), worker_threads);
+            let ret = build_runtime(worker_threads)
+                .unwrap()
+                .block_on(async_main(matches, Config::generate_test_config()))
+                .unwrap();
+            assert_eq!(ret, 0);
+        }
+
+        let matches = get_arg_matches(Some(vec!["a", "--worker-threads", "0", "examples"]));
+        assert!(get_worker_threads(&matches).is_err());
+        // Filter calls stay on current thread
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "--worker-threads",
+            "2",
+            "body",
+            "",
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "on",
+            "",
+            "",
+            "",
+            "",
+        ]));
+        assert_eq!(get_worker_threads(&matches).unwrap(), None);
+    }
+
+    #[test]
+    fn test_buildinfo() {
+        assert!(crate::cli::VERSION.starts_with(env!("CARGO_PKG_VERSION")));
+        let mut version = Vec::new();
+        crate::cli::build_app().write_version(&mut version).unwrap();
+        assert!(String::from_utf8(version)
+            .unwrap()
+            .contains(env!("CARGO_PKG_VERSION")));
+
+        let mut output = Vec::new();
+        crate::cli::cmd_buildinfo(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(&format!("version: {}", env!("CARGO_PKG_VERSION"))));
+        assert!(output.contains(&format!("commit: {}", env!("GIT_COMMIT"))));
+    }
+
+    #[test]
+    fn test_completions() {
+        for shell in &clap::Shell::variants() {
+            let mut output = Vec::new();
+            crate::cli::cmd_completions(shell, &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.contains("killsessions"), "{}", shell);
+        }
+        assert!(crate::cli::cmd_completions("tcsh", &mut Vec::new()).is_err());
+        assert!(get_arg_matches(Some(vec!["a", "completions", "zsh"]))
+            .subcommand_matches("completions")
+            .is_some());
+    }
+
+    #[test]
+    fn test_env_overlay() {
+        let tmpdir = tempdir::TempDir::new("env_overlay").unwrap();
+        let path = tmpdir.path().join("CFG");
+        write_to_specify_file(
+            &path,
+            b"cgit-simple-auth-redis-url=redis://127.0.0.1/1\ncgit-simple-auth-cookie-ttl=600",
+        )
+        .unwrap();
+        let env = vec![
+            (
+                "CGIT_AUTH_REDIS_URL".to_string(),
+                "redis://:secret@127.0.0.1/2".to_string(),
+            ),
+            ("CGIT_REPO_URL".to_string(), "ignored".to_string()),
+            // Access control is not taken from environment
+            ("CGIT_AUTH_COOKIE_TTL".to_string(), "60".to_string()),
+            ("CGIT_AUTH_PROTECT".to_string(), "none".to_string()),
+            ("CGIT_AUTH_BYPASS_ROOT".to_string(), "true".to_string()),
+            (
+                "CGIT_AUTH_DATABASE".to_string(),
+                "/tmp/other.db".to_string(),
+            ),
+        ];
+        let cfg = Config::load_with_env(&path, env, None);
+        // Environment wins over file
+        let info = cfg.get_redis_connection_info().unwrap();
+        assert_eq!(info.db, 2);
+        assert_eq!(info.passwd.as_deref(), Some("secret"));
+        // Other options keep coming from file
+        assert_eq!(cfg.cookie_ttl, 600);
+        assert!(cfg.query_is_all_protected());
+        assert!(!cfg.is_bypass_path("", "/"));
+        assert_eq!(cfg.get_database_location(), "/etc/cgit/auth.db");
+
+        let cfg = Config::load_with_env(&path, Vec::new(), None);
+        assert_eq!(cfg.get_redis_connection_info().unwrap().db, 1);
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        let tmpdir = tempdir::TempDir::new("config_profiles").unwrap();
+        let path = tmpdir.path().join("CFG");
+        write_to_specify_file(
+            &path,
+            b"cgit-simple-auth-database=/var/lib/cgit/auth.db\n\
+            cgit-simple-auth-redis-url=redis://127.0.0.1/1\n\
+            cgit-simple-auth-cookie-ttl=600\n\
+            cgit-simple-auth-profile.staging.database=/var/lib/cgit/staging.db\n\
+            cgit-simple-auth-profile.staging.redis-url=redis://127.0.0.1/2\n\
+            cgit-simple-auth-profile.staging.cookie-ttl=1200 # short\n\
+            cgit-simple-auth-profile.production.cookie-ttl=7200",
+        )
+        .unwrap();
+        let env = |profile: &str| {
+            vec![(
+                format!("{}PROFILE", crate::datastructures::ENV_PREFIX),
+                profile.to_string(),
+            )]
+        };
+
+        let staging = Config::load_with_env(&path, Vec::new(), Some("staging"));
+        assert_eq!(staging.get_profile(), Some("staging"));
+        assert_eq!(staging.get_database_location(), "/var/lib/cgit/staging.db");
+        assert_eq!(staging.get_redis_connection_info().unwrap().db, 2);
+        assert_eq!(staging.cookie_ttl, 1200);
+
+        // Options missing from profile fall back to plain ones
+        let production = Config::load_with_env(&path, Vec::new(), Some("production"));
+        assert_eq!(production.get_database_location(), "/var/lib/cgit/auth.db");
+        assert_eq!(production.get_redis_connection_info().unwrap().db, 1);
+        assert_eq!(production.cookie_ttl, 7200);
+
+        let plain = Config::load_with_env(&path, Vec::new(), None);
+        assert_eq!(plain.get_profile(), None);
+        assert_eq!(plain.get_database_location(), "/var/lib/cgit/auth.db");
+        assert_eq!(plain.cookie_ttl, 600);
+
+        // Environment selects profile when flag is absent, and still overrides profile options
+        let cfg = Config::load_with_env(&path, env("staging"), None);
+        assert_eq!(cfg.get_database_location(), "/var/lib/cgit/staging.db");
+        let cfg = Config::load_with_env(&path, env("staging"), Some("production"));
+        assert_eq!(cfg.cookie_ttl, 7200);
+        let mut vars = env("staging");
+        vars.push((
+            "CGIT_AUTH_REDIS_URL".to_string(),
+            "redis://127.0.0.1/3".to_string(),
+        ));
+        let cfg = Config::load_with_env(&path, vars, None);
+        assert_eq!(cfg.get_redis_connection_info().unwrap().db, 3);
+        assert_eq!(cfg.get_database_location(), "/var/lib/cgit/staging.db");
+        assert_eq!(cfg.cookie_ttl, 1200);
+
+        let typo = Config::load_with_env(&path, Vec::new(), Some("stagign"));
+        assert_eq!(typo.get_database_location(), "/var/lib/cgit/auth.db");
+        assert!(typo
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("profile stagign has no options in cgitrc"));
+        assert!(
+            get_arg_matches(Some(vec!["a", "--profile", "staging", "user", "list"]))
+                .subcommand_matches("user")
+                .is_some()
+        );
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 1 and 6
+        let hex = |x: Vec<u8>| x.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(
+            hex(hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            hex(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    async fn async_test_stateless_sessions(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "ursula", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("ursula", "hunter2", &cfg, &mut store).await?;
+        let cookie = get_set_cookie(&out);
+        assert_eq!(cookie.matches('.').count(), 2);
+        assert_eq!(
+            validate_cookie(&cfg, &cookie, "", &mut store, &RequestPools::default()).await?,
+            Some("ursula".to_string())
+        );
+
+        // Session store was flushed, signature alone keeps user logged in
+        let mut flushed = MemorySessionStore::default();
+        assert_eq!(
+            validate_cookie(&cfg, &cookie, "", &mut flushed, &RequestPools::default()).await?,
+            Some("ursula".to_string())
+        );
+
+        // Changing any signed part or the signature is rejected
+        let (value, signature) = cookie.rsplit_once('.').unwrap();
+        let (value, expires_at) = value.rsplit_once('.').unwrap();
+        let tampered = [
+            format!(
+                "{}.{}.{}",
+                value,
+                expires_at.parse::<u64>()? + 3600,
+                signature
+            ),
+            format!("{}.{}.{}", value, expires_at, base64::encode([0u8; 32])),
+            format!("{}.{}.{}", value, expires_at, &signature[1..]),
+            format!("{}.{}", value, expires_at),
+            value.to_string(),
+            format!(
+                "cgit_auth={}.{}.{}",
+                base64::encode(
+                    String::from_utf8(base64::decode(value.trim_start_matches("cgit_auth="))?)?
+                        .replace("ursula", "mallory")
+                ),
+                expires_at,
+                signature
+            ),
+        ];
+        for cookie in &tampered {
+            assert!(
+                validate_cookie(&cfg, cookie, "", &mut flushed, &RequestPools::default())
+                    .await?
+                    .is_none()
+            );
+        }
+
+        // Expired signature is rejected
+        let secret = cfg.get_cookie_secret()?.unwrap();
+        let expired = Cookie::load_from_request(&cookie)?
+            .unwrap()
+            .to_signed_string(&secret, crate::datastructures::get_current_timestamp() - 1);
+        let expired = format!("cgit_auth={}", expired);
+        assert!(
+            validate_cookie(&cfg, &expired, "", &mut flushed, &RequestPools::default())
+                .await?
+                .is_none()
+        );
+
+        // Revocation is still honoured while session store keeps the marker
+        kill_user_sessions(&mut store, "ursula").await?;
+        assert!(
+            validate_cookie(&cfg, &cookie, "", &mut store, &RequestPools::default())
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stateless_sessions() {
+        let tmpdir = tempdir::TempDir::new("stateless").unwrap();
+        let secret_file = tmpdir.path().join("cookie.secret");
+        let mut cfg = temp_database_config(tmpdir.path(), b"");
+        cfg.set_stateless_sessions(secret_file.to_str().unwrap());
+        // Secret is required once stateless sessions are enabled
+        assert!(cfg.get_cookie_secret().is_err());
+        write_to_specify_file(&secret_file, b"cookie secret\n").unwrap();
+        assert_eq!(cfg.get_cookie_secret().unwrap().unwrap(), b"cookie secret");
+
+        block_on(async_test_stateless_sessions(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_log_level() {
+        use log::LevelFilter;
+        assert_eq!(get_log_level(None, 0, 0), LevelFilter::Info);
+        assert_eq!(get_log_level(None, 1, 0), LevelFilter::Debug);
+        assert_eq!(get_log_level(None, 5, 0), LevelFilter::Trace);
+        assert_eq!(get_log_level(None, 0, 1), LevelFilter::Warn);
+        assert_eq!(get_log_level(None, 0, 9), LevelFilter::Off);
+        assert_eq!(get_log_level(Some("trace"), 0, 0), LevelFilter::Trace);
+        assert_eq!(get_log_level(Some("WARN"), 1, 0), LevelFilter::Info);
+        assert_eq!(get_log_level(Some("error"), 0, 1), LevelFilter::Off);
+        assert_eq!(get_log_level(Some("nonsense"), 0, 0), LevelFilter::Info);
+
+        let matches = get_arg_matches(Some(vec!["a", "-vv", "user", "list"]));
+        assert_eq!(matches.occurrences_of("verbose"), 2);
+        let matches = get_arg_matches(Some(vec!["a", "-q", "user", "list"]));
+        assert_eq!(matches.occurrences_of("quiet"), 1);
+    }
+
+    #[test]
+    fn test_log_target() {
+        assert_eq!(LogTarget::from("STDERR"), LogTarget::Stderr);
+        assert_eq!(LogTarget::from("syslog"), LogTarget::Syslog);
+        assert_eq!(LogTarget::from(""), LogTarget::File);
+
+        let tmpdir = tempdir::TempDir::new("log_target").unwrap();
+        let log_file = tmpdir.path().join("auth.log");
+        for target in &[LogTarget::File, LogTarget::Stderr, LogTarget::Syslog] {
+            build_log_config(*target, log::LevelFilter::Info, log_file.to_str().unwrap()).unwrap();
+        }
+        assert!(log_file.exists());
+
+        #[cfg(unix)]
+        {
+            use log4rs::append::Append;
+            let socket_path = tmpdir.path().join("log.sock");
+            let server = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+            let appender = crate::logger::SyslogAppender::new(&socket_path).unwrap();
+            appender
+                .append(
+                    &log::Record::builder()
+                        .args(format_args!("login failed"))
+                        .level(log::Level::Warn)
+                        .build(),
+                )
+                .unwrap();
+            let mut buf = [0u8; 256];
+            let size = server.recv(&mut buf).unwrap();
+            let message = String::from_utf8_lossy(&buf[..size]).to_string();
+            assert!(message.starts_with("<84>cgit-simple-authentication["));
+            assert!(message.ends_with("]: login failed"));
+        }
+        tmpdir.close().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_log_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmpdir = tempdir::TempDir::new("log_file").unwrap();
+        let log_file = tmpdir.path().join("cgit").join("auth.log");
+        build_log_config(
+            LogTarget::File,
+            log::LevelFilter::Info,
+            log_file.to_str().unwrap(),
+        )
+        .unwrap();
+        let mode = std::fs::metadata(&log_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_format_arguments() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        let cookie = args(&["auth", "authenticate-cookie", "secret", "GET"]);
+        assert_eq!(
+            format_arguments(&cookie, false),
+            "[0]=auth [1]=authenticate-cookie [2]=<redacted> [3]=GET"
+        );
+        assert_eq!(
+            format_arguments(&cookie, true),
+            "[0]=auth [1]=authenticate-cookie [2]=secret [3]=GET"
+        );
+        assert_eq!(
+            format_arguments(
+                &args(&["auth", "-v", "user", "add", "alice", "hunter2"]),
+                false
+            ),
+            "[0]=auth [1]=-v [2]=user [3]=add [4]=alice [5]=<redacted>"
+        );
+        assert_eq!(
+            format_arguments(&args(&["auth", "user", "list"]), false),
+            "[0]=auth [1]=user [2]=list"
+        );
+    }
+
+    fn init_matches<'a>(args: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+        match args.subcommand() {
+            ("database", Some(matches)) => match matches.subcommand() {
+                ("init", Some(matches)) => matches,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    async fn async_test_init_admin(cfg: Config) -> anyhow::Result<()> {
+        let admin = get_arg_matches(Some(vec![
+            "a",
+            "database",
+            "init",
+            "--admin-user",
+            "admin",
+            "--admin-password",
+            "hunter2",
+        ]));
+        cmd_init(cfg.clone()).await?;
+        cmd_init_admin(init_matches(&admin), cfg.clone()).await?;
+
+        // Second run keeps existing account
+        let other = get_arg_matches(Some(vec![
+            "a",
+            "database",
+            "init",
+            "--admin-user",
+            "root",
+            "--admin-password",
+            "hunter3",
+        ]));
+        cmd_init(cfg.clone()).await?;
+        cmd_init_admin(init_matches(&other), cfg.clone()).await?;
+
+        let pool = cfg.connect_read_only_database().await?;
+        let accounts = sqlx::query_as::<_, (String,)>(r#"SELECT "user" FROM "accounts""#)
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(accounts, vec![("admin".to_string(),)]);
+        pool.close().await;
+
+        assert!(
+            verify_login(
+                &WrapConfigure::from(cfg),
+                &FormData::from("username=admin&password=hunter2".to_string())
+            )
+            .await?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_admin() {
+        run_with_temp_database("init_admin", async_test_init_admin);
+    }
+
+    async fn async_test_logout(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "judy", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("judy", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let cookie = get_set_cookie(&out);
+        let key = format!(
+            "cgit_auth_{}",
+            Cookie::load_from_request(&cookie)?.unwrap().get_key()
+        );
+        assert!(store.get_session(&key).await?.is_some());
+        // Issued cookie only carries Max-Age
+        assert!(!out.contains("Expires="));
+
+        // Logout posted from another site carries no valid token
+        for input in ["action=logout", "action=logout&csrf_token=forged"] {
+            let out = post_with_cookie(input.as_bytes(), &cookie, cfg.clone(), &mut store).await?;
+            assert!(out.starts_with("Status: 403"));
+            assert!(!out.contains("Set-Cookie"));
+            assert!(store.get_session(&key).await?.is_some());
+        }
+
+        let input = format!(
+            "action=logout&csrf_token={}",
+            generate_csrf_token(&mut store).await?
+        );
+        let out = post_with_cookie(input.as_bytes(), &cookie, cfg.clone(), &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let expired = out
+            .lines()
+            .find(|line| line.starts_with("Set-Cookie"))
+            .unwrap();
+        assert!(expired.starts_with("Set-Cookie: cgit_auth=;"));
+        assert!(expired.contains("; Max-Age=0;"));
+        assert_eq!(
+            expired.contains("; Expires=Thu, 01 Jan 1970 00:00:00 GMT;"),
+            cfg.is_cookie_legacy_expires()
+        );
+        assert!(store.get_session(&key).await?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_logout() {
+        for legacy in &[false, true] {
+            let tmpdir = tempdir::TempDir::new("logout").unwrap();
+            let cfg = temp_database_config(
+                tmpdir.path(),
+                format!("cgit-simple-auth-cookie-legacy-expires={}", legacy).as_bytes(),
+            );
+            assert_eq!(cfg.is_cookie_legacy_expires(), *legacy);
+
+            block_on(async_test_logout(cfg)).unwrap();
+
+            tmpdir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_form_data_decoding() {
+        let data = FormData::from("username=alice&password=p%2Bss%26w%25rd+x%3D".to_string());
+        assert_eq!(data.get_user(), "alice");
+        assert!(data.has_credential_fields());
+        assert!(!data.has_empty_credentials());
+
+        let data = FormData::from("username=alice".to_string());
+        assert!(!data.has_credential_fields());
+
+        let data = FormData::from("username=&password=".to_string());
+        assert!(data.has_credential_fields());
+        assert!(data.has_empty_credentials());
+
+        for body in &["", "&&==", "%zz=%%&password", "username"] {
+            let data = FormData::from(body.to_string());
+            assert!(data.has_empty_credentials());
+        }
+    }
+
+    async fn async_test_custom_form_fields(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "oscar", "hunter2"]));
+        cmd_add_user(add_user_matches(&add), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let body = render_body_with_store(cfg.clone(), &mut store).await?;
+        assert!(body.contains(r#"name="login""#));
+        assert!(body.contains(r#"name="secret""#));
+
+        let input = format!(
+            "login=oscar&secret=hunter2&csrf_token={}",
+            get_csrf_token(&body)
+        );
+        let out = post_login_with_store(input.as_bytes(), cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_form_fields() {
+        let fields = FormFields {
+            user: "login".to_string(),
+            password: "secret".to_string(),
+        };
+        let data = FormData::parse(b"login=alice&secret=hunter2&csrf_token=abc", &fields);
+        assert_eq!(data.get_user(), "alice");
+        assert_eq!(data.get_csrf_token(), "abc");
+        assert!(data.has_credential_fields());
+
+        // Default names are ignored once renamed
+        let data = FormData::parse(b"username=alice&password=hunter2", &fields);
+        assert!(!data.has_credential_fields());
+
+        let tmpdir = tempdir::TempDir::new("form_fields").unwrap();
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-form-user-field=login\n\
+            cgit-simple-auth-form-password-field=secret",
+        );
+        assert_eq!(cfg.get_form_fields().user, "login");
+
+        block_on(async_test_custom_form_fields(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_form_special_password(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "mallory", "p+ss&w%rd x="]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("mallory", "p%2Bss%26w%25rd+x%3D", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+
+        // Raw characters are decoded differently and do not match
+        let out = post_with_new_token("mallory", "p+ss&w%rd x=", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+
+        let input = format!(
+            "username=mallory&csrf_token={}",
+            generate_csrf_token(&mut store).await?
+        );
+        let out = post_login_with_store(input.as_bytes(), cfg.clone(), &mut store).await?;
+        assert!(out.starts_with("Status: 400"));
+        assert!(out.ends_with("\n\nLogin form must post username and password fields\n"));
+
+        let out = post_with_new_token("mallory", "", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_form_special_password() {
+        run_with_temp_database("form", async_test_form_special_password);
+    }
+
+    #[test]
+    fn test_max_post_bytes() {
+        let tmpdir = tempdir::TempDir::new("post_size").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-max-post-bytes=1024",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert_eq!(cfg.get_max_post_bytes(), 1024);
+
+        let matches = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-post",
+            "",
+            "POST",
+            "p=login",
+            "",
+            "/",
+            "git.example.com",
+            "",
+            "",
+            "login",
+            "/?p=login",
+            "/?p=login",
+        ]));
+        // Endless body, reading all of it would never finish
+        let mut output = Vec::new();
+        let mut module = IOModule {
+            reader: std::io::BufReader::new(std::io::repeat(b'a')),
+            writer: &mut output,
+        };
+        let mut store = MemorySessionStore::default();
+        if let ("authenticate-post", Some(matches)) = matches.subcommand() {
+            block_on(module.cmd_authenticate_post(matches, cfg, &mut store, &Default::default()))
+                .unwrap();
+        }
+        let out = String::from_utf8(output).unwrap();
+        assert!(out.starts_with("Status: 413"));
+        assert!(out.ends_with("\n\nLogin form is too large\n"));
+
+        tmpdir.close().unwrap();
+    }
+
+    // Stands for stdin of a request without body, reading it would block forever.
+    struct BlockingInput;
+
+    impl Read for BlockingInput {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            panic!("Request body should not be read")
+        }
+    }
+
+    impl std::io::BufRead for BlockingInput {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            panic!("Request body should not be read")
+        }
+
+        fn consume(&mut self, _amt: usize) {}
+    }
+
+    #[test]
+    fn test_post_method_not_allowed() {
+        let cfg = Config::generate_test_config();
+        for method in ["GET", "HEAD", ""] {
+            let matches = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-post",
+                "",
+                method,
+                "p=login",
+                "",
+                "/",
+                "git.example.com",
+                "",
+                "",
+                "login",
+                "/?p=login",
+                "/?p=login",
+            ]));
+            let mut output = Vec::new();
+            let mut module = IOModule {
+                reader: BlockingInput,
+                writer: &mut output,
+            };
+            let mut store = MemorySessionStore::default();
+            if let ("authenticate-post", Some(matches)) = matches.subcommand() {
+                block_on(module.cmd_authenticate_post(
+                    matches,
+                    cfg.clone(),
+                    &mut store,
+                    &Default::default(),
+                ))
+                .unwrap();
+            }
+            let out = String::from_utf8(output).unwrap();
+            assert!(out.starts_with("Status: 405"));
+            assert!(out.contains("\nAllow: POST\n"));
+            assert!(out.ends_with("\n\nLogin form must be submitted with POST\n"));
+        }
+    }
+
+    // Client that never sends its body.
+    struct PendingInput;
+
+    impl Read for PendingInput {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(3600));
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_input_timeout() {
+        let tmpdir = tempdir::TempDir::new("operation_timeout").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-operation-timeout=1",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert_eq!(
+            cfg.get_operation_timeout(),
+            std::time::Duration::from_secs(1)
+        );
+        assert_eq!(
+            Config::generate_test_config().get_operation_timeout(),
+            std::time::Duration::from_secs(5)
+        );
+
+        let start = std::time::Instant::now();
+        assert!(
+            read_input_with_timeout(PendingInput, 16, cfg.get_operation_timeout())
+                .unwrap()
+                .is_none()
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(3));
+
+        // Reader is cut at limit, so oversized body is still detected by caller
+        let input = read_input_with_timeout(std::io::repeat(b'a'), 16, cfg.get_operation_timeout())
+            .unwrap()
+            .unwrap();
+        assert_eq!(input.len(), 16);
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_unknown_user_timing(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "niaj", "hunter2"]));
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+
+        let wrap_cfg = WrapConfigure::from(cfg);
+        let known = FormData::from("username=niaj&password=wrong".to_string());
+        let unknown = FormData::from("username=nobody&password=wrong".to_string());
+
+        let start = std::time::Instant::now();
+        assert!(!verify_login(&wrap_cfg, &known).await?);
+        let known_elapsed = start.elapsed();
+
+        let start = std::time::Instant::now();
+        assert!(!verify_login(&wrap_cfg, &unknown).await?);
+        let unknown_elapsed = start.elapsed();
+
+        // Both paths do one argon2 verification
+        assert!(
+            unknown_elapsed * 4 >= known_elapsed,
+            "unknown user took {:?}, known user took {:?}",
+            unknown_elapsed,
+            known_elapsed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_user_timing() {
+        run_with_temp_database("timing", async_test_unknown_user_timing);
+    }
+
+    async fn async_test_sqlite_busy_timeout(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        // A cgit filter call reading accounts meanwhile
+        let reader = cfg.connect_read_only_database().await?;
+        let mut read_tx = reader.begin().await?;
+        sqlx::query(r#"SELECT * FROM "accounts""#)
+            .fetch_all(&mut read_tx)
+            .await?;
+
+        let lock_database = || async {
+            let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+            sqlx::query("BEGIN IMMEDIATE").execute(&mut conn).await?;
+            anyhow::Result::<_>::Ok(conn)
+        };
+
+        let mut no_wait = cfg.clone();
+        no_wait.set_sqlite_busy_timeout_ms(0);
+        let conn = lock_database().await?;
+        let matches = get_arg_matches(Some(vec!["a", "user", "add", "alice", "hunter2"]));
+        let err = cmd_add_user(add_user_matches(&matches), no_wait)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("database is locked"));
+        conn.close().await?;
+
+        let mut conn = lock_database().await?;
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            sqlx::query("COMMIT").execute(&mut conn).await.unwrap();
+            conn.close().await.unwrap();
+        });
+        cmd_add_user(add_user_matches(&matches), cfg.clone()).await?;
+        writer.await?;
+
+        read_tx.rollback().await?;
+        reader.close().await;
+        assert_eq!(fetch_users(&cfg).await?, vec!["alice"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_busy_timeout() {
+        let tmpdir = tempdir::TempDir::new("busy_timeout").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+        assert_eq!(cfg.get_sqlite_busy_timeout(), Duration::from_secs(2));
+
+        block_on(async_test_sqlite_busy_timeout(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_healthcheck(cfg: Config, redis_down: Config) -> anyhow::Result<()> {
+        let mut output = Vec::new();
+        assert!(!cmd_healthcheck(cfg.clone(), &mut output).await?);
+        let report = String::from_utf8(output)?;
+        assert!(report.contains("database: FAILED"));
+        assert!(report.contains("schema version: skipped"));
+        assert!(report.contains("redis: ok"));
+
+        cmd_init(cfg.clone()).await?;
+        let mut output = Vec::new();
+        assert!(cmd_healthcheck(cfg.clone(), &mut output).await?);
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!(
+                "database: ok\nschema version: ok ({})\nredis: ok\n",
+                crate::database::current::VERSION
+            )
+        );
+
+        let mut output = Vec::new();
+        assert!(!cmd_healthcheck(redis_down, &mut output).await?);
+        let report = String::from_utf8(output)?;
+        assert!(report.contains("database: ok"));
+        assert!(report.contains("redis: FAILED"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        let tmpdir = tempdir::TempDir::new("health").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        // Nothing listens on port 1
+        let redis_down = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-redis-url=redis://127.0.0.1:1/",
+        );
+
+        block_on(async_test_healthcheck(cfg, redis_down)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("docs", "docs"));
+        assert!(!glob_match("docs", "docs/api"));
+        assert!(glob_match("docs/*", "docs/api"));
+        assert!(!glob_match("docs/*", "doc"));
+        assert!(glob_match("*-mirror", "linux-mirror"));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(!glob_match("a*b*c", "a-c-b"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_public_repos() {
+        let tmpdir = tempdir::TempDir::new("public").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-protect=full\ncgit-simple-auth-public-repos=website docs/*",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+
+        let bypass = |repo: &str| {
+            let args = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                "",
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "",
+                repo,
+                "",
+                "/",
+                "/?p=login",
+            ]));
+            check_bypass(cookie_matches(&args), &cfg)
+        };
+        assert!(bypass("website"));
+        assert!(bypass("docs/api"));
+        assert!(!bypass("private"));
+        assert!(!bypass("docs"));
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_bypass_paths() {
+        let tmpdir = tempdir::TempDir::new("bypass").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-protect=full\n\
+            cgit-simple-auth-bypass-root=true\n\
+            cgit-simple-auth-bypass-paths=/about/ /status/*",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+
+        let bypass = |repo: &str, current_url: &str| {
+            let args = get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                "",
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "",
+                repo,
+                "",
+                current_url,
+                "/?p=login",
+            ]));
+            check_bypass(cookie_matches(&args), &cfg)
+        };
+        // Root views, same as bypass-root
+        assert!(bypass("", "/"));
+        assert!(bypass("", "/?q=search"));
+        // Exact match
+        assert!(bypass("", "/about/"));
+        assert!(!bypass("private", "/about/private"));
+        // Prefix match
+        assert!(bypass("private", "/status/health"));
+        // Not matched
+        assert!(!bypass("private", "/private/tree/"));
+
+        let cfg = Config::generate_test_config();
+        let args = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-cookie",
+            "",
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "",
+            "",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+        assert!(!check_bypass(cookie_matches(&args), &cfg));
+
+        tmpdir.close().unwrap();
+    }
+
+    fn user_matches<'a>(args: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+        match args.subcommand() {
+            ("user", Some(matches)) => matches.subcommand().1.unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn async_test_account_status(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+
+        let expired = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "add",
+            "olivia",
+            "hunter2",
+            "--expires",
+            "1",
+        ]));
+        cmd_add_user(user_matches(&expired), cfg.clone()).await?;
+        let out = post_with_new_token("olivia", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+
+        let valid = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "add",
+            "peggy",
+            "hunter2",
+            "--expires",
+            "1d",
+        ]));
+        cmd_add_user(user_matches(&valid), cfg.clone()).await?;
+        let out = post_with_new_token("peggy", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let cookie = get_set_cookie(&out);
+
+        let args = get_arg_matches(Some(vec![
+            "a",
+            "authenticate-cookie",
+            &cookie,
+            "GET",
+            "",
+            "",
+            "/",
+            "git.example.com",
+            "",
+            "",
+            "",
+            "/",
+            "/?p=login",
+        ]));
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+
+        // Existing session is rejected once user is disabled
+        let disable = get_arg_matches(Some(vec!["a", "user", "disable", "peggy"]));
+        cmd_set_user_disabled(user_matches(&disable), cfg.clone(), true).await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+        let out = post_with_new_token("peggy", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+
+        let enable = get_arg_matches(Some(vec!["a", "user", "enable", "peggy"]));
+        cmd_set_user_disabled(user_matches(&enable), cfg.clone(), false).await?;
+        let out = post_with_new_token("peggy", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+
+        let unknown = get_arg_matches(Some(vec!["a", "user", "disable", "nobody"]));
+        assert!(cmd_set_user_disabled(user_matches(&unknown), cfg, true)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_status() {
+        run_with_temp_database("account_status", async_test_account_status);
+    }
+
+    async fn async_test_kill_sessions(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "trent", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let login = |out: String| {
+            assert!(out.starts_with("Status: 302"));
+            get_set_cookie(&out)
+        };
+        fn check(cookie: &str) -> ArgMatches<'_> {
+            get_arg_matches(Some(vec![
+                "a",
+                "authenticate-cookie",
+                cookie,
+                "GET",
+                "",
+                "",
+                "/",
+                "git.example.com",
+                "",
+                "",
+                "",
+                "/",
+                "/?p=login",
+            ]))
+        }
+
+        let first = login(post_with_new_token("trent", "hunter2", &cfg, &mut store).await?);
+        let second = login(post_with_new_token("trent", "hunter2", &cfg, &mut store).await?);
+        let kill = get_arg_matches(Some(vec!["a", "user", "killsessions", "trent"]));
+        cmd_kill_sessions(user_matches(&kill), &mut store).await?;
+        for cookie in [&first, &second] {
+            let args = check(cookie);
+            assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+        }
+
+        let cookie = login(post_with_new_token("trent", "hunter2", &cfg, &mut store).await?);
+        let passwd = get_arg_matches(Some(vec!["a", "user", "passwd", "trent", "hunter3"]));
+        cmd_change_password(user_matches(&passwd), cfg.clone(), Some(&mut store)).await?;
+        let args = check(&cookie);
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+
+        // Cookie issued before deletion must not outlive the account
+        let cookie = login(post_with_new_token("trent", "hunter3", &cfg, &mut store).await?);
+        let args = check(&cookie);
+        assert!(authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "trent"]));
+        cmd_delete_user(user_matches(&del), cfg.clone(), Some(&mut store)).await?;
+        assert!(!authenticate_cookie(cookie_matches(&args), cfg.clone(), &mut store).await?);
+        assert!(store.get_user_sessions("trent").await?.is_empty());
+        Ok(())
+    }
+
+    async fn async_test_logout_all(
+        cfg: Config,
+        store: &mut dyn SessionStore,
+    ) -> anyhow::Result<()> {
+        for user in &["uma", "victor"] {
+            let add = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        }
+
+        let mut cookies = Vec::new();
+        for user in &["uma", "victor", "uma"] {
+            let out = post_with_new_token(user, "hunter2", &cfg, store).await?;
+            assert!(out.starts_with("Status: 302"));
+            cookies.push(get_set_cookie(&out));
+        }
+        for cookie in &cookies {
+            assert!(
+                validate_cookie(&cfg, cookie, "", store, &RequestPools::default())
+                    .await?
+                    .is_some()
+            );
+        }
+
+        let kill = get_arg_matches(Some(vec!["a", "user", "killsessions", "--all"]));
+        cmd_kill_sessions(user_matches(&kill), store).await?;
+        for cookie in &cookies {
+            assert!(
+                validate_cookie(&cfg, cookie, "", store, &RequestPools::default())
+                    .await?
+                    .is_none()
+            );
+        }
+        assert!(store.get_user_sessions("uma").await?.is_empty());
+        assert_eq!(store.delete_all_sessions().await?, 0);
+
+        // Login still works afterwards, only live sessions are counted.
+        // Cookie issued in the second of global logout is rejected, so wait for the next one.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let out = post_with_new_token("victor", "hunter2", &cfg, store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let cookie = get_set_cookie(&out);
+        assert!(
+            validate_cookie(&cfg, &cookie, "", store, &RequestPools::default())
+                .await?
+                .is_some()
+        );
+        assert_eq!(store.delete_all_sessions().await?, 1);
+        assert!(
+            validate_cookie(&cfg, &cookie, "", store, &RequestPools::default())
+                .await?
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_logout_all() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // Redis part uses its own logical database, other tests keep sessions in default one.
+        // Signed cookies outlive their sessions, so they are checked for each backend too.
+        for (backend, stateless) in [
+            ("memory", false),
+            ("memory", true),
+            ("sqlite", false),
+            ("sqlite", true),
+            ("redis", false),
+            ("redis", true),
+        ] {
+            let tmpdir = tempdir::TempDir::new("logout_all").unwrap();
+            let mut cfg = temp_database_config(tmpdir.path(), format!(
+                    "cgit-simple-auth-session-backend={}\ncgit-simple-auth-redis-url=redis://127.0.0.1/14",
+                    backend
+                )
+                .as_bytes());
+            if stateless {
+                let secret_file = tmpdir.path().join("cookie.secret");
+                write_to_specify_file(&secret_file, b"cookie secret\n").unwrap();
+                cfg.set_stateless_sessions(secret_file.to_str().unwrap());
+            }
+
+            runtime
+                .block_on(async {
+                    cmd_init(cfg.clone()).await?;
+                    if backend == "memory" {
+                        let mut store = MemorySessionStore::default();
+                        return async_test_logout_all(cfg, &mut store).await;
+                    }
+                    let mut store = cfg.connect_session_store().await?;
+                    async_test_logout_all(cfg, store.as_mut()).await
+                })
+                .unwrap();
+
+            tmpdir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_kill_sessions() {
+        run_with_temp_database("kill_sessions", async_test_kill_sessions);
+    }
+
+    async fn async_test_list_sessions(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "victor", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        for _ in 0..2 {
+            let out = post_with_new_token("victor", "hunter2", &cfg, &mut store).await?;
+            assert!(out.starts_with("Status: 302"));
+        }
+
+        let json = get_arg_matches(Some(vec!["a", "user", "sessions", "victor", "--json"]));
+        let mut output = Vec::new();
+        cmd_list_sessions(user_matches(&json), &mut store, &mut output).await?;
+        let sessions: serde_json::Value = serde_json::from_slice(&output)?;
+        let sessions = sessions.as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions
+            .iter()
+            .all(|x| x["ttl"].as_u64().unwrap() > 0 && x["created_at"].as_u64().unwrap() > 0));
+
+        // Expire first session, its index entry is pruned on next listing
+        let key = sessions[0]["key"].as_str().unwrap();
+        store
+            .set_session(&format!("cgit_auth_{}", key), "", 0)
+            .await?;
+        let text = get_arg_matches(Some(vec!["a", "user", "sessions", "victor"]));
+        let mut output = Vec::new();
+        cmd_list_sessions(user_matches(&text), &mut store, &mut output).await?;
+        let output = String::from_utf8(output)?;
+        assert!(output.starts_with("There is 1 session of victor"));
+        assert!(!output.contains(key));
+        assert_eq!(store.get_user_sessions("victor").await?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_sessions() {
+        run_with_temp_database("list_sessions", async_test_list_sessions);
+    }
+
+    async fn async_test_sessions_older_than(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+        let now = crate::datastructures::get_current_timestamp();
+        // Session keys start with login time
+        for (age, name) in [(0, "fresh"), (13 * 3600, "day"), (8 * 86400, "week")] {
+            let key = format!("{}_{}", now - age, name);
+            store
+                .set_session(&format!("cgit_auth_{}", key), "", 600)
+                .await?;
+            store.add_user_session("wanda", &key).await?;
+        }
+
+        for (age, expected) in [
+            ("7d", vec!["week"]),
+            ("12h", vec!["day", "week"]),
+            ("9d", vec![]),
+        ] {
+            let matches = get_arg_matches(Some(vec![
+                "a",
+                "user",
+                "sessions",
+                "wanda",
+                "--json",
+                "--older-than",
+                age,
+            ]));
+            let mut output = Vec::new();
+            cmd_list_sessions(user_matches(&matches), &mut store, &mut output).await?;
+            let sessions: Vec<serde_json::Value> = serde_json::from_slice(&output)?;
+            let mut names = sessions
+                .iter()
+                .map(|x| x["key"].as_str().unwrap().split_once('_').unwrap().1)
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            assert_eq!(names, expected, "{}", age);
+        }
+
+        let kill = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "sessions",
+            "wanda",
+            "--older-than",
+            "12h",
+            "--kill",
+        ]));
+        cmd_list_sessions(user_matches(&kill), &mut store, &mut Vec::new()).await?;
+        let remaining = store.get_user_sessions("wanda").await?;
+        assert_eq!(remaining, vec![format!("{}_fresh", now)]);
+        assert!(store
+            .get_session(&format!("cgit_auth_{}_week", now - 8 * 86400))
+            .await?
+            .is_none());
+
+        let invalid = get_arg_matches(Some(vec![
+            "a",
+            "user",
+            "sessions",
+            "wanda",
+            "--older-than",
+            "soon",
+        ]));
+        assert!(
+            cmd_list_sessions(user_matches(&invalid), &mut store, &mut Vec::new())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_older_than() {
+        let tmpdir = tempdir::TempDir::new("sessions_older_than").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_sessions_older_than(cfg)).unwrap();
+
+        // --kill only together with --older-than
+        assert!(build_app()
+            .get_matches_from_safe(vec!["a", "user", "sessions", "wanda", "--kill"])
+            .is_err());
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_last_login(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+        for user in ["walter", "xavier"] {
+            let add = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        }
+
+        let before = crate::datastructures::get_current_timestamp() as i64;
+        let out = post_with_new_token("walter", "hunter2", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 302"));
+        let out = post_with_new_token("xavier", "wrong", &cfg, &mut store).await?;
+        assert!(out.starts_with("Status: 403"));
+
+        let mut output = Vec::new();
+        cmd_list_user(cfg.clone(), true, false, &mut output).await?;
+        let accounts: serde_json::Value = serde_json::from_slice(&output)?;
+        let accounts = accounts.as_array().unwrap();
+        let walter = accounts.iter().find(|x| x["user"] == "walter").unwrap();
+        assert!(walter["last_login"].as_i64().unwrap() >= before);
+        assert!(walter["last_host"].is_string());
+        let xavier = accounts.iter().find(|x| x["user"] == "xavier").unwrap();
+        assert!(xavier["last_login"].is_null());
+
+        let mut output = Vec::new();
+        cmd_count_user(cfg.clone(), &mut output).await?;
+        assert_eq!(String::from_utf8(output)?, "2\n");
+        // Top-level shortcut for scripts
+        let count = get_arg_matches(Some(vec!["a", "count"]));
+        assert_eq!(async_main(count, cfg.clone()).await?, 0);
+
+        let mut output = Vec::new();
+        cmd_list_user(cfg, false, false, &mut output).await?;
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("walter\tlast login at "));
+        assert!(output.contains("\nxavier\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_login() {
+        run_with_temp_database("last_login", async_test_last_login);
+    }
+
+    async fn async_test_list_user_repos(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for user in ["uma", "ben", "cleo"] {
+            let add = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(add_user_matches(&add), cfg.clone()).await?;
+        }
+        for (repo, user) in [
+            ("list_direct", "uma"),
+            ("list_group", "uma"),
+            ("list_other", "ben"),
+        ] {
+            let args = get_arg_matches(Some(vec!["a", "repo", "add", repo, user]));
+            if let ("repo", Some(matches)) = args.subcommand() {
+                if let ("add", Some(matches)) = matches.subcommand() {
+                    cmd_repo_user_control(matches, cfg.clone(), false).await?;
+                }
+            }
+        }
+        run_group_command(&cfg, &["add", "crew"]).await?;
+        run_group_command(&cfg, &["member", "crew", "uma"]).await?;
+        run_group_command(&cfg, &["grant", "crew", "list_group"]).await?;
+        run_group_command(&cfg, &["grant", "crew", "list_crew"]).await?;
+
+        let mut output = Vec::new();
+        cmd_list_user(cfg.clone(), true, true, &mut output).await?;
+        let accounts: serde_json::Value = serde_json::from_slice(&output)?;
+        let repos = |user: &str| {
+            accounts
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|x| x["user"] == user)
+                .unwrap()["repos"]
+                .clone()
+        };
+        // Repository granted both directly and by group is listed once
+        assert_eq!(
+            repos("uma"),
+            serde_json::json!(["list_crew", "list_direct", "list_group"])
+        );
+        assert_eq!(repos("ben"), serde_json::json!(["list_other"]));
+        assert_eq!(repos("cleo"), serde_json::json!([]));
+
+        let mut output = Vec::new();
+        cmd_list_user(cfg.clone(), false, true, &mut output).await?;
+        let output = String::from_utf8(output)?;
+        assert!(output.contains("\numa\trepos: list_crew list_direct list_group\n"));
+        assert!(output.contains("\nben\trepos: list_other\n"));
+        assert!(output.contains("\ncleo\trepos: (none)\n"));
+
+        // Without the flag repositories are neither queried nor printed
+        let mut output = Vec::new();
+        cmd_list_user(cfg, true, false, &mut output).await?;
+        assert!(!String::from_utf8(output)?.contains("repos"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_user_repos() {
+        run_with_temp_database("list_user_repos", async_test_list_user_repos);
+    }
+
+    async fn async_test_argon2_variant(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "yvonne", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let wrap_cfg = WrapConfigure::from(cfg);
+        let form = FormData::from("username=yvonne&password=hunter2".to_string());
+        assert!(verify_login(&wrap_cfg, &form).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_argon2_variant() {
+        let tmpdir = tempdir::TempDir::new("argon2_variant").unwrap();
+        for (variant, ident) in [("id", "argon2id"), ("i", "argon2i"), ("d", "argon2d")] {
+            let cfg = temp_database_config(
+                tmpdir.path(),
+                format!("cgit-simple-auth-argon2-variant={}", variant).as_bytes(),
+            );
+
+            let hash = FormData::gen_string_argon2_hash(
+                "hunter2",
+                cfg.get_argon2_algorithm().unwrap(),
+                DEFAULT_ARGON2_OUTPUT_LEN,
+                DEFAULT_ARGON2_SALT_LEN,
+                None,
+            )
+            .unwrap();
+            let parsed = PasswordHash::new(&hash).unwrap();
+            assert_eq!(parsed.algorithm.as_str(), ident);
+            assert!(Argon2::default()
+                .verify_password(b"hunter2", &parsed)
+                .is_ok());
+
+            // Accounts hashed with any variant can log in
+            block_on(async_test_argon2_variant(cfg)).unwrap();
+            std::fs::remove_file(tmpdir.path().join("auth.db")).unwrap();
+        }
+
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-argon2-variant=argon3",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg.get_argon2_algorithm().is_err());
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_argon2_output_len(cfg: Config, plain_cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "walter", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "xavier", "hunter2"]));
+        cmd_add_user(user_matches(&add), plain_cfg.clone()).await?;
+
+        let hash = read_password_hash(&cfg, "walter").await?;
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert_eq!(parsed.hash.unwrap().len(), 48);
+        assert!(!is_hash_outdated(&parsed, Default::default(), 48));
+        assert!(is_hash_outdated(
+            &parsed,
+            Default::default(),
+            DEFAULT_ARGON2_OUTPUT_LEN
+        ));
+
+        // Both lengths verify with either configuration
+        for user in ["walter", "xavier"] {
+            let form = FormData::from(format!("username={}&password=hunter2", user));
+            assert!(verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+            assert!(verify_login(&WrapConfigure::from(plain_cfg.clone()), &form).await?);
+            let wrong = FormData::from(format!("username={}&password=hunter3", user));
+            assert!(!verify_login(&WrapConfigure::from(cfg.clone()), &wrong).await?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_argon2_output_len() {
+        let tmpdir = tempdir::TempDir::new("argon2_output_len").unwrap();
+        for (value, expected) in [
+            ("9", None),
+            ("65", None),
+            ("abc", Some(32)),
+            ("10", Some(10)),
+        ] {
+            write_to_specify_file(
+                &tmpdir.path().join("CFG"),
+                format!("cgit-simple-auth-argon2-output-len={}", value).as_bytes(),
+            )
+            .unwrap();
+            let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+            assert_eq!(cfg.get_argon2_output_len().ok(), expected, "{}", value);
+        }
+
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-argon2-output-len=48");
+        let plain_cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_argon2_output_len(cfg, plain_cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_argon2_salt_len(cfg: Config, plain_cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "walter", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "xavier", "hunter2"]));
+        cmd_add_user(user_matches(&add), plain_cfg.clone()).await?;
+
+        for (user, expected) in [("walter", 32), ("xavier", DEFAULT_ARGON2_SALT_LEN)] {
+            let hash = read_password_hash(&cfg, user).await?;
+            let parsed = PasswordHash::new(&hash).unwrap();
+            let mut buffer = [0u8; 64];
+            assert_eq!(
+                parsed.salt.unwrap().b64_decode(&mut buffer).unwrap().len(),
+                expected
+            );
+            // Salt length is read from the stored hash, not the configuration
+            let form = FormData::from(format!("username={}&password=hunter2", user));
+            assert!(verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+            assert!(verify_login(&WrapConfigure::from(plain_cfg.clone()), &form).await?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_argon2_salt_len() {
+        let tmpdir = tempdir::TempDir::new("argon2_salt_len").unwrap();
+        for (value, expected) in [
+            ("7", None),
+            ("49", None),
+            ("abc", Some(16)),
+            ("8", Some(8)),
+            ("48", Some(48)),
+        ] {
+            write_to_specify_file(
+                &tmpdir.path().join("CFG"),
+                format!("cgit-simple-auth-argon2-salt-len={}", value).as_bytes(),
+            )
+            .unwrap();
+            let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+            assert_eq!(cfg.get_argon2_salt_len().ok(), expected, "{}", value);
+        }
+
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-argon2-salt-len=32");
+        let plain_cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_argon2_salt_len(cfg, plain_cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_argon2_secret(cfg: Config, plain_cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "wendy", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let form = FormData::from("username=wendy&password=hunter2".to_string());
+        assert!(verify_login(&WrapConfigure::from(cfg), &form).await?);
+        // Same database without secret can not verify account
+        assert!(!verify_login(&WrapConfigure::from(plain_cfg), &form).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_argon2_secret() {
+        let hash = FormData::gen_string_argon2_hash(
+            "hunter2",
+            Default::default(),
+            DEFAULT_ARGON2_OUTPUT_LEN,
+            DEFAULT_ARGON2_SALT_LEN,
+            Some(b"pepper"),
+        )
+        .unwrap();
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert!(build_argon2(Some(b"pepper"))
+            .unwrap()
+            .verify_password(b"hunter2", &parsed)
+            .is_ok());
+        assert!(build_argon2(None)
+            .unwrap()
+            .verify_password(b"hunter2", &parsed)
+            .is_err());
+        assert!(build_argon2(Some(b"salt"))
+            .unwrap()
+            .verify_password(b"hunter2", &parsed)
+            .is_err());
+
+        let tmpdir = tempdir::TempDir::new("argon2_secret").unwrap();
+        let secret_file = tmpdir.path().join("argon2.secret");
+        write_to_specify_file(&secret_file, b"pepper\n").unwrap();
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            format!(
+                "cgit-simple-auth-argon2-secret-file={}",
+                secret_file.to_str().unwrap()
+            )
+            .as_bytes(),
+        );
+        assert_eq!(cfg.get_argon2_secret().unwrap(), Some(b"pepper".to_vec()));
+
+        let plain_cfg = temp_database_config(tmpdir.path(), b"");
+        assert_eq!(plain_cfg.get_argon2_secret().unwrap(), None);
+
+        block_on(async_test_argon2_secret(cfg.clone(), plain_cfg)).unwrap();
+
+        // Empty or missing secret file is a configuration error
+        write_to_specify_file(&secret_file, b"\n").unwrap();
+        assert!(cfg.get_argon2_secret().is_err());
+        std::fs::remove_file(&secret_file).unwrap();
+        assert!(cfg.get_argon2_secret().is_err());
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_blocking_password_hash() -> anyhow::Result<()> {
+        let secret = Some(b"pepper".to_vec());
+        let hash = FormData::gen_string_argon2_hash_blocking(
+            "hunter2",
+            Default::default(),
+            DEFAULT_ARGON2_OUTPUT_LEN,
+            DEFAULT_ARGON2_SALT_LEN,
+            secret.clone(),
+        )
+        .await?;
+        // Same result as verifying on current thread
+        assert!(build_argon2(Some(b"pepper"))?
+            .verify_password(b"hunter2", &PasswordHash::new(&hash).unwrap())
+            .is_ok());
+
+        assert!(verify_password_blocking("hunter2", &hash, secret.clone()).await?);
+        assert!(!verify_password_blocking("hunter3", &hash, secret.clone()).await?);
+        assert!(!verify_password_blocking("hunter2", &hash, None).await?);
+        assert!(verify_password_blocking("hunter2", "not a hash", secret)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocking_password_hash() {
+        block_on(async_test_blocking_password_hash()).unwrap();
+    }
+
+    async fn async_test_repo_cache_ttl(cfg: Config) -> anyhow::Result<()> {
+        let mut redis = RedisSessionStore::new(cfg.connect_redis().await?);
+        redis
+            .set_repo_set("ttl_repo", &["alice".to_string()], 300)
+            .await?;
+        let ttl = redis.get_session_ttl("cgit_repo_ttl_repo").await?.unwrap();
+        assert!(ttl > 0 && ttl <= 300);
+        redis.clear_repo_sets(&["ttl_repo".to_string()]).await?;
+
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "vera", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        let repo = get_arg_matches(Some(vec!["a", "repo", "add", "ttl_repo", "vera"]));
+        if let ("repo", Some(matches)) = repo.subcommand() {
+            if let ("add", Some(matches)) = matches.subcommand() {
+                cmd_repo_user_control(matches, cfg.clone(), false).await?;
+            }
+        }
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("vera", "hunter2", &cfg, &mut store).await?;
+        let cookie = get_set_cookie(&out);
+        assert!(validate_cookie(
+            &cfg,
+            &cookie,
+            "ttl_repo",
+            &mut store,
+            &RequestPools::default()
+        )
+        .await?
+        .is_some());
+
+        // Revoke behind cache's back, cached grant is served until it expires
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"DELETE FROM "repos" WHERE "repo" = 'ttl_repo'"#)
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        assert!(validate_cookie(
+            &cfg,
+            &cookie,
+            "ttl_repo",
+            &mut store,
+            &RequestPools::default()
+        )
+        .await?
+        .is_some());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(validate_cookie(
+            &cfg,
+            &cookie,
+            "ttl_repo",
+            &mut store,
+            &RequestPools::default()
+        )
+        .await?
+        .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repo_cache_ttl() {
+        let tmpdir = tempdir::TempDir::new("repo_cache_ttl").unwrap();
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-protect=full\ncgit-simple-auth-repo-cache-ttl=1",
+        );
+        assert_eq!(cfg.get_repo_cache_ttl(), 1);
+        assert_eq!(Config::generate_test_config().get_repo_cache_ttl(), 300);
+
+        block_on(async_test_repo_cache_ttl(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_cookie_path(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "tess", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let mut store = MemorySessionStore::default();
+        let out = post_with_new_token("tess", "hunter2", &cfg, &mut store).await?;
+        let header = out
+            .lines()
+            .find(|line| line.starts_with("Set-Cookie"))
+            .unwrap();
+        assert!(header.contains("; Path=/cgit;"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_validate() {
+        assert!(Config::generate_test_config().validate().is_ok());
+
+        let tmpdir = tempdir::TempDir::new("config_validate").unwrap();
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-argon2-variant=x\n\
+            cgit-simple-auth-cookie-path=cgit\n\
+            cgit-simple-auth-login-failure-delay-ms=9000\n\
+            cgit-simple-auth-redis-url=not a url",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        let err = cfg.validate().unwrap_err();
+        match err.downcast_ref::<AuthError>() {
+            Some(AuthError::InvalidConfig(problems)) => assert_eq!(problems.len(), 4),
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(AuthError::exit_code_of(&err), 7);
+        let message = err.to_string();
+        assert!(message.contains("argon2-variant x"));
+        assert!(message.contains("cookie-path cgit"));
+        assert!(message.contains("login-failure-delay-ms 9000"));
+
+        // Redis url is not used with `--no-redis`
+        let mut cfg = cfg;
+        cfg.no_redis = true;
+        match cfg.validate().unwrap_err().downcast_ref::<AuthError>() {
+            Some(AuthError::InvalidConfig(problems)) => assert_eq!(problems.len(), 3),
+            _ => unreachable!(),
+        }
+
+        // Typo in a value is reported instead of silently falling back to default
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            b"cgit-simple-auth-max-attempts-per-host=5x\n\
+            cgit-simple-auth-max-attempts-per-user=-1\n\
+            cgit-simple-auth-pow-difficulty=high\n\
+            cgit-simple-auth-password-min-length=eight\n\
+            cgit-simple-auth-session-backend=sqllite\n\
+            cgit-simple-auth-protect=fulll\n\
+            cgit-simple-auth-operation-timeout=3\n\
+            cgit-simple-auth-attempts-window=1x\n\
+            cgit-simple-auth-attempts-window=600",
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        let err = cfg.validate().unwrap_err();
+        match err.downcast_ref::<AuthError>() {
+            Some(AuthError::InvalidConfig(problems)) => {
+                assert_eq!(problems.len(), 6, "{:?}", problems)
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        let message = err.to_string();
+        assert!(message.contains("max-attempts-per-host has invalid value \"5x\""));
+        assert!(message.contains("session-backend has invalid value \"sqllite\""));
+        assert!(message.contains("protect has invalid value \"fulll\""));
+        // Valid later value replaces an invalid one
+        assert!(!message.contains("attempts-window"));
+
+        // Secret files are not read by filter calls, only by commands hashing passwords
+        write_to_specify_file(
+            &tmpdir.path().join("CFG"),
+            format!(
+                "cgit-simple-auth-argon2-secret-file={}\n\
+                cgit-simple-auth-cookie-secret-file={}",
+                tmpdir.path().join("missing_pepper").display(),
+                tmpdir.path().join("missing_cookie").display()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let cfg = Config::load_from_path(tmpdir.path().join("CFG"));
+        assert!(cfg.validate().is_ok());
+        match cfg
+            .validate_with_secret()
+            .unwrap_err()
+            .downcast_ref::<AuthError>()
+        {
+            Some(AuthError::InvalidConfig(problems)) => assert_eq!(problems.len(), 1),
+            _ => unreachable!(),
+        }
+        for (args, expected) in [
+            (vec!["a", "user", "add", "u", "p"], true),
+            (vec!["a", "user", "list"], false),
+            (vec!["a", "database", "init"], true),
+            (
+                vec![
+                    "a", "body", "", "GET", "", "", "/", "", "", "", "", "/", "/",
+                ],
+                false,
+            ),
+        ] {
+            let matches = get_arg_matches(Some(args));
+            assert_eq!(crate::cli::uses_argon2_secret(&matches), expected);
+        }
+
+        tmpdir.close().unwrap();
+    }
+
+    #[test]
+    fn test_cookie_path() {
+        assert_eq!(
+            Config::generate_test_config().get_cookie_path().unwrap(),
+            "/"
+        );
+
+        let tmpdir = tempdir::TempDir::new("cookie_path").unwrap();
+        for path in ["cgit", "/cgit; Domain=evil.com"] {
+            write_to_specify_file(
+                &tmpdir.path().join("CFG"),
+                format!("cgit-simple-auth-cookie-path={}", path).as_bytes(),
+            )
+            .unwrap();
+            assert!(Config::load_from_path(tmpdir.path().join("CFG"))
+                .get_cookie_path()
+                .is_err());
+        }
+
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-cookie-path=/cgit");
+
+        block_on(async_test_cookie_path(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn read_password_hash(cfg: &Config, user: &str) -> anyhow::Result<String> {
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        let (hash,) =
+            sqlx::query_as::<_, (String,)>(r#"SELECT "password" FROM "accounts" WHERE "user" = ?"#)
+                .bind(user)
+                .fetch_one(&mut conn)
+                .await?;
+        conn.close().await?;
+        Ok(hash)
+    }
+
+    async fn async_test_rehash_on_login(cfg: Config, plain_cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let weak = argon2::Params {
+            t_cost: 1,
+            ..Default::default()
+        };
+        let hash = Argon2::default()
+            .hash_password(
+                b"hunter2",
+                Some(argon2::Algorithm::Argon2i.ident()),
+                weak,
+                SaltString::generate(&mut rand_core::OsRng).as_salt(),
+            )
+            .unwrap()
+            .to_string();
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "--hash", "sam", &hash]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        let form = FormData::from("username=sam&password=hunter2".to_string());
+
+        // Disabled by default, stored hash is kept
+        assert!(verify_login(&WrapConfigure::from(plain_cfg), &form).await?);
+        assert_eq!(read_password_hash(&cfg, "sam").await?, hash);
+
+        let wrong = FormData::from("username=sam&password=hunter3".to_string());
+        assert!(!verify_login(&WrapConfigure::from(cfg.clone()), &wrong).await?);
+        assert_eq!(read_password_hash(&cfg, "sam").await?, hash);
+
+        assert!(verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+        let upgraded = read_password_hash(&cfg, "sam").await?;
+        let parsed = PasswordHash::new(&upgraded).unwrap();
+        assert!(!is_hash_outdated(
+            &parsed,
+            cfg.get_argon2_algorithm()?,
+            cfg.get_argon2_output_len()?
+        ));
+        assert_eq!(parsed.algorithm.as_str(), "argon2id");
+        assert!(verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+        assert_eq!(read_password_hash(&cfg, "sam").await?, upgraded);
+
+        // Damaged stored hash is a failed login, not a panic
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        sqlx::query(r#"UPDATE "accounts" SET "password" = ? WHERE "user" = ?"#)
+            .bind("not-a-phc-string")
+            .bind("sam")
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        assert!(!verify_login(&WrapConfigure::from(cfg.clone()), &form).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rehash_on_login() {
+        let tmpdir = tempdir::TempDir::new("rehash_on_login").unwrap();
+        let cfg = temp_database_config(tmpdir.path(), b"cgit-simple-auth-rehash-on-login=true");
+        let plain_cfg = temp_database_config(tmpdir.path(), b"");
+
+        block_on(async_test_rehash_on_login(cfg, plain_cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_delete_user_purge(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        for user in ["sam", "tess"] {
+            let add = get_arg_matches(Some(vec!["a", "user", "add", user, "hunter2"]));
+            cmd_add_user(add_user_matches(&add), cfg.clone()).await?;
+        }
+        for (repo, user) in [
+            ("purge_shared", "sam"),
+            ("purge_shared", "tess"),
+            ("purge_own", "sam"),
+            ("purge_other", "tess"),
+        ] {
+            let args = get_arg_matches(Some(vec!["a", "repo", "add", repo, user]));
+            if let ("repo", Some(matches)) = args.subcommand() {
+                if let ("add", Some(matches)) = matches.subcommand() {
+                    cmd_repo_user_control(matches, cfg.clone(), false).await?;
+                }
+            }
+        }
+        run_group_command(&cfg, &["add", "purge_crew"]).await?;
+        run_group_command(&cfg, &["member", "purge_crew", "sam"]).await?;
+        run_group_command(&cfg, &["member", "purge_crew", "tess"]).await?;
+        run_group_command(&cfg, &["grant", "purge_crew", "purge_group"]).await?;
+
+        let mut store = MemorySessionStore::default();
+        for repo in ["purge_shared", "purge_group", "purge_other"] {
+            store.set_repo_set(repo, &["sam".to_string()], 300).await?;
+        }
+
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "--purge", "sam"]));
+        cmd_delete_user(user_matches(&del), cfg.clone(), Some(&mut store)).await?;
+
+        let pool = cfg.connect_read_only_database().await?;
+        let repos = sqlx::query_as::<_, (String, String)>(
+            r#"SELECT "repo", "users" FROM "repos" ORDER BY "repo""#,
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert!(repos
+            .iter()
+            .all(|(_, users)| !users.split_whitespace().any(|x| x.eq("sam"))));
+        assert!(repos.contains(&("purge_shared".to_string(), "tess".to_string())));
+        let members = sqlx::query_as::<_, (String,)>(r#"SELECT "user" FROM "group_members""#)
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(members, vec![("tess".to_string(),)]);
+        pool.close().await;
+
+        // Only repositories sam had access to are invalidated
+        assert!(!store.has_repo_set("purge_shared").await?);
+        assert!(!store.has_repo_set("purge_group").await?);
+        assert!(store.has_repo_set("purge_other").await?);
+
+        // Without --purge ACL is kept
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "tess"]));
+        cmd_delete_user(user_matches(&del), cfg.clone(), Some(&mut store)).await?;
+        let pool = cfg.connect_read_only_database().await?;
+        let (users,) = sqlx::query_as::<_, (String,)>(
+            r#"SELECT "users" FROM "repos" WHERE "repo" = 'purge_other'"#,
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(users, "tess");
+        pool.close().await;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_user_purge() {
+        run_with_temp_database("delete_purge", async_test_delete_user_purge);
+    }
+
+    async fn async_test_offline_management(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "rita", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+        let repo = get_arg_matches(Some(vec!["a", "repo", "add", "offline_repo", "rita"]));
+        if let ("repo", Some(matches)) = repo.subcommand() {
+            if let ("add", Some(matches)) = matches.subcommand() {
+                cmd_repo_user_control(matches, cfg.clone(), false).await?;
+            }
+        }
+
+        let mut store = connect_optional_session_store(&cfg).await;
+        assert!(store.is_none());
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "rita"]));
+        cmd_delete_user(
+            user_matches(&del),
+            cfg.clone(),
+            store.as_mut().map(|x| x.as_mut() as _),
+        )
+        .await?;
+
+        let mut conn = sqlx::SqliteConnection::connect(cfg.get_database_location()).await?;
+        assert!(
+            sqlx::query(r#"SELECT 1 FROM "accounts" WHERE "user" = 'rita'"#)
+                .fetch_optional(&mut conn)
+                .await?
+                .is_none()
+        );
+        conn.close().await?;
+
+        // Reachable Redis is skipped too when asked to
+        let mut no_redis = Config::generate_test_config();
+        no_redis.no_redis = true;
+        assert!(connect_optional_session_store(&no_redis).await.is_none());
+        assert!(
+            connect_optional_session_store(&Config::generate_test_config())
+                .await
+                .is_some()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline_management() {
+        let tmpdir = tempdir::TempDir::new("offline_management").unwrap();
+        // Nothing listens on port 1, connection is refused at once
+        let cfg = temp_database_config(
+            tmpdir.path(),
+            b"cgit-simple-auth-redis-url=redis://127.0.0.1:1/",
+        );
+
+        assert!(
+            get_arg_matches(Some(vec!["a", "--no-redis", "user", "del", "rita"]))
+                .is_present("no-redis")
+        );
+
+        block_on(async_test_offline_management(cfg)).unwrap();
+
+        tmpdir.close().unwrap();
+    }
+
+    async fn async_test_auth_error_kinds(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = |args: Vec<&'static str>| {
+            let cfg = cfg.clone();
+            async move {
+                let matches = get_arg_matches(Some([vec!["a", "user", "add"], args].concat()));
+                cmd_add_user(user_matches(&matches), cfg).await
+            }
+        };
+        add(vec!["quinn", "Hunter22"]).await?;
+
+        let err = add(vec!["quinn", "Hunter22"]).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(AuthError::UserExists)));
+        assert_eq!(err.to_string(), "User already exists!");
+        assert_eq!(AuthError::exit_code_of(&err), 3);
+
+        let err = add(vec!["qu-inn", "Hunter22"]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(AuthError::InvalidUsername(_))
+        ));
+        let err = add(vec!["quincy", "hunter"]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(AuthError::WeakPassword(_))
+        ));
+        assert_eq!(AuthError::exit_code_of(&err), 2);
+
+        let del = get_arg_matches(Some(vec!["a", "user", "del", "ghost"]));
+        let err = cmd_delete_user(user_matches(&del), cfg.clone(), None)
+            .await
+            .unwrap_err();
+        match err.downcast_ref() {
+            Some(AuthError::UserNotFound(user)) => assert_eq!(user, "ghost"),
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(err.to_string(), "User ghost not found");
+        assert_eq!(AuthError::exit_code_of(&err), 4);
+
+        let del = get_arg_matches(Some(vec!["a", "user", "del", ""]));
+        let err = cmd_delete_user(user_matches(&del), cfg.clone(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(AuthError::InvalidUsername(_))
+        ));
+
+        assert_eq!(
+            AuthError::exit_code_of(&anyhow::Error::from(sqlx::Error::RowNotFound)),
+            5
+        );
+        assert_eq!(AuthError::exit_code_of(&anyhow::Error::msg("other")), 1);
+        Ok(())
+    }
+
+    async fn async_test_unicode_username(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let add = |user: String| {
+            let cfg = cfg.clone();
+            async move {
+                let matches = get_arg_matches(Some(vec!["a", "user", "add", &user, "hunter2"]));
+                cmd_add_user(user_matches(&matches), cfg).await
+            }
+        };
+        add("Jos\u{e9}".to_string()).await?;
+        add("\u{5f20}\u{4f1f}".to_string()).await?;
+
+        // Decomposed form looks the same and must hit the existing account
+        let err = add("Jose\u{301}".to_string()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(AuthError::UserExists)));
+
+        // Limit counts characters, not bytes
+        add("\u{e9}".repeat(19)).await?;
+        for invalid in [
+            "\u{e9}".repeat(20),
+            "jo se".to_string(),
+            "jo\u{7}".to_string(),
+        ] {
+            let err = add(invalid).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(AuthError::InvalidUsername(_))
+            ));
+        }
+
+        let form = FormData::from("username=Jose%CC%81&password=hunter2".to_string());
+        assert_eq!(form.get_user(), "Jos\u{e9}");
+        assert!(verify_login(&WrapConfigure::from(cfg), &form).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unicode_username() {
+        run_with_temp_database("unicode_username", async_test_unicode_username);
+    }
+
+    #[test]
+    fn test_auth_error_kinds() {
+        run_with_temp_config(
+            "auth_error",
+            b"cgit-simple-auth-password-require-digit=true",
+            async_test_auth_error_kinds,
+        );
+    }
+
+    async fn async_test_validate_cookie(cfg: Config) -> anyhow::Result<()> {
+        cmd_init(cfg.clone()).await?;
+        let mut store = MemorySessionStore::default();
+        let add = get_arg_matches(Some(vec!["a", "user", "add", "zoe", "hunter2"]));
+        cmd_add_user(user_matches(&add), cfg.clone()).await?;
+
+        let out = post_with_new_token("zoe", "hunter2", &cfg, &mut store).await?;
+        let cookie = get_set_cookie(&out);
+        let pools = RequestPools::default();
+        assert_eq!(
+            validate_cookie(&cfg, &cookie, "", &mut store, &pools).await?,
+            Some("zoe".to_string())
+        );
+        assert_eq!(
+            validate_cookie(&cfg, "cgit_auth=garbage", "", &mut store, &pools).await?,
+            None
+        );
+
+        // Later steps of a request reuse the pool opened first instead of connecting again,
+        // so once it is closed they fail.
+        pools.close().await;
+        assert!(validate_cookie(&cfg, &cookie, "", &mut store, &pools)
+            .await
+            .is_err());
+        assert!(
+            validate_cookie(&cfg, &cookie, "", &mut store, &RequestPools::default())
+                .await?
+                .is_some()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cookie() {
+        run_with_temp_database("validate_cookie", async_test_validate_cookie);
+    }
+
+    #[test]
+    fn test_fixed_salt_hash() {
+        let salt = SaltString::new("c2FsdHNhbHRzYWx0").unwrap();
+        let hash = FormData::gen_string_argon2_hash_with_salt(
+            "hunter2",
+            Default::default(),
+            DEFAULT_ARGON2_OUTPUT_LEN,
+            None,
+            &salt,
+        )
+        .unwrap();
+        assert_eq!(
+            hash,
+            FormData::gen_string_argon2_hash_with_salt(
+                "hunter2",
+                Default::default(),
+                DEFAULT_ARGON2_OUTPUT_LEN,
+                None,
+                &salt,
+            )
+            .unwrap()
+        );
+        assert!(hash.starts_with("$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHRzYWx0$"));
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert!(Argon2::default()
+            .verify_password(b"hunter2", &parsed)
+            .is_ok());
+
+        // Random salt stays the default
+        assert_ne!(
+            FormData::gen_string_argon2_hash(
+                "hunter2",
+                Default::default(),
+                DEFAULT_ARGON2_OUTPUT_LEN,
+                DEFAULT_ARGON2_SALT_LEN,
+                None,
+            )
+            .unwrap(),
+            FormData::gen_string_argon2_hash(
+                "hunter2",
+                Default::default(),
+                DEFAULT_ARGON2_OUTPUT_LEN,
+                DEFAULT_ARGON2_SALT_LEN,
+                None,
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_expires() {
+        let now = crate::datastructures::get_current_timestamp() as i64;
+        assert_eq!(parse_expires("1700000000").unwrap(), 1700000000);
+        assert!((parse_expires("2d").unwrap() - now - 2 * 86400).abs() <= 1);
+        assert!((parse_expires("90m").unwrap() - now - 90 * 60).abs() <= 1);
+        assert!(parse_expires("soon").is_err());
+        assert!(parse_expires("xd").is_err());
+    }
 }